@@ -180,6 +180,13 @@ impl MAabbIntersection {
     pub fn should_try_before(&self, other: &MAabbIntersection) -> bool {
         (self.tmin - other.tmin).all_sign_bits_positive()
     }
+
+    /// Returns the distances along the rays at which they enter and leave the
+    /// AABB. For rays that miss it, the exit distance is smaller than the
+    /// entry distance.
+    pub fn distances(&self) -> (Mf32, Mf32) {
+        (self.tmin, self.tmax)
+    }
 }
 
 #[test]
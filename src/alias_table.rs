@@ -0,0 +1,149 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Implements the alias method for sampling from a discrete distribution.
+//!
+//! The alias method (Walker, 1974; the construction below is Vose's, 1991)
+//! samples from a distribution over n outcomes in constant time. The
+//! distribution is flattened into n buckets of equal probability. Every bucket
+//! contains at most two outcomes: the outcome with the bucket index, and an
+//! alias. Sampling picks a bucket uniformly, and then picks one of the two
+//! outcomes with a biased coin flip.
+
+#[cfg(test)]
+use random::Rng;
+
+pub struct AliasTable {
+    /// For every bucket, the probability of picking the outcome with the
+    /// bucket index rather than its alias.
+    threshold: Vec<f32>,
+
+    /// For every bucket, the outcome to pick if the coin flip fails.
+    alias: Vec<u32>,
+
+    /// The normalized probability of every outcome.
+    pmf: Vec<f32>,
+}
+
+impl AliasTable {
+    /// Builds a table that samples index i with probability proportional to
+    /// `weights[i]`. Weights must be nonnegative and not all zero.
+    pub fn new(weights: &[f32]) -> AliasTable {
+        assert!(!weights.is_empty(), "alias table needs at least one outcome");
+
+        let sum: f64 = weights.iter().map(|&w| w as f64).sum();
+        assert!(sum > 0.0, "alias table weights must not all be zero");
+
+        let n = weights.len();
+        let pmf: Vec<f32> = weights.iter().map(|&w| (w as f64 / sum) as f32).collect();
+
+        // Scale the probabilities such that the average bucket has weight 1.
+        // Then repeatedly top up a bucket that is too light with the excess of
+        // a bucket that is too heavy. Doing the arithmetic in f64 keeps the
+        // accumulated rounding error well below what is visible in an f32.
+        let mut scaled: Vec<f64> = weights.iter().map(|&w| w as f64 * n as f64 / sum).collect();
+        let mut threshold = vec![1.0; n];
+        let mut alias: Vec<u32> = (0..n as u32).collect();
+
+        let mut small: Vec<usize> = (0..n).filter(|&i| scaled[i] < 1.0).collect();
+        let mut large: Vec<usize> = (0..n).filter(|&i| scaled[i] >= 1.0).collect();
+
+        loop {
+            let (s, l) = match (small.last(), large.last()) {
+                (Some(&s), Some(&l)) => (s, l),
+                _ => break,
+            };
+
+            small.pop();
+            threshold[s] = scaled[s] as f32;
+            alias[s] = l as u32;
+
+            scaled[l] = (scaled[l] + scaled[s]) - 1.0;
+            if scaled[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+
+        // Whatever is left should have weight 1 up to rounding errors, so
+        // these buckets never need their alias. The threshold is already 1.
+
+        AliasTable {
+            threshold: threshold,
+            alias: alias,
+            pmf: pmf,
+        }
+    }
+
+    /// Returns the number of outcomes.
+    pub fn len(&self) -> usize {
+        self.pmf.len()
+    }
+
+    /// Picks an outcome given 32 random bits and a number uniformly
+    /// distributed in [0, 1).
+    ///
+    /// The bucket is selected with the high order bits, which are the more
+    /// random ones for the numbers produced by `Rng`.
+    #[inline(always)]
+    pub fn sample(&self, random_bits: u32, u: f32) -> u32 {
+        let n = self.threshold.len() as u64;
+        let bucket = ((random_bits as u64 * n) >> 32) as usize;
+
+        // The bucket is less than n by construction, so skip the bounds checks.
+        unsafe {
+            if u < *self.threshold.get_unchecked(bucket) {
+                bucket as u32
+            } else {
+                *self.alias.get_unchecked(bucket)
+            }
+        }
+    }
+
+    /// Returns the probability of sampling the given outcome.
+    #[inline(always)]
+    pub fn pmf(&self, index: u32) -> f32 {
+        self.pmf[index as usize]
+    }
+}
+
+#[test]
+fn alias_table_pmf_is_normalized() {
+    let table = AliasTable::new(&[1.0, 3.0, 0.0, 4.0]);
+    assert_eq!(0.125, table.pmf(0));
+    assert_eq!(0.375, table.pmf(1));
+    assert_eq!(0.0, table.pmf(2));
+    assert_eq!(0.5, table.pmf(3));
+}
+
+#[test]
+fn alias_table_samples_proportional_to_weight() {
+    let weights = [1.0, 3.0, 0.0, 4.0, 2.0, 6.0];
+    let table = AliasTable::new(&weights);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut counts = [0u32; 6];
+    let n = 4096;
+
+    for _ in 0..n {
+        let bits = rng.sample_u32();
+        let us = rng.sample_unit();
+        for i in 0..8 {
+            let k = table.sample(bits[i], us.get_coord(i));
+            counts[k as usize] += 1;
+        }
+    }
+
+    // An outcome with zero weight must never be sampled.
+    assert_eq!(0, counts[2]);
+
+    for i in 0..weights.len() {
+        let expected = table.pmf(i as u32);
+        let frequency = counts[i] as f32 / (n * 8) as f32;
+        assert!((frequency - expected).abs() < 0.01,
+                "outcome {} has frequency {} but expected {}", i, frequency, expected);
+    }
+}
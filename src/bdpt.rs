@@ -0,0 +1,543 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements a bidirectional path tracer.
+//!
+//! The interiors that I render are lit through small windows. A path from the
+//! camera has to find a window by chance, or by direct sampling from a surface
+//! that can see the window. Light that bounces off the window sill first and
+//! then lights the room is hard to find from the camera. So in addition to the
+//! camera subpath, a light subpath starts at a window and bounces into the
+//! room. Every vertex of the camera subpath is connected to every vertex of
+//! the light subpath with a shadow ray, and every connection is a complete
+//! path from the light to the camera. Many of those paths could have been
+//! constructed in different ways, with more or fewer vertices on either side,
+//! so they are weighted with multiple importance sampling, see Veach, 1997,
+//! chapter 10.
+//!
+//! The start of the light subpath is picked with `Scene::get_direct_sample()`
+//! as seen from the first camera vertex. That way the windows that matter for
+//! the pixel are picked more often. Windows emit the sky into both sides, so
+//! the light subpath leaves into either side with a cosine distribution.
+//!
+//! There are some deliberate omissions:
+//!
+//!  * Light vertices are not connected to the camera. That would add light to
+//!    a different pixel than the one being rendered, which does not fit the
+//!    `Integrator` interface. The strategy is left out of the weights, so the
+//!    result is still unbiased.
+//!
+//!  * The sun and analytic lights are sampled directly from camera vertices,
+//!    like in the path tracer. Escaped rays are not sampled directly.
+//!
+//!  * Participating media and translucent materials are ignored, and normal
+//!    maps and texture filtering are not applied. Use the path tracer for
+//!    those.
+
+use integrator::{Integrator, MPixelData, Settings};
+use layered::pick_layers;
+use material::{MMaterial, MisHeuristic, continue_path, eval_brdf, pd_brdf};
+use material::{sample_analytic_lights, sample_sun_light};
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::Scene;
+use simd::{Mask, Mf32};
+use spectral::{MChannels, SpectralTables};
+use std::f32::consts::PI;
+use vector3::MVector3;
+
+pub struct Bdpt;
+
+/// A vertex of 8 subpaths.
+struct MVertex {
+    isect: MIntersection,
+
+    /// The material at the vertex, after picking a layer and applying the
+    /// texture.
+    material: MMaterial,
+
+    /// The direction of the ray that arrived at the vertex.
+    incoming: MVector3,
+
+    /// The throughput of the subpath up to the vertex, excluding the BRDF at
+    /// the vertex itself.
+    throughput: MVector3,
+
+    /// The probability density per unit area of sampling the vertex from the
+    /// previous vertex of its own subpath.
+    pd_fwd: Mf32,
+
+    /// The probability density per unit area of sampling the vertex from the
+    /// next vertex, when the path would have been constructed from the other
+    /// side.
+    pd_rev: Mf32,
+
+    /// The sign bit is 1 where the vertex lies on an emitter or on the sky.
+    emissive: Mask,
+
+    /// The sign bit is 1 where the subpath was terminated before it reached
+    /// the vertex.
+    absent: Mask,
+}
+
+/// The densities at the vertices around a connection, which are different
+/// from the ones that were stored when the subpaths were traced.
+struct MConnection {
+    /// The density of the last camera vertex, sampled from the light side.
+    camera_rev: Mf32,
+
+    /// The density of the camera vertex before it, if it differs from the one
+    /// that was stored.
+    camera_prev_rev: Option<Mf32>,
+
+    /// The density of the last light vertex, sampled from the camera side.
+    light_rev: Mf32,
+}
+
+/// Returns the probability density of a window emitting in a direction. It
+/// emits into both sides with a cosine distribution.
+fn pd_emission(normal: MVector3, direction: MVector3) -> Mf32 {
+    normal.dot(direction).abs() * Mf32::broadcast(0.5 / PI)
+}
+
+/// Converts a density per solid angle into a density per unit area at a
+/// point at the given squared distance, where the cosine with the normal is
+/// `cos_theta`.
+fn to_area(pd: Mf32, cos_theta: Mf32, distance_sqr: Mf32) -> Mf32 {
+    pd * cos_theta * distance_sqr.max(Mf32::broadcast(1e-10)).recip_precise()
+}
+
+/// Returns the multiple importance sampling weight of the path made of the
+/// first s light vertices and t camera vertices, where the camera itself is
+/// camera vertex 0, so `camera[i]` is vertex i + 1.
+fn mis_weight(camera: &[MVertex],
+              light: &[MVertex],
+              s: usize,
+              t: usize,
+              connection: &MConnection,
+              heuristic: MisHeuristic)
+              -> Mf32 {
+    // Every step along one of the subpaths moves a vertex to the other
+    // subpath, which results in the ratio of the density of the other
+    // strategy to the density of this one. Where the reverse density is zero
+    // the strategy could not have found the path, and neither can the
+    // strategies beyond it.
+    let ratio = |rev: Mf32, fwd: Mf32| rev * fwd.max(Mf32::broadcast(1e-30)).recip_precise();
+    let power = |r: Mf32| match heuristic {
+        MisHeuristic::Balance => r,
+        MisHeuristic::Power => r * r,
+    };
+    let mut sum = Mf32::zero();
+
+    // Vertex 1 is never moved to the light subpath, because that strategy is
+    // not used.
+    let mut r = Mf32::one();
+    for i in (2..t).rev() {
+        let vertex = &camera[i - 1];
+        let rev = if i == t - 1 {
+            connection.camera_rev
+        } else if i == t - 2 {
+            connection.camera_prev_rev.unwrap_or(vertex.pd_rev)
+        } else {
+            vertex.pd_rev
+        };
+        r = r * ratio(rev, vertex.pd_fwd);
+        sum = sum + power(r);
+    }
+
+    let mut r = Mf32::one();
+    for i in (0..s).rev() {
+        let vertex = &light[i];
+        let rev = if i == s - 1 { connection.light_rev } else { vertex.pd_rev };
+        r = r * ratio(rev, vertex.pd_fwd);
+        sum = sum + power(r);
+    }
+
+    (Mf32::one() + sum).recip_precise()
+}
+
+/// Extends a subpath from the ray until it has `max_vertices` vertices, or
+/// until all paths are terminated. The throughput is that of the ray, and
+/// `pd_dir` the density per solid angle with which the ray direction was
+/// sampled at the last vertex.
+fn trace_subpath(scene: &Scene,
+                 mut ray: MRay,
+                 mut throughput: MVector3,
+                 mut pd_dir: Mf32,
+                 vertices: &mut Vec<MVertex>,
+                 max_vertices: usize,
+                 settings: &Settings,
+                 rng: &mut Rng,
+                 channels: MChannels) {
+    while vertices.len() < max_vertices {
+        if ray.active.all_sign_bits_negative() {
+            break;
+        }
+
+        let mut isect = scene.intersect_nearest(&ray);
+        isect.face_towards(&ray);
+
+        let absent = ray.active;
+        let emissive = isect.material.pick(Mask::zero(), absent);
+        let distance_sqr = isect.distance * isect.distance;
+        let cos_here = isect.normal.dot(ray.direction).abs();
+        let pd_fwd = to_area(pd_dir, cos_here, distance_sqr);
+
+        // The previous vertex could also have been sampled from this one.
+        if let Some(prev) = vertices.last_mut() {
+            let pd_back = pd_brdf(&isect, &-ray.clone());
+            let cos_prev = prev.isect.normal.dot(ray.direction).abs();
+            prev.pd_rev = to_area(pd_back, cos_prev, distance_sqr).pick(prev.pd_rev, absent);
+        }
+
+        // A path sees one layer of a layered material, like in the path
+        // tracer. Translucent bases are not supported, those paths end here.
+        let layers = pick_layers(scene, &ray, &isect, absent | emissive, rng, channels);
+        isect.material = layers.material;
+        throughput = throughput.mul_coords(layers.weight);
+        let material = scene.apply_textures(&isect, Mf32::zero(), false);
+        let (material, _emission, _transparent) =
+            scene.apply_parameter_maps(material, &isect, Mf32::zero(), rng, false);
+        let material = match channels {
+            MChannels::Rgb => material,
+            MChannels::Spectral(..) => {
+                let keep = material | material.is_layered();
+                material.with_color(channels.uplift(material.get_color())).pick(material, keep)
+            }
+        };
+
+        let depth = vertices.len() as u32;
+        vertices.push(MVertex {
+            isect: isect,
+            material: material,
+            incoming: ray.direction,
+            throughput: throughput,
+            pd_fwd: pd_fwd,
+            pd_rev: Mf32::zero(),
+            emissive: emissive,
+            absent: absent | layers.translucent,
+        });
+
+        let vertex = vertices.last().unwrap();
+        let arriving = MRay {
+            origin: ray.origin,
+            direction: ray.direction,
+            active: vertex.absent | vertex.emissive,
+        };
+        let max_color_mod = if settings.clamp_fireflies { Some(2.0) } else { None };
+        let (new_ray, color_mod, _fresnel) =
+            continue_path(vertex.material, &arriving, &vertex.isect, rng, false, max_color_mod);
+        pd_dir = pd_brdf(&vertex.isect, &new_ray);
+        throughput = throughput.mul_coords(color_mod);
+        ray = new_ray;
+
+        // Terminate subpaths with Russian roulette, like in the path tracer.
+        if depth + 1 >= settings.roulette_depth {
+            let max_throughput = throughput.x.max(throughput.y).max(throughput.z);
+            let survival = max_throughput.max(Mf32::broadcast(0.05)).min(Mf32::one());
+            let u = rng.sample_unit();
+            ray.active = ray.active | (survival - u);
+            throughput = throughput * survival.recip_precise();
+        }
+    }
+}
+
+/// Samples the first vertex of the light subpaths, on an emitter, and traces
+/// the rest of the subpath from there.
+fn trace_light_subpath(scene: &Scene,
+                       reference: &MIntersection,
+                       vertices: &mut Vec<MVertex>,
+                       max_vertices: usize,
+                       settings: &Settings,
+                       rng: &mut Rng,
+                       channels: MChannels) {
+    let ds = scene.get_direct_sample(reference, rng);
+    let pd_area = ds.pmf * ds.area.recip_precise();
+
+    // Leave into either side of the window, with a cosine distribution.
+    let u = rng.sample_unit();
+    let side = ds.normal.pick(-ds.normal, u - Mf32::broadcast(0.5));
+    let direction = rng.sample_hemisphere_vector().rotate_hemisphere(side);
+    let pd_dir = pd_emission(ds.normal, direction);
+
+    let isect = MIntersection {
+        position: ds.position,
+        normal: ds.normal,
+        distance: Mf32::zero(),
+        material: MMaterial::sky(),
+        tex_coords: (Mf32::zero(), Mf32::zero()),
+        tex_density: Mf32::zero(),
+        tangent: MVector3::zero(),
+        bitangent: MVector3::zero(),
+    };
+    let one = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+    let throughput = one * pd_area.max(Mf32::broadcast(1e-30)).recip_precise();
+    vertices.push(MVertex {
+        isect: isect,
+        material: MMaterial::sky(),
+        incoming: MVector3::zero(),
+        throughput: throughput,
+        pd_fwd: pd_area,
+        pd_rev: Mf32::zero(),
+        emissive: Mask::ones(),
+        absent: Mask::zero(),
+    });
+
+    // A window emits the sky that is behind it. The cosine and the density of
+    // the direction cancel up to a factor 2 pi.
+    let emission = channels.uplift(scene.sky_radiance(-direction));
+    let throughput = throughput.mul_coords(emission) * Mf32::broadcast(2.0 * PI);
+    let ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), ds.position),
+        direction: direction,
+        active: Mask::zero(),
+    };
+    trace_subpath(scene, ray, throughput, pd_dir, vertices, max_vertices, settings, rng, channels);
+}
+
+/// Returns the light of paths where the camera subpath hit an emitter or the
+/// sky at `camera[t - 2]`.
+fn connect_none(scene: &Scene,
+                camera: &[MVertex],
+                t: usize,
+                settings: &Settings,
+                channels: MChannels)
+                -> MVector3 {
+    let vertex = &camera[t - 2];
+    let active = vertex.absent | (vertex.emissive ^ Mask::ones());
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    let direction = vertex.incoming;
+    let sky = channels.uplift(scene.sky_radiance(direction));
+    let sun = channels.uplift(scene.sun_radiance(direction));
+    let light = if t == 2 {
+        // The camera sees the emitter directly, nothing else could have found
+        // this path.
+        sky + sun
+    } else {
+        // The light subpath could have started at the emitter. The sun is
+        // only found by sun sampling, which is weighted like in the path
+        // tracer.
+        let prev = &camera[t - 3];
+        let ray = MRay {
+            origin: prev.isect.position,
+            direction: direction,
+            active: active,
+        };
+        let distance_sqr = vertex.isect.distance * vertex.isect.distance;
+        let cos_emitter = vertex.isect.normal.dot(direction).abs();
+        let pd_light = scene.pd_direct_sample(&camera[0].isect, &ray, vertex.isect.distance);
+        let cos_prev = prev.isect.normal.dot(direction).abs();
+        let connection = MConnection {
+            camera_rev: to_area(pd_light, cos_emitter, distance_sqr),
+            camera_prev_rev: Some(to_area(pd_emission(vertex.isect.normal, direction),
+                                          cos_prev,
+                                          distance_sqr)),
+            light_rev: Mf32::zero(),
+        };
+        let weight = mis_weight(camera, &[], 0, t, &connection, settings.mis_heuristic);
+        let pd_dir = pd_brdf(&prev.isect, &ray);
+        let sun_weight = settings.mis_heuristic.weight(pd_dir, scene.pd_sun(direction));
+        sky * weight + sun * sun_weight
+    };
+
+    light.mul_coords(vertex.throughput).pick(MVector3::zero(), active)
+}
+
+/// Returns the light of paths made by connecting `camera[t - 2]` to
+/// `light[s - 1]` with a shadow ray.
+fn connect(scene: &Scene,
+           camera: &[MVertex],
+           light: &[MVertex],
+           s: usize,
+           t: usize,
+           settings: &Settings,
+           channels: MChannels)
+           -> MVector3 {
+    let z = &camera[t - 2];
+    let y = &light[s - 1];
+
+    // Camera vertices on an emitter end the path, and so do light vertices,
+    // except for the first one.
+    let light_ended = if s == 1 { y.absent } else { y.absent | y.emissive };
+    let active = (z.absent | z.emissive) | light_ended;
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    let to_light = y.isect.position - z.isect.position;
+    let distance_sqr = to_light.norm_squared();
+    let distance = distance_sqr.sqrt();
+    let direction = to_light * distance_sqr.rsqrt();
+
+    // Light cannot pass through a surface, the connection must leave both
+    // vertices on the side that the subpaths arrived from. A window emits into
+    // both sides.
+    let cos_z = z.isect.normal.dot(direction);
+    let cos_y_signed = y.isect.normal.dot(-direction);
+    let (cos_y, active) = if s == 1 {
+        (cos_y_signed.abs(), active | cos_z)
+    } else {
+        (cos_y_signed, active | cos_z | cos_y_signed)
+    };
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    let brdf_z = eval_brdf(z.material, &z.isect, z.incoming, direction);
+    let (light_y, pd_y) = if s == 1 {
+        let emission = channels.uplift(scene.sky_radiance(direction));
+        (emission, pd_emission(y.isect.normal, direction))
+    } else {
+        let brdf_y = eval_brdf(y.material, &y.isect, y.incoming, -direction);
+        let to_camera = MRay {
+            origin: y.isect.position,
+            direction: -direction,
+            active: active,
+        };
+        (brdf_y, pd_brdf(&y.isect, &to_camera))
+    };
+    let to_light = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), z.isect.position),
+        direction: direction,
+        active: active,
+    };
+    let pd_z = pd_brdf(&z.isect, &to_light);
+
+    let connection = MConnection {
+        camera_rev: to_area(pd_y, cos_z, distance_sqr),
+        camera_prev_rev: None,
+        light_rev: to_area(pd_z, cos_y, distance_sqr),
+    };
+    let weight = mis_weight(camera, light, s, t, &connection, settings.mis_heuristic);
+
+    let geometry = cos_z * cos_y * distance_sqr.recip_precise();
+    let contribution = z.throughput.mul_coords(brdf_z).mul_coords(light_y).mul_coords(y.throughput);
+    let contribution = contribution * (geometry * weight);
+
+    debug_assert!(contribution.all_finite());
+
+    let occluded = scene.is_occluded(&to_light, distance);
+    contribution.pick(MVector3::zero(), active | occluded)
+}
+
+impl Integrator for Bdpt {
+    fn name(&self) -> &'static str {
+        "bidirectional path tracer"
+    }
+
+    fn integrate(&self, scene: &Scene, ray: MRay, settings: &Settings, rng: &mut Rng) -> MPixelData {
+        let channels = match settings.spectral {
+            Some(ref tables) => MChannels::Spectral(tables, SpectralTables::sample_wavelengths(rng)),
+            None => MChannels::Rgb,
+        };
+        let max_vertices = settings.max_bounces as usize;
+        let one = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+
+        let mut camera = Vec::with_capacity(max_vertices);
+        trace_subpath(scene, ray, one, Mf32::one(), &mut camera, max_vertices, settings, rng, channels);
+
+        let mut light = Vec::with_capacity(max_vertices);
+        if scene.has_emitters() && !camera.is_empty() {
+            trace_light_subpath(scene, &camera[0].isect, &mut light, max_vertices, settings, rng, channels);
+        }
+
+        let mut color = MVector3::zero();
+        for t in 2..camera.len() + 2 {
+            color = color + connect_none(scene, &camera, t, settings, channels);
+
+            // The sun and the analytic lights are only sampled directly.
+            let vertex = &camera[t - 2];
+            let arriving = MRay {
+                origin: vertex.isect.position,
+                direction: vertex.incoming,
+                active: vertex.absent | vertex.emissive,
+            };
+            if !arriving.active.all_sign_bits_negative() {
+                let sun = sample_sun_light(vertex.material,
+                                           scene,
+                                           &arriving,
+                                           &vertex.isect,
+                                           rng,
+                                           settings.mis_heuristic,
+                                           false,
+                                           channels);
+                let analytic = sample_analytic_lights(vertex.material,
+                                                      scene,
+                                                      &arriving,
+                                                      &vertex.isect,
+                                                      rng,
+                                                      false,
+                                                      channels);
+                color = color + (sun + analytic).mul_coords(vertex.throughput);
+            }
+
+            // A path with t - 1 surface vertices from the camera and s from the
+            // light has t + s - 2 bounces.
+            for s in 1..light.len() + 1 {
+                if (t + s - 2) as u32 > settings.max_bounces {
+                    break;
+                }
+                color = color + connect(scene, &camera, &light, s, t, settings, channels);
+            }
+        }
+
+        // Project the radiance at the wavelengths back onto RGB.
+        let color = match channels {
+            MChannels::Rgb => color,
+            MChannels::Spectral(tables, wavelengths) => tables.to_rgb(color, wavelengths),
+        };
+
+        MPixelData::with_color(color)
+    }
+}
+
+#[cfg(test)]
+fn vertex_with_densities(pd_fwd: f32, pd_rev: f32) -> MVertex {
+    MVertex {
+        isect: MIntersection::with_max_distance(1.0),
+        material: MMaterial::sky(),
+        incoming: MVector3::zero(),
+        throughput: MVector3::zero(),
+        pd_fwd: Mf32::broadcast(pd_fwd),
+        pd_rev: Mf32::broadcast(pd_rev),
+        emissive: Mask::zero(),
+        absent: Mask::zero(),
+    }
+}
+
+#[test]
+fn mis_weights_sum_to_one() {
+    // A path with two surface vertices x1 and x2 between the camera and a
+    // point on a light x3. Every vertex has a density for sampling it from
+    // the camera side and from the light side.
+    let from_camera = [0.0, 0.3, 0.7, 0.2];
+    let from_light = [0.0, 0.5, 0.9, 0.4];
+
+    // The strategies with t = 2, 3, 4: the camera subpath is x1 .. x(t - 1),
+    // the light subpath is x3 .. xt in reverse.
+    let mut total = 0.0;
+    for t in 2..5 {
+        let s = 4 - t;
+        let camera: Vec<MVertex> = (1..t)
+            .map(|i| vertex_with_densities(from_camera[i], from_light[i]))
+            .collect();
+        let light: Vec<MVertex> = (0..s)
+            .map(|j| vertex_with_densities(from_light[3 - j], from_camera[3 - j]))
+            .collect();
+        let connection = MConnection {
+            camera_rev: Mf32::broadcast(from_light[t - 1]),
+            camera_prev_rev: None,
+            light_rev: Mf32::broadcast(from_camera[t]),
+        };
+        let weight = mis_weight(&camera, &light, s, t, &connection, MisHeuristic::Power);
+        total += weight.0;
+    }
+    assert!((total - 1.0).abs() < 1e-5, "weights sum to {}", total);
+}
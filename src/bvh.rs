@@ -9,6 +9,7 @@
 
 use aabb::Aabb;
 use ray::{MIntersection, MRay};
+use texture::TextureSet;
 use triangle::Triangle;
 use util;
 use vector3::{Axis, SVector3};
@@ -554,10 +555,11 @@ impl Bvh {
                 let v1 = mesh.vertices[i1 as usize];
                 let v2 = mesh.vertices[i2 as usize];
                 let mut triangle = Triangle::new(v0, v1, v2, tri.material);
+                triangle.sidedness = tri.sidedness;
                 if let Some((tx0, tx1, tx2)) = tri.tex_coords {
-                    triangle.uv0 = mesh.tex_coords[tx0 as usize];
-                    triangle.uv1 = mesh.tex_coords[tx1 as usize];
-                    triangle.uv2 = mesh.tex_coords[tx2 as usize];
+                    triangle.set_tex_coords(mesh.tex_coords[tx0 as usize],
+                                            mesh.tex_coords[tx1 as usize],
+                                            mesh.tex_coords[tx2 as usize]);
                 }
                 triangle
             });
@@ -584,11 +586,13 @@ impl Bvh {
 
     /// Returns the nearest intersection closer than the provided intersection.
     /// Also returns the number of AABBs intersected and the number of triangles
-    /// intersected.
+    /// intersected. Triangles with a cutout are alpha-tested against the
+    /// textures.
     #[inline(always)]
     pub fn intersect_nearest_impl(&self,
                                   ray: &MRay,
-                                  mut isect: MIntersection)
+                                  mut isect: MIntersection,
+                                  textures: &TextureSet)
                                   -> (MIntersection, u32, u32) {
         // Keep a stack of nodes that still need to be intersected. This does
         // involve a heap allocation, but that is not so bad. Using a small
@@ -647,7 +651,11 @@ impl Bvh {
             } else {
                 for i in node.index..node.index + node.len {
                     let triangle = unsafe { self.triangles.get_unchecked(i as usize) };
-                    isect = triangle.intersect(ray, isect);
+                    isect = if triangle.cutout.is_some() {
+                        triangle.intersect_cutout(ray, isect, textures)
+                    } else {
+                        triangle.intersect(ray, isect)
+                    };
                     numi_tri += 1;
                 }
             }
@@ -656,15 +664,23 @@ impl Bvh {
         (isect, numi_aabb, numi_tri)
     }
 
-    pub fn intersect_nearest(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
-        let (isect, _, _) = self.intersect_nearest_impl(ray, isect);
+    pub fn intersect_nearest(&self,
+                             ray: &MRay,
+                             isect: MIntersection,
+                             textures: &TextureSet)
+                             -> MIntersection {
+        let (isect, _, _) = self.intersect_nearest_impl(ray, isect, textures);
         isect
     }
 
     /// Returns the number of AABBs and the number of triangles intersected to
     /// find the closest intersection.
-    pub fn intersect_debug(&self, ray: &MRay, isect: MIntersection) -> (u32, u32) {
-        let (_, numi_aabb, numi_tri) = self.intersect_nearest_impl(ray, isect);
+    pub fn intersect_debug(&self,
+                           ray: &MRay,
+                           isect: MIntersection,
+                           textures: &TextureSet)
+                           -> (u32, u32) {
+        let (_, numi_aabb, numi_tri) = self.intersect_nearest_impl(ray, isect, textures);
         (numi_aabb, numi_tri)
     }
 }
@@ -684,11 +700,12 @@ fn bench_intersect_decoherent_mray_suzanne(b: &mut test::Bencher) {
     let suzanne = Mesh::load("models/suzanne.obj");
     let bvh = Bvh::from_meshes(&[suzanne]);
     let rays = bench::mrays_inward(4096 / 8);
+    let textures = TextureSet::new();
     let mut rays_it = rays.iter().cycle();
     b.iter(|| {
         let ray = rays_it.next().unwrap();
         let isect_far = MIntersection::with_max_distance(1e5);
-        let isect = bvh.intersect_nearest(ray, isect_far);
+        let isect = bvh.intersect_nearest(ray, isect_far, &textures);
         test::black_box(isect);
     });
 }
@@ -699,11 +716,12 @@ fn bench_intersect_coherent_mray_suzanne(b: &mut test::Bencher) {
     let suzanne = Mesh::load("models/suzanne.obj");
     let bvh = Bvh::from_meshes(&[suzanne]);
     let rays = bench::mrays_inward_coherent(4096 / 8);
+    let textures = TextureSet::new();
     let mut rays_it = rays.iter().cycle();
     b.iter(|| {
         let ray = rays_it.next().unwrap();
         let isect_far = MIntersection::with_max_distance(1e5);
-        let isect = bvh.intersect_nearest(ray, isect_far);
+        let isect = bvh.intersect_nearest(ray, isect_far, &textures);
         test::black_box(isect);
     });
 }
@@ -714,11 +732,12 @@ fn bench_intersect_decoherent_mray_bunny(b: &mut test::Bencher) {
     let bunny = Mesh::load("models/stanford_bunny.obj");
     let bvh = Bvh::from_meshes(&[bunny]);
     let rays = bench::mrays_inward(4096 / 8);
+    let textures = TextureSet::new();
     let mut rays_it = rays.iter().cycle();
     b.iter(|| {
         let ray = rays_it.next().unwrap();
         let isect_far = MIntersection::with_max_distance(1e5);
-        let isect = bvh.intersect_nearest(ray, isect_far);
+        let isect = bvh.intersect_nearest(ray, isect_far, &textures);
         test::black_box(isect);
     });
 }
@@ -729,11 +748,12 @@ fn bench_intersect_coherent_mray_bunny(b: &mut test::Bencher) {
     let bunny = Mesh::load("models/stanford_bunny.obj");
     let bvh = Bvh::from_meshes(&[bunny]);
     let rays = bench::mrays_inward_coherent(4096 / 8);
+    let textures = TextureSet::new();
     let mut rays_it = rays.iter().cycle();
     b.iter(|| {
         let ray = rays_it.next().unwrap();
         let isect_far = MIntersection::with_max_distance(1e5);
-        let isect = bvh.intersect_nearest(ray, isect_far);
+        let isect = bvh.intersect_nearest(ray, isect_far, &textures);
         test::black_box(isect);
     });
 }
@@ -0,0 +1,323 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements a procedural daylight sky.
+//!
+//! The sky dome uses the analytic model of Preetham, Shirley, and Smits, "A
+//! Practical Analytic Model for Daylight", 1999. It describes the luminance
+//! and chromaticity of the clear sky as a function of the position of the sun
+//! and the turbidity (haziness) of the atmosphere. The sun itself is a small
+//! but very bright disk, that is attenuated by the atmosphere with the
+//! formulas from the appendix of the same paper.
+//!
+//! The sun is not part of the sky dome radiance. The dome is smooth, so it is
+//! fine to find it by BRDF sampling (or through windows, by sampling the
+//! window triangles). The sun covers a tiny solid angle, so it must be sampled
+//! directly. It gets its own sampling strategy, with its own weights for
+//! multiple importance sampling.
+//!
+//! The scene is y-up. The azimuth is measured in the xz-plane, from the
+//! positive x-axis towards the positive z-axis.
+
+use random::Rng;
+use simd::Mf32;
+use std::f32::consts::PI;
+use util::generate_slice8;
+use vector3::{MVector3, SVector3};
+
+pub struct Daylight {
+    /// Angle of the sun above the horizon in radians.
+    elevation: f32,
+
+    /// Angle of the sun in the horizontal plane in radians.
+    azimuth: f32,
+
+    /// Haziness of the atmosphere, 2 is very clear, 10 is hazy.
+    turbidity: f32,
+
+    /// Normalized direction towards the sun.
+    sun_direction: SVector3,
+
+    /// Perez function coefficients A through E for luminance Y, and for the
+    /// chromaticity coordinates x and y.
+    perez_lum: [f32; 5],
+    perez_x: [f32; 5],
+    perez_y: [f32; 5],
+
+    /// The luminance and chromaticity at the zenith, divided by the Perez
+    /// function at the zenith, so the Perez function can be multiplied by it
+    /// directly.
+    zenith_lum: f32,
+    zenith_x: f32,
+    zenith_y: f32,
+
+    /// Two vectors that together with the sun direction form an orthonormal
+    /// basis, to sample the sun disk.
+    sun_tangent: SVector3,
+    sun_bitangent: SVector3,
+
+    /// One minus the cosine of the angular radius of the sun disk. The cosine
+    /// itself is so close to one that it is useless in single precision.
+    sun_cap_height: f32,
+
+    /// The radiance of the sun disk.
+    sun_radiance: SVector3,
+}
+
+/// The angular radius of the sun as seen from earth, in radians.
+const SUN_RADIUS: f32 = 0.00465;
+
+/// The Preetham model produces luminance in kcd/m². This factor brings it in
+/// the range that the renderer works with.
+const EXPOSURE: f32 = 0.2;
+
+/// The illuminance of the sun above the atmosphere is roughly 128 klux, so in
+/// the same units as the sky.
+const SUN_ILLUMINANCE: f32 = 128.0;
+
+/// The fraction of the light that the ground reflects.
+const GROUND_ALBEDO: f32 = 0.3;
+
+/// Evaluates the Perez sky distribution function.
+fn perez(coefs: &[f32; 5], cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+    let (a, b, c, d, e) = (coefs[0], coefs[1], coefs[2], coefs[3], coefs[4]);
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+/// Converts CIE xyY to linear sRGB, clamping negative values.
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> SVector3 {
+    let cx = x / y * lum;
+    let cy = lum;
+    let cz = (1.0 - x - y) / y * lum;
+    let r = 3.2406 * cx - 1.5372 * cy - 0.4986 * cz;
+    let g = -0.9689 * cx + 1.8758 * cy + 0.0415 * cz;
+    let b = 0.0557 * cx - 0.2040 * cy + 1.0570 * cz;
+    SVector3::new(r.max(0.0), g.max(0.0), b.max(0.0))
+}
+
+/// Returns the fraction of sunlight at the given wavelength (in micrometer)
+/// that makes it through the atmosphere. Only Rayleigh and aerosol scattering
+/// are taken into account, the absorption by ozone and water vapour is small
+/// in the visible range.
+fn sun_transmittance(lambda: f32, turbidity: f32, air_mass: f32) -> f32 {
+    let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+    let beta = 0.04608 * turbidity - 0.04586;
+    let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+    rayleigh * aerosol
+}
+
+impl Daylight {
+    /// Creates a sky with the sun at the given elevation and azimuth, both in
+    /// radians. The turbidity should be between 2 and 10.
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Daylight {
+        assert!(turbidity >= 1.0, "turbidity must be at least 1");
+
+        // Keep the sun slightly above the horizon, the model breaks down below.
+        let elevation = elevation.max(0.01).min(0.5 * PI);
+        let t = turbidity;
+        let sun_direction = SVector3::new(elevation.cos() * azimuth.cos(),
+                                          elevation.sin(),
+                                          elevation.cos() * azimuth.sin());
+
+        // Coefficients from the appendix of the paper.
+        let perez_lum = [0.1787 * t - 1.4630,
+                         -0.3554 * t + 0.4275,
+                         -0.0227 * t + 5.3251,
+                         0.1206 * t - 2.5771,
+                         -0.0670 * t + 0.3703];
+        let perez_x = [-0.0193 * t - 0.2592,
+                       -0.0665 * t + 0.0008,
+                       -0.0004 * t + 0.2125,
+                       -0.0641 * t - 0.8989,
+                       -0.0033 * t + 0.0452];
+        let perez_y = [-0.0167 * t - 0.2608,
+                       -0.0950 * t + 0.0092,
+                       -0.0079 * t + 0.2102,
+                       -0.0441 * t - 1.6537,
+                       -0.0109 * t + 0.0529];
+
+        let theta_s = 0.5 * PI - elevation;
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let th = theta_s;
+        let th2 = th * th;
+        let th3 = th2 * th;
+        let t2 = t * t;
+        let zenith_x = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) +
+                       t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) +
+                       (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_y = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) +
+                       t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) +
+                       (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        // The sun is never exactly at the horizon, so it is never parallel to
+        // the x-axis.
+        let sun_tangent = SVector3::new(1.0, 0.0, 0.0).cross(sun_direction).normalized();
+
+        // At the zenith, theta is 0 and gamma is the zenith angle of the sun.
+        let cos_ts = theta_s.cos();
+
+        // The relative optical air mass (Kasten and Young, 1989), the amount of
+        // atmosphere that sunlight travels through, relative to the zenith.
+        let theta_s_deg = theta_s.to_degrees();
+        let air_mass = 1.0 / (cos_ts + 0.50572 * (96.07995 - theta_s_deg).powf(-1.6364));
+
+        // Evaluate the transmittance at typical wavelengths for red, green,
+        // and blue. The illuminance is spread over the disk of the sun.
+        let sun_cap_height = (1.0 - (SUN_RADIUS as f64).cos()) as f32;
+        let solid_angle = 2.0 * PI * sun_cap_height;
+        let scale = SUN_ILLUMINANCE * EXPOSURE / solid_angle;
+        let sun_radiance = SVector3::new(sun_transmittance(0.68, t, air_mass) * scale,
+                                         sun_transmittance(0.55, t, air_mass) * scale,
+                                         sun_transmittance(0.44, t, air_mass) * scale);
+
+        Daylight {
+            elevation: elevation,
+            azimuth: azimuth,
+            turbidity: turbidity,
+            sun_direction: sun_direction,
+            zenith_lum: zenith_lum / perez(&perez_lum, 1.0, theta_s, cos_ts),
+            zenith_x: zenith_x / perez(&perez_x, 1.0, theta_s, cos_ts),
+            zenith_y: zenith_y / perez(&perez_y, 1.0, theta_s, cos_ts),
+            perez_lum: perez_lum,
+            perez_x: perez_x,
+            perez_y: perez_y,
+            sun_tangent: sun_tangent,
+            sun_bitangent: sun_direction.cross(sun_tangent),
+            sun_cap_height: sun_cap_height,
+            sun_radiance: sun_radiance,
+        }
+    }
+
+    pub fn elevation(&self) -> f32 {
+        self.elevation
+    }
+
+    pub fn azimuth(&self) -> f32 {
+        self.azimuth
+    }
+
+    pub fn turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    /// Returns the radiance of the sky dome in a single direction.
+    fn sky_radiance_single(&self, direction: SVector3) -> SVector3 {
+        // Below the horizon there is ground. Instead of modelling its
+        // illumination properly, take the sky in the mirrored direction, dimmed
+        // by the albedo of the ground.
+        let (cos_theta, albedo) = if direction.y >= 0.0 {
+            (direction.y, 1.0)
+        } else {
+            (-direction.y, GROUND_ALBEDO)
+        };
+
+        // The model is not defined at the horizon itself.
+        let cos_theta = cos_theta.max(0.01);
+        let mirrored = SVector3::new(direction.x, cos_theta, direction.z);
+        let cos_gamma = mirrored.dot(self.sun_direction).max(-1.0).min(1.0);
+        let gamma = cos_gamma.acos();
+
+        let lum = self.zenith_lum * perez(&self.perez_lum, cos_theta, gamma, cos_gamma);
+        let x = self.zenith_x * perez(&self.perez_x, cos_theta, gamma, cos_gamma);
+        let y = self.zenith_y * perez(&self.perez_y, cos_theta, gamma, cos_gamma);
+
+        xyy_to_rgb(x, y, lum * EXPOSURE * albedo)
+    }
+
+    /// Returns the radiance of the sky dome in the given directions, excluding
+    /// the sun.
+    pub fn sky_radiance(&self, direction: MVector3) -> MVector3 {
+        let radiance = generate_slice8(|i| self.sky_radiance_single(direction.extract(i)));
+        MVector3::generate(|i| radiance[i])
+    }
+
+    /// Returns a mask with sign bit 1 for directions that do not point into the
+    /// sun disk.
+    fn outside_sun(&self, direction: MVector3) -> Mf32 {
+        // If the angle between the direction and the sun is theta, then the
+        // squared distance between the two unit vectors is 2 - 2cos(theta).
+        // This is precise for small angles, unlike the cosine itself. Allow a
+        // tiny bit of slack for rounding errors in the samples.
+        let to_sun = direction - MVector3::broadcast(self.sun_direction);
+        let max_dist_sqr = Mf32::broadcast(2.0 * self.sun_cap_height * 1.001);
+        max_dist_sqr - to_sun.norm_squared()
+    }
+
+    /// Returns the radiance of the sun for directions that point into the sun
+    /// disk, and zero otherwise.
+    pub fn sun_radiance(&self, direction: MVector3) -> MVector3 {
+        let outside = self.outside_sun(direction);
+        MVector3::broadcast(self.sun_radiance).pick(MVector3::zero(), outside)
+    }
+
+    /// Samples 8 directions uniformly in the cone of the sun disk. Returns the
+    /// directions and their probability density.
+    pub fn sample_sun(&self, rng: &mut Rng) -> (MVector3, Mf32) {
+        let phi = rng.sample_angle();
+        let u = rng.sample_unit();
+
+        // The height of the spherical cap up to the sampled point, which is
+        // one minus the cosine of the angle with the sun direction. Sampling
+        // it uniformly samples the cap uniformly.
+        let h = u * Mf32::broadcast(self.sun_cap_height);
+        let sin_theta = (h * (Mf32::broadcast(2.0) - h)).sqrt();
+
+        // The sun is sampled rarely compared to other things, and the cone is
+        // so narrow that the polynomial approximations are not good enough, so
+        // compute the sine and cosine precisely.
+        let cos_phi = phi.map(|x| x.cos());
+        let sin_phi = phi.map(|x| x.sin());
+
+        let s = MVector3::broadcast(self.sun_direction);
+        let t = MVector3::broadcast(self.sun_tangent);
+        let b = MVector3::broadcast(self.sun_bitangent);
+        let direction = s.mul_add(Mf32::one() - h, t.mul_add(sin_theta * cos_phi, b * (sin_theta * sin_phi)));
+
+        let pd = Mf32::broadcast(1.0 / (2.0 * PI * self.sun_cap_height));
+        (direction, pd)
+    }
+
+    /// Returns the probability density of sampling the given directions with
+    /// `sample_sun()`.
+    pub fn pd_sun(&self, direction: MVector3) -> Mf32 {
+        let pd = Mf32::broadcast(1.0 / (2.0 * PI * self.sun_cap_height));
+        pd.pick(Mf32::zero(), self.outside_sun(direction))
+    }
+}
+
+#[test]
+fn daylight_zenith_is_blue_and_horizon_is_brighter() {
+    let sky = Daylight::new(0.6, 0.0, 3.0);
+    let zenith = sky.sky_radiance_single(SVector3::new(0.0, 1.0, 0.0));
+    let horizon = sky.sky_radiance_single(SVector3::new(-0.3, 0.1, 0.95).normalized());
+
+    assert!(zenith.z > zenith.x, "zenith should be blue, but is {}", zenith);
+    assert!(horizon.y > zenith.y, "horizon should be brighter than zenith");
+}
+
+#[test]
+fn daylight_sun_samples_hit_sun() {
+    let sky = Daylight::new(0.6, 1.0, 3.0);
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    for _ in 0..64 {
+        let (direction, pd) = sky.sample_sun(&mut rng);
+        let radiance = sky.sun_radiance(direction);
+        let pd_lookup = sky.pd_sun(direction);
+        for i in 0..8 {
+            assert!(radiance.x.get_coord(i) > 0.0, "sampled direction misses the sun");
+            assert_eq!(pd.get_coord(i), pd_lookup.get_coord(i));
+        }
+    }
+
+    // The direction opposite to the sun is not in the disk.
+    let away = MVector3::broadcast(-sky.sun_direction);
+    assert_eq!(0.0, sky.pd_sun(away).0);
+    assert_eq!(0.0, sky.sun_radiance(away).x.0);
+}
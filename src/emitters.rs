@@ -0,0 +1,687 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements direct sampling of emissive triangles.
+//!
+//! Direct sampling picks a point on an emissive triangle and sends a ray
+//! towards it. There are two ways to pick the triangle:
+//!
+//!  * With an alias table, with a probability proportional to the power of the
+//!    triangle, so bright and large emitters get more samples. This is cheap,
+//!    and fine when there are only a few emitters.
+//!  * By walking down a hierarchy of emitters, where at every node the child
+//!    with the largest estimated contribution at the shading point is more
+//!    likely to be chosen. The estimate uses the power of all emitters in the
+//!    node, a bounding box, and a cone that bounds their normals. This is the
+//!    light BVH of Conty Estevez and Kulla, 2018. It is more expensive per
+//!    sample, but with thousands of emitters most of them contribute very
+//!    little at a given point, and the tree gets the samples where they matter.
+//!
+//! To weigh a ray with multiple importance sampling, we also need the
+//! probability density of direct sampling for a ray that was not generated by
+//! direct sampling. That density is the sum of the densities of all emissive
+//! triangles that the ray passes through before it hits something. (A direct
+//! sample behind that would be occluded, so it cannot produce the same path.)
+//! Usually that is only the triangle that the ray hits. To avoid visiting
+//! every emitter for every ray, only the triangles in nodes of the hierarchy
+//! that the ray intersects are tested. When sampling with the tree, the
+//! probability of picking a triangle is the product of the probabilities of
+//! the choices along the way, and those are computed during the same traversal.
+
+use aabb::Aabb;
+use alias_table::AliasTable;
+use material::MDirectSample;
+use random::Rng;
+use ray::MRay;
+use simd::Mf32;
+use std::f32::consts::FRAC_PI_2;
+use triangle::Triangle;
+use util::generate_slice8;
+use vector3::{Axis, MVector3, SVector3};
+
+#[cfg(test)]
+use material::SMaterial;
+
+/// One node in the emitter hierarchy.
+struct EmitterNode {
+    aabb: Aabb,
+
+    /// For leaf nodes, the index of the triangle, for internal nodes, the index
+    /// of the first child. The second child is at `index + 1`.
+    index: u32,
+
+    is_leaf: bool,
+
+    /// The sum of the weights of the triangles in the node.
+    power: f32,
+
+    /// The axis of a cone that bounds the normals of the triangles. Emitters
+    /// emit on both sides, so the cone really bounds lines rather than vectors:
+    /// a normal n is in the cone if either n or -n is.
+    axis: SVector3,
+
+    /// The half opening angle of the normal cone in radians.
+    theta_o: f32,
+}
+
+/// Strategies for picking an emissive triangle to sample.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EmitterSampling {
+    /// Pick triangles proportional to their power.
+    Power,
+
+    /// Pick triangles by their estimated contribution at the shading point.
+    Tree,
+}
+
+/// The triangles in a scene that are eligible for direct sampling.
+pub struct Emitters {
+    triangles: Vec<Triangle>,
+
+    /// The hierarchy of bounding boxes, the root is at index 0. This is empty
+    /// if there are no emitters.
+    nodes: Vec<EmitterNode>,
+
+    /// Distribution over the triangles used to pick one with power sampling.
+    /// This is `None` if there are no emitters.
+    table: Option<AliasTable>,
+
+    sampling: EmitterSampling,
+}
+
+/// With more emitters than this, sampling with the tree is worth its cost.
+const TREE_SAMPLING_THRESHOLD: usize = 16;
+
+impl EmitterNode {
+    fn new() -> EmitterNode {
+        EmitterNode {
+            aabb: Aabb::zero(),
+            index: 0,
+            is_leaf: true,
+            power: 0.0,
+            axis: SVector3::new(0.0, 0.0, 1.0),
+            theta_o: 0.0,
+        }
+    }
+
+    /// Estimates how much light the emitters in this node contribute to a
+    /// surface at the given position with the given normal.
+    ///
+    /// The node is approximated by the sphere around its bounding box. The
+    /// angles to the receiver normal and to the emitter normals are reduced by
+    /// the angle that the sphere subtends, and by the spread of the normal
+    /// cone, so the estimate is optimistic. This matters: an estimate of zero
+    /// means that the emitters are never sampled.
+    fn importance(&self, position: SVector3, normal: SVector3) -> f32 {
+        let center = (self.aabb.origin + self.aabb.far) * 0.5;
+        let radius_sqr = self.aabb.size().norm_squared() * 0.25;
+        let to_center = center - position;
+
+        // Inside the bounding sphere the distance is clamped to the radius, so
+        // that the estimate does not blow up for emitters close by.
+        let dist_sqr = to_center.norm_squared().max(radius_sqr).max(1e-8);
+        let dist = dist_sqr.sqrt();
+        let direction = to_center * dist.recip();
+
+        let sin_theta_u = (radius_sqr.sqrt() / dist).min(1.0);
+        let theta_u = sin_theta_u.asin();
+
+        let cos_theta_i = normal.dot(direction).max(-1.0).min(1.0);
+        let theta_i = (cos_theta_i.acos() - theta_u).max(0.0);
+        if theta_i >= FRAC_PI_2 {
+            return 0.0;
+        }
+
+        let cos_theta_e = self.axis.dot(direction).abs().min(1.0);
+        let theta_e = (cos_theta_e.acos() - self.theta_o - theta_u).max(0.0);
+        if theta_e >= FRAC_PI_2 {
+            return 0.0;
+        }
+
+        self.power * theta_e.cos() * theta_i.cos() / dist_sqr
+    }
+}
+
+/// Returns a cone that bounds the lines in the cones a and b.
+fn union_cones(a: (SVector3, f32), b: (SVector3, f32)) -> (SVector3, f32) {
+    let (axis_a, theta_a) = a;
+    let (axis_b, theta_b) = b;
+
+    // Flip b into the hemisphere of a. For lines that does not change
+    // anything, but it keeps the sum from cancelling.
+    let axis_b = if axis_a.dot(axis_b) < 0.0 { -axis_b } else { axis_b };
+    let sum = axis_a + axis_b;
+
+    if sum.norm_squared() < 1e-6 {
+        return (axis_a, FRAC_PI_2);
+    }
+
+    let axis = sum.normalized();
+    let angle_to = |v: SVector3| v.dot(axis).abs().min(1.0).acos();
+    let theta = (angle_to(axis_a) + theta_a).max(angle_to(axis_b) + theta_b);
+
+    // An opening angle of pi/2 contains every line already.
+    (axis, theta.min(FRAC_PI_2))
+}
+
+/// Recursively builds the node at index `into` for the given triangles.
+fn build_node(triangles: &[Triangle],
+              weights: &[f32],
+              indices: &mut [u32],
+              nodes: &mut Vec<EmitterNode>,
+              into: usize) {
+    let mut vertices = Vec::with_capacity(indices.len() * 3);
+    let mut barycenters = Vec::with_capacity(indices.len());
+    for &i in indices.iter() {
+        let tri = &triangles[i as usize];
+        vertices.push(tri.v0);
+        vertices.push(tri.v1);
+        vertices.push(tri.v2);
+        barycenters.push(tri.barycenter());
+    }
+
+    nodes[into].aabb = Aabb::enclose_points(&vertices);
+
+    if indices.len() == 1 {
+        let i = indices[0] as usize;
+        nodes[into].index = i as u32;
+        nodes[into].is_leaf = true;
+        nodes[into].power = weights[i];
+        nodes[into].axis = triangles[i].normal();
+        nodes[into].theta_o = 0.0;
+        return;
+    }
+
+    // The number of emitters is usually small compared to the number of
+    // triangles in the scene, so there is no need for a fancy heuristic here.
+    // Split at the median along the axis in which the barycenters are spread
+    // out the most.
+    let size = Aabb::enclose_points(&barycenters).size();
+    let axis = if size.x > size.y && size.x > size.z {
+        Axis::X
+    } else if size.y > size.z {
+        Axis::Y
+    } else {
+        Axis::Z
+    };
+
+    indices.sort_by(|&a, &b| {
+        let ca = triangles[a as usize].barycenter().get_coord(axis);
+        let cb = triangles[b as usize].barycenter().get_coord(axis);
+        ca.partial_cmp(&cb).unwrap()
+    });
+
+    let child_index = nodes.len();
+    nodes.push(EmitterNode::new());
+    nodes.push(EmitterNode::new());
+    nodes[into].index = child_index as u32;
+    nodes[into].is_leaf = false;
+
+    let mid = indices.len() / 2;
+    let (left, right) = indices.split_at_mut(mid);
+    build_node(triangles, weights, left, nodes, child_index + 0);
+    build_node(triangles, weights, right, nodes, child_index + 1);
+
+    let (axis, theta_o) = {
+        let a = &nodes[child_index + 0];
+        let b = &nodes[child_index + 1];
+        union_cones((a.axis, a.theta_o), (b.axis, b.theta_o))
+    };
+    nodes[into].power = nodes[child_index + 0].power + nodes[child_index + 1].power;
+    nodes[into].axis = axis;
+    nodes[into].theta_o = theta_o;
+}
+
+/// Asserts that the values where active has sign bit 0 (positive) are nonzero.
+fn debug_assert_all_nonzero(x: Mf32, active: Mf32, tag: &str) {
+    debug_assert!(x.0 != 0.0 || active.0.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.1 != 0.0 || active.1.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.2 != 0.0 || active.2.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.3 != 0.0 || active.3.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.4 != 0.0 || active.4.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.5 != 0.0 || active.5.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.6 != 0.0 || active.6.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+    debug_assert!(x.7 != 0.0 || active.7.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+}
+
+impl Emitters {
+    /// Builds the sampling structures for the given triangles. The weight of a
+    /// triangle should be proportional to the power that it emits.
+    ///
+    /// Picks the sampling strategy based on the number of emitters.
+    pub fn new(triangles: Vec<Triangle>, weights: &[f32]) -> Emitters {
+        let sampling = if triangles.len() > TREE_SAMPLING_THRESHOLD {
+            EmitterSampling::Tree
+        } else {
+            EmitterSampling::Power
+        };
+        Emitters::with_sampling(triangles, weights, sampling)
+    }
+
+    /// Builds the sampling structures for the given triangles, to be sampled
+    /// with the given strategy.
+    pub fn with_sampling(triangles: Vec<Triangle>,
+                         weights: &[f32],
+                         sampling: EmitterSampling)
+                         -> Emitters {
+        assert_eq!(triangles.len(), weights.len());
+
+        if triangles.is_empty() {
+            return Emitters {
+                triangles: triangles,
+                nodes: Vec::new(),
+                table: None,
+                sampling: sampling,
+            };
+        }
+
+        let mut indices: Vec<u32> = (0..triangles.len() as u32).collect();
+        let mut nodes = Vec::with_capacity(triangles.len() * 2 - 1);
+        nodes.push(EmitterNode::new());
+        build_node(&triangles, weights, &mut indices, &mut nodes, 0);
+
+        Emitters {
+            triangles: triangles,
+            nodes: nodes,
+            table: Some(AliasTable::new(weights)),
+            sampling: sampling,
+        }
+    }
+
+    /// Returns the number of emissive triangles.
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn sampling(&self) -> EmitterSampling {
+        self.sampling
+    }
+
+    pub fn set_sampling(&mut self, sampling: EmitterSampling) {
+        self.sampling = sampling;
+    }
+
+    /// Returns the probability of descending into the first child of the
+    /// internal node, for a surface at the given position with given normal.
+    fn probability_first(&self, node: &EmitterNode, position: SVector3, normal: SVector3) -> f32 {
+        let first = &self.nodes[node.index as usize + 0];
+        let second = &self.nodes[node.index as usize + 1];
+        let importance_first = first.importance(position, normal);
+        let importance_second = second.importance(position, normal);
+        let importance = importance_first + importance_second;
+
+        // If neither child appears to contribute, any choice is as good as
+        // another, but the probabilities must still sum to one.
+        if importance > 0.0 {
+            importance_first / importance
+        } else if first.power + second.power > 0.0 {
+            first.power / (first.power + second.power)
+        } else {
+            0.5
+        }
+    }
+
+    /// Walks down the tree to pick a triangle for a surface at the given
+    /// position. Returns the index of the triangle and the probability of
+    /// picking it.
+    fn sample_tree(&self, position: SVector3, normal: SVector3, mut u: f32) -> (u32, f32) {
+        let mut pmf = 1.0;
+        let mut node = &self.nodes[0];
+
+        while !node.is_leaf {
+            let p_first = self.probability_first(node, position, normal);
+
+            // Reuse the random number for the next level by stretching the
+            // part of the unit interval that was selected back to the unit
+            // interval. Clamp it to stay below one in case of rounding errors.
+            if u < p_first {
+                u = u / p_first;
+                pmf = pmf * p_first;
+                node = &self.nodes[node.index as usize + 0];
+            } else {
+                u = (u - p_first) / (1.0 - p_first);
+                pmf = pmf * (1.0 - p_first);
+                node = &self.nodes[node.index as usize + 1];
+            }
+
+            u = u.min(0.99999994);
+        }
+
+        (node.index, pmf)
+    }
+
+    /// Returns 8 random points on 8 random emissive triangles, for surfaces at
+    /// the given positions with the given normals.
+    ///
+    /// There must be at least one emitter.
+    pub fn sample(&self, position: MVector3, normal: MVector3, rng: &mut Rng) -> MDirectSample {
+        let table = self.table.as_ref().expect("cannot direct sample without emitters");
+
+        // Pick a triangle for every coordinate. This has to be done serially,
+        // unfortunately.
+        let random_bits = rng.sample_u32();
+        let coin_flips = rng.sample_unit();
+        let (indices, pmf) = match self.sampling {
+            EmitterSampling::Power => {
+                let indices = generate_slice8(|i| table.sample(random_bits[i], coin_flips.get_coord(i)));
+                let pmf = Mf32::generate(|i| table.pmf(indices[i]));
+                (indices, pmf)
+            }
+            EmitterSampling::Tree => {
+                let picks = generate_slice8(|i| {
+                    self.sample_tree(position.extract(i), normal.extract(i), coin_flips.get_coord(i))
+                });
+                let indices = generate_slice8(|i| picks[i].0);
+                let pmf = Mf32::generate(|i| picks[i].1);
+                (indices, pmf)
+            }
+        };
+        self.sample_triangles(indices, pmf, rng)
+    }
+
+    /// Returns 8 random points on 8 random emissive triangles, picked with a
+    /// probability proportional to their power, regardless of the sampling
+    /// strategy. This is for paths that start at the emitters, where there is
+    /// no surface to estimate the contribution for.
+    ///
+    /// There must be at least one emitter.
+    pub fn sample_by_power(&self, rng: &mut Rng) -> MDirectSample {
+        let table = self.table.as_ref().expect("cannot sample without emitters");
+        let random_bits = rng.sample_u32();
+        let coin_flips = rng.sample_unit();
+        let indices = generate_slice8(|i| table.sample(random_bits[i], coin_flips.get_coord(i)));
+        let pmf = Mf32::generate(|i| table.pmf(indices[i]));
+        self.sample_triangles(indices, pmf, rng)
+    }
+
+    /// Picks a uniformly distributed point on each of the given triangles.
+    fn sample_triangles(&self, indices: [u32; 8], pmf: Mf32, rng: &mut Rng) -> MDirectSample {
+        let tris = generate_slice8(|i| unsafe { self.triangles.get_unchecked(indices[i] as usize) });
+
+        // Gather the vertices of the triangles into SIMD vectors, so from now
+        // on we are not serial any more.
+        let v0 = MVector3::generate(|i| tris[i].v0);
+        let v1 = MVector3::generate(|i| tris[i].v1);
+        let v2 = MVector3::generate(|i| tris[i].v2);
+
+        let e1 = v0 - v2;
+        let e2 = v1 - v0;
+        let normal_denorm = e1.cross(e2);
+        let cross_norm_recip = normal_denorm.norm_squared().rsqrt();
+        let normal = normal_denorm * cross_norm_recip;
+        let area = Mf32::broadcast(0.5) * cross_norm_recip.recip_fast();
+
+        let u = rng.sample_unit();
+        let v = rng.sample_unit();
+        // If u + v > 1, the point lies outside of the triangle, and s will have
+        // negative sign. If the point is inside the triangle, s will have
+        // positive sign.
+        let s = (Mf32::one() - u) - v;
+        // If the point lies outside the triangle, it lies in the other half of
+        // the parallellogram, so transform the coordinates to get them into the
+        // correct triangle again.
+        let u = u.pick(Mf32::one() - u, s);
+        let v = v.pick(Mf32::one() - v, s);
+
+        let p = e2.mul_add(v, e1.neg_mul_add(u, v0));
+
+        let ds = MDirectSample {
+            position: p,
+            normal: normal,
+            area: area,
+            pmf: pmf,
+        };
+
+        // Prevent NaNs from creeping in, and ensure that the sample is valid.
+        debug_assert!(normal.all_finite());
+        debug_assert!(area.all_finite());
+        debug_assert!(area.all_sign_bits_positive(), "area must be positive");
+        debug_assert!(pmf.all_sign_bits_positive(), "probability must be positive");
+
+        ds
+    }
+
+    /// Returns the probability density of direct sampling for the direction of
+    /// the given ray, for surfaces at the given positions with given normals.
+    ///
+    /// Only triangles closer than `max_distance` along the ray are taken into
+    /// account.
+    pub fn pd(&self, position: MVector3, normal: MVector3, ray: &MRay, max_distance: Mf32) -> Mf32 {
+        let mut pd_total = Mf32::zero();
+
+        if self.nodes.is_empty() {
+            return pd_total;
+        }
+
+        let table = self.table.as_ref().unwrap();
+        let positions = generate_slice8(|i| position.extract(i));
+        let normals = generate_slice8(|i| normal.extract(i));
+
+        // Along with the node index, the stack holds the probability of
+        // reaching the node when sampling with the tree.
+        let mut stack = Vec::with_capacity(16);
+        stack.push((0, Mf32::one()));
+
+        while let Some((i, pmf_node)) = stack.pop() {
+            // The indices in the hierarchy are valid by construction.
+            let node = unsafe { self.nodes.get_unchecked(i) };
+
+            if !node.aabb.intersect(ray).any_masked(ray.active) {
+                continue;
+            }
+
+            if !node.is_leaf {
+                let (pmf_first, pmf_second) = match self.sampling {
+                    EmitterSampling::Power => (pmf_node, pmf_node),
+                    EmitterSampling::Tree => {
+                        let p_first = Mf32::generate(|k| {
+                            self.probability_first(node, positions[k], normals[k])
+                        });
+                        (pmf_node * p_first, pmf_node * (Mf32::one() - p_first))
+                    }
+                };
+                stack.push((node.index as usize + 0, pmf_first));
+                stack.push((node.index as usize + 1, pmf_second));
+                continue;
+            }
+
+            // The probability density for the point on the triangle is simply
+            // 1/area, but we want to know the probability of the ray direction,
+            // not the probability of the point. The conversion factor is
+            // cos(phi)/r^2, where phi is the angle between the ray and the
+            // surface normal. A hand-waving justification: imagine a small
+            // triangle on a unit hemisphere, small enough that its area equals
+            // the solid angle it subtends. Then the pdf for the point and the
+            // ray will be equal (1/area). Now move the triangle away. The solid
+            // angle decreases proportional to r^2, so we must compensate the pdf
+            // to keep it normalized. Now rotate the small triangle. When
+            // cos(phi) is 0, the projection is a line of zero surface area, but
+            // it needs to integrate to 1, so the pdf goes to infinity as
+            // cos(phi) goes to 0.
+            let triangle = unsafe { self.triangles.get_unchecked(node.index as usize) };
+            let sample_isect = triangle.intersect_direct(ray);
+            let distance_sqr = sample_isect.distance * sample_isect.distance;
+            // Add a small constant to avoid division by zero later on.
+            let dot_emissive = sample_isect.normal.dot(ray.direction).abs() + Mf32::broadcast(0.0001);
+            let pmf = match self.sampling {
+                EmitterSampling::Power => Mf32::broadcast(table.pmf(node.index)),
+                EmitterSampling::Tree => pmf_node,
+            };
+            let pd = pmf * distance_sqr * (sample_isect.area * dot_emissive).recip_fast();
+
+            debug_assert_all_nonzero(sample_isect.area, ray.active, "area");
+            debug_assert_all_nonzero(dot_emissive, ray.active, "dot_emissive");
+
+            // Add the probability density if the triangle was intersected. If
+            // the triangle was not intersected, the probability of sampling it
+            // directly was 0. The distance is not as accurate as the one from
+            // the regular intersection, so allow a bit of slack.
+            let beyond = sample_isect.distance.geq(max_distance * Mf32::broadcast(1.001));
+            pd_total = (pd_total + pd).pick(pd_total, sample_isect.mask | beyond);
+
+            debug_assert!(pd_total.all_finite());
+            debug_assert!(pd_total.all_sign_bits_positive(),
+                          "probability density must be positive");
+        }
+
+        pd_total
+    }
+}
+
+#[cfg(test)]
+fn unit_triangle_at(z: f32) -> Triangle {
+    Triangle::new(
+        SVector3::new(0.0, 1.0, z),
+        SVector3::new(-1.0, -1.0, z),
+        SVector3::new(1.0, -1.0, z),
+        SMaterial::sky(),
+    )
+}
+
+#[test]
+fn emitters_pd_sums_triangles_along_ray() {
+    use ray::SRay;
+
+    // Two triangles of area 2, one behind the other, and one far off to the
+    // side that should never be considered.
+    let mut side = unit_triangle_at(1.0);
+    side.v0.x += 10.0;
+    side.v1.x += 10.0;
+    side.v2.x += 10.0;
+    let triangles = vec![unit_triangle_at(1.0), unit_triangle_at(2.0), side];
+    let emitters = Emitters::with_sampling(triangles, &[1.0, 3.0, 4.0], EmitterSampling::Power);
+
+    let position = MVector3::zero();
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    let ray = MRay::broadcast(&SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, 1.0)));
+    let far = Mf32::broadcast(1.0e5);
+    let pd = emitters.pd(position, normal, &ray, far);
+
+    // The triangles are picked with probability 1/8 and 3/8, the area is 2 and
+    // the distances are 1 and 2.
+    let expected = 0.125 * 1.0 / 2.0 + 0.375 * 4.0 / 2.0;
+    assert!((pd.0 - expected).abs() < 0.01 * expected,
+            "expected pd {} but found {}", expected, pd.0);
+
+    // A ray that misses all triangles has zero density.
+    let ray = MRay::broadcast(&SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, -1.0)));
+    assert_eq!(0.0, emitters.pd(position, normal, &ray, far).0);
+
+    // When the ray is blocked before the second triangle, only the first one
+    // counts.
+    let ray = MRay::broadcast(&SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, 1.0)));
+    let pd = emitters.pd(position, normal, &ray, Mf32::broadcast(1.5));
+    let expected = 0.125 * 1.0 / 2.0;
+    assert!((pd.0 - expected).abs() < 0.01 * expected,
+            "expected pd {} but found {}", expected, pd.0);
+}
+
+#[test]
+fn emitters_sample_lies_on_triangle() {
+    let triangles = vec![unit_triangle_at(1.0), unit_triangle_at(2.0)];
+    let emitters = Emitters::with_sampling(triangles, &[1.0, 1.0], EmitterSampling::Power);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let position = MVector3::zero();
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+
+    for _ in 0..512 {
+        let ds = emitters.sample(position, normal, &mut rng);
+        for i in 0..8 {
+            let z = ds.position.z.get_coord(i);
+            assert!((z - 1.0).abs() < 1e-5 || (z - 2.0).abs() < 1e-5);
+            assert_eq!(0.5, ds.pmf.get_coord(i));
+        }
+    }
+}
+
+#[cfg(test)]
+fn stacked_emitters(n: usize) -> Emitters {
+    // Triangles at z = 1, 2, ..., n, with varying weights, and some of them
+    // tilted.
+    let mut triangles = Vec::with_capacity(n);
+    let mut weights = Vec::with_capacity(n);
+    for k in 0..n {
+        let mut tri = unit_triangle_at((k + 1) as f32);
+        if k % 3 == 2 {
+            tri.v0.z += 0.5;
+        }
+        triangles.push(tri);
+        weights.push(1.0 + (k % 4) as f32);
+    }
+    Emitters::with_sampling(triangles, &weights, EmitterSampling::Tree)
+}
+
+#[test]
+fn emitters_tree_samples_proportional_to_pmf() {
+    let n = 24;
+    let emitters = stacked_emitters(n);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let position = MVector3::zero();
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    let mut counts = vec![0u32; n];
+    let mut pmfs = vec![0.0f32; n];
+    let iterations = 4096;
+
+    for _ in 0..iterations {
+        let ds = emitters.sample(position, normal, &mut rng);
+        for i in 0..8 {
+            // The closest vertex of triangle k is at z = k + 1.
+            let k = ds.position.z.get_coord(i).floor() as usize - 1;
+            counts[k] += 1;
+            pmfs[k] = ds.pmf.get_coord(i);
+        }
+    }
+
+    // Closer triangles should get more samples, so the tree must not be
+    // sampling uniformly.
+    assert!(counts[0] > counts[n - 1]);
+
+    for k in 0..n {
+        let frequency = counts[k] as f32 / (iterations * 8) as f32;
+        assert!((frequency - pmfs[k]).abs() < 0.01,
+                "triangle {} has frequency {} but pmf {}", k, frequency, pmfs[k]);
+    }
+}
+
+#[test]
+fn emitters_tree_pd_matches_sample_pmf() {
+    use ray::SRay;
+
+    let n = 24;
+    let emitters = stacked_emitters(n);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let position = MVector3::zero();
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+
+    // A ray straight up hits every triangle, the tilted ones at an angle, so
+    // the density is a sum over all triangles. Find the pmf of every triangle
+    // by sampling, and the normal and area from the triangles.
+    let ray = MRay::broadcast(&SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, 1.0)));
+    let mut expected = 0.0;
+    let mut found = vec![false; n];
+
+    while found.iter().any(|&f| !f) {
+        let ds = emitters.sample(position, normal, &mut rng);
+        for i in 0..8 {
+            let k = ds.position.z.get_coord(i).floor() as usize - 1;
+            if found[k] {
+                continue;
+            }
+            found[k] = true;
+
+            let triangle = &emitters.triangles[k];
+            let isect = triangle.intersect_direct(&ray);
+            let distance = isect.distance.0;
+            let cos_phi = triangle.normal().z.abs();
+            expected += ds.pmf.get_coord(i) * distance * distance / (triangle.area() * cos_phi);
+        }
+    }
+
+    let pd = emitters.pd(position, normal, &ray, Mf32::broadcast(1.0e5));
+    assert!((pd.0 - expected).abs() < 0.01 * expected,
+            "expected pd {} but found {}", expected, pd.0);
+}
@@ -0,0 +1,332 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements lighting by an environment map.
+//!
+//! The environment map is an equirectangular image: the horizontal axis maps
+//! to the azimuth, and the vertical axis to the inclination, with the top row
+//! of the image looking straight up (positive y). Rays that escape the scene
+//! get the radiance of the pixel in their direction.
+//!
+//! An outdoor environment has most of its energy in a small part of the
+//! image, typically the sun. Sampling directions uniformly, or proportional to
+//! the BRDF, will rarely find it. So the map is also importance sampled, with a
+//! probability proportional to the luminance of a pixel, times the solid angle
+//! that it covers. This is done with a two-dimensional distribution: first a
+//! row is picked from the marginal distribution over rows, and then a column
+//! from the conditional distribution of that row.
+
+use exr::ExrImage;
+use hdr::HdrImage;
+use random::Rng;
+use simd::Mf32;
+use std::f32::consts::PI;
+use std::path::Path;
+use util::generate_slice8;
+use vector3::{MVector3, SVector3};
+
+/// A piecewise constant distribution over the unit interval.
+struct Distribution1D {
+    /// The function that the distribution is proportional to.
+    func: Vec<f32>,
+
+    /// The cumulative distribution function at the boundaries of the pieces.
+    /// This has one more element than `func`.
+    cdf: Vec<f32>,
+
+    /// The integral of `func` over the unit interval.
+    integral: f32,
+}
+
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    pixels: Vec<SVector3>,
+
+    /// For every row, the distribution over the columns.
+    conditional: Vec<Distribution1D>,
+
+    /// The distribution over the rows.
+    marginal: Distribution1D,
+}
+
+impl Distribution1D {
+    fn new(func: Vec<f32>) -> Distribution1D {
+        let n = func.len();
+        assert!(n > 0, "distribution needs at least one piece");
+
+        // Accumulate in f64, large images have many pieces.
+        let mut cdf = Vec::with_capacity(n + 1);
+        let mut acc = 0.0f64;
+        cdf.push(0.0);
+        for &f in &func {
+            acc += f as f64 / n as f64;
+            cdf.push(acc as f32);
+        }
+        let integral = acc as f32;
+
+        // If the function is zero everywhere, fall back to a uniform
+        // distribution, so that sampling still works.
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 { (*c as f64 / acc) as f32 } else { i as f32 / n as f32 };
+        }
+
+        Distribution1D {
+            func: func,
+            cdf: cdf,
+            integral: integral,
+        }
+    }
+
+    /// Returns the probability density at the given piece.
+    fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 { self.func[index] / self.integral } else { 1.0 }
+    }
+
+    /// Maps a number uniformly distributed in [0, 1) to a number in [0, 1)
+    /// distributed with this distribution. Returns that number and the index of
+    /// the piece that it lies in.
+    fn sample(&self, u: f32) -> (f32, usize) {
+        let n = self.func.len();
+
+        // Find the first piece whose upper boundary is beyond u. Pieces with
+        // zero probability have zero width and are skipped this way.
+        let mut lo = 0;
+        let mut hi = n - 1;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid + 1] > u {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+
+        // Find out where in the piece u lies, and map that to the same relative
+        // position within the piece.
+        let width = self.cdf[lo + 1] - self.cdf[lo];
+        let offset = if width > 0.0 { (u - self.cdf[lo]) / width } else { 0.5 };
+        let x = (lo as f32 + offset.max(0.0).min(1.0)) / n as f32;
+
+        (x.min(0.99999994), lo)
+    }
+}
+
+fn luminance(color: SVector3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Returns the equirectangular coordinates in [0, 1) for a normalized
+/// direction.
+fn direction_to_uv(direction: SVector3) -> (f32, f32) {
+    let phi = direction.z.atan2(direction.x);
+    let theta = direction.y.max(-1.0).min(1.0).acos();
+    let u = phi * (0.5 / PI) + 0.5;
+    let v = theta * (1.0 / PI);
+    (u.max(0.0).min(0.99999994), v.max(0.0).min(0.99999994))
+}
+
+/// Drops the alpha channel of an OpenEXR image, and expands luminance to RGB.
+fn exr_to_rgb(image: ExrImage) -> HdrImage {
+    let stride = image.channels as usize;
+    let pixels = image.data.chunks(stride).map(|px| {
+        if stride < 3 {
+            SVector3::new(px[0], px[0], px[0])
+        } else {
+            SVector3::new(px[0], px[1], px[2])
+        }
+    }).collect();
+
+    HdrImage {
+        width: image.width,
+        height: image.height,
+        pixels: pixels,
+    }
+}
+
+/// Returns the normalized direction for equirectangular coordinates, and the
+/// sine of the inclination.
+fn uv_to_direction(u: f32, v: f32) -> (SVector3, f32) {
+    let phi = (u - 0.5) * (2.0 * PI);
+    let theta = v * PI;
+    let sin_theta = theta.sin();
+    let direction = SVector3::new(sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin());
+    (direction, sin_theta)
+}
+
+impl EnvironmentMap {
+    pub fn new(image: HdrImage) -> EnvironmentMap {
+        let w = image.width as usize;
+        let h = image.height as usize;
+        assert!(w > 0 && h > 0, "environment map must not be empty");
+
+        // Rows near the poles cover less solid angle than rows near the
+        // horizon, so weigh them by the sine of the inclination.
+        let mut conditional = Vec::with_capacity(h);
+        for y in 0..h {
+            let sin_theta = ((y as f32 + 0.5) * PI / h as f32).sin();
+            let row = &image.pixels[y * w..(y + 1) * w];
+            let func = row.iter().map(|&px| luminance(px) * sin_theta).collect();
+            conditional.push(Distribution1D::new(func));
+        }
+
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral).collect());
+
+        EnvironmentMap {
+            width: image.width,
+            height: image.height,
+            pixels: image.pixels,
+            conditional: conditional,
+            marginal: marginal,
+        }
+    }
+
+    /// Loads an environment map from a Radiance HDR or an OpenEXR file. The
+    /// format is determined by the extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> EnvironmentMap {
+        let path = path.as_ref();
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or(String::new());
+
+        let image = match &extension[..] {
+            "exr" => exr_to_rgb(ExrImage::load(path)),
+            "hdr" | "pic" => HdrImage::load(path),
+            _ => panic!("environment map must be an .hdr or .exr file"),
+        };
+
+        EnvironmentMap::new(image)
+    }
+
+    /// Returns the row and column of the pixel at the given coordinates.
+    fn pixel_index(&self, u: f32, v: f32) -> (usize, usize) {
+        let row = ((v * self.height as f32) as usize).min(self.height as usize - 1);
+        let col = ((u * self.width as f32) as usize).min(self.width as usize - 1);
+        (row, col)
+    }
+
+    /// Returns the probability density with respect to solid angle, for
+    /// a direction in the given pixel.
+    fn pd_pixel(&self, row: usize, col: usize, sin_theta: f32) -> f32 {
+        // The density with respect to the image coordinates, which must be
+        // converted to a density over the sphere. The image spans 2pi
+        // horizontally and pi vertically, and a solid angle element is
+        // sin(theta) dtheta dphi.
+        let pd_uv = self.marginal.pdf(row) * self.conditional[row].pdf(col);
+        if sin_theta > 0.0 {
+            pd_uv / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the radiance of the environment in the given directions.
+    pub fn radiance(&self, direction: MVector3) -> MVector3 {
+        let pixels = generate_slice8(|i| {
+            let (u, v) = direction_to_uv(direction.extract(i));
+            let (row, col) = self.pixel_index(u, v);
+            self.pixels[row * self.width as usize + col]
+        });
+        MVector3::generate(|i| pixels[i])
+    }
+
+    /// Samples 8 directions proportional to the luminance of the environment.
+    /// Returns the directions and their probability density.
+    pub fn sample(&self, rng: &mut Rng) -> (MVector3, Mf32) {
+        let u1 = rng.sample_unit();
+        let u2 = rng.sample_unit();
+
+        // The distributions can only be sampled serially.
+        let samples = generate_slice8(|i| {
+            let (v, row) = self.marginal.sample(u1.get_coord(i));
+            let (u, col) = self.conditional[row].sample(u2.get_coord(i));
+            let (direction, sin_theta) = uv_to_direction(u, v);
+            (direction, self.pd_pixel(row, col, sin_theta))
+        });
+
+        let direction = MVector3::generate(|i| samples[i].0);
+        let pd = Mf32::generate(|i| samples[i].1);
+
+        debug_assert!(direction.all_finite());
+        debug_assert!(pd.all_sign_bits_positive(), "probability density must be positive");
+
+        (direction, pd)
+    }
+
+    /// Returns the probability density of sampling the given directions.
+    pub fn pd(&self, direction: MVector3) -> Mf32 {
+        Mf32::generate(|i| {
+            let (u, v) = direction_to_uv(direction.extract(i));
+            let (row, col) = self.pixel_index(u, v);
+            let sin_theta = (v * PI).sin();
+            self.pd_pixel(row, col, sin_theta)
+        })
+    }
+}
+
+#[cfg(test)]
+fn gradient_image(width: u32, height: u32) -> HdrImage {
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let a = (x + 1) as f32 * (height - y) as f32;
+            pixels.push(SVector3::new(a, a, a));
+        }
+    }
+    HdrImage {
+        width: width,
+        height: height,
+        pixels: pixels,
+    }
+}
+
+#[test]
+fn environment_uv_roundtrips() {
+    for &(u, v) in &[(0.1, 0.2), (0.5, 0.5), (0.9, 0.7), (0.3, 0.95)] {
+        let (direction, _) = uv_to_direction(u, v);
+        let (u2, v2) = direction_to_uv(direction);
+        assert!((u - u2).abs() < 1e-4 && (v - v2).abs() < 1e-4,
+                "({}, {}) became ({}, {})", u, v, u2, v2);
+    }
+}
+
+#[test]
+fn environment_pd_matches_sample() {
+    let env = EnvironmentMap::new(gradient_image(16, 8));
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    for _ in 0..64 {
+        let (direction, pd) = env.sample(&mut rng);
+        let pd_lookup = env.pd(direction);
+        for i in 0..8 {
+            let (a, b) = (pd.get_coord(i), pd_lookup.get_coord(i));
+            assert!((a - b).abs() < 1e-3 * a, "sampled pd {} but looked up {}", a, b);
+        }
+    }
+}
+
+#[test]
+fn environment_pd_integrates_to_one() {
+    let env = EnvironmentMap::new(gradient_image(16, 8));
+
+    // Integrate the density over the sphere with the midpoint rule in the
+    // image coordinates.
+    let n = 256;
+    let mut integral = 0.0;
+    for j in 0..n {
+        for i in 0..n {
+            let u = (i as f32 + 0.5) / n as f32;
+            let v = (j as f32 + 0.5) / n as f32;
+            let (direction, sin_theta) = uv_to_direction(u, v);
+            let pd = env.pd(MVector3::broadcast(direction)).0;
+            integral += pd * sin_theta * 2.0 * PI * PI / (n * n) as f32;
+        }
+    }
+
+    assert!((integral - 1.0).abs() < 0.01, "density integrates to {}", integral);
+}
@@ -0,0 +1,325 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module reads OpenEXR (.exr) files.
+//!
+//! The format is flexible: it can store any number of channels with 16-bit or
+//! 32-bit floats, in scanlines or tiles, with many compression methods. Only
+//! single-part scanline files without compression or with zip compression are
+//! supported, with half or float channels. That is what most tools write by
+//! default. Of the channels, only R, G, B, A, and Y are read.
+
+use filebuffer::FileBuffer;
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+use std::mem::transmute;
+use std::path::Path;
+use std::str::from_utf8;
+
+pub struct ExrImage {
+    pub width: u32,
+    pub height: u32,
+
+    /// The number of channels: 1 for luminance, 2 for luminance and alpha, 3
+    /// for RGB, and 4 for RGBA.
+    pub channels: u32,
+
+    /// Linear values, interleaved per pixel, row by row, starting at the top
+    /// left.
+    pub data: Vec<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PixelType {
+    Half,
+    Float,
+}
+
+struct Channel {
+    name: String,
+    pixel_type: PixelType,
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    (input[pos] as u32) | ((input[pos + 1] as u32) << 8) | ((input[pos + 2] as u32) << 16) |
+    ((input[pos + 3] as u32) << 24)
+}
+
+fn read_i32(input: &[u8], pos: usize) -> i32 {
+    read_u32(input, pos) as i32
+}
+
+/// Reads a null-terminated string, returns it and the position after the null.
+fn read_str(input: &[u8], pos: usize) -> (&str, usize) {
+    let len = input[pos..].iter().position(|&b| b == 0).expect("exr string is not terminated");
+    let s = from_utf8(&input[pos..pos + len]).expect("exr string must be valid utf-8");
+    (s, pos + len + 1)
+}
+
+/// Converts a 16-bit float to a 32-bit float.
+fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+
+    let bits = if exponent == 0 {
+        if mantissa == 0 {
+            sign
+        } else {
+            // A denormal half is a normal float, the exponent is -14.
+            let value = mantissa as f32 * (1.0 / 16777216.0);
+            return if sign == 0 { value } else { -value };
+        }
+    } else if exponent == 31 {
+        // Infinity or NaN.
+        sign | 0x7f800000 | (mantissa << 13)
+    } else {
+        sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)
+    };
+
+    unsafe { transmute(bits) }
+}
+
+/// Undoes the predictor and the byte reordering that zip compression applies
+/// before deflating.
+fn unpredict_and_deinterleave(data: &[u8]) -> Vec<u8> {
+    let mut t = data.to_vec();
+    for i in 1..t.len() {
+        t[i] = t[i - 1].wrapping_add(t[i]).wrapping_sub(128);
+    }
+
+    // The first half of the buffer contains the even bytes, the second half
+    // the odd bytes.
+    let half = (t.len() + 1) / 2;
+    let mut out = Vec::with_capacity(t.len());
+    for i in 0..t.len() {
+        out.push(if i % 2 == 0 { t[i / 2] } else { t[half + i / 2] });
+    }
+    out
+}
+
+impl ExrImage {
+    /// Decodes an image from the contents of an .exr file.
+    pub fn decode(input: &[u8]) -> ExrImage {
+        assert!(input.len() >= 8 && read_u32(input, 0) == 20000630, "not an openexr file");
+        let version = read_u32(input, 4);
+        assert_eq!(2, version & 0xff, "unsupported openexr version");
+        assert!(version & 0x1a00 == 0, "only single-part scanline exr files are supported");
+
+        let mut channels = Vec::new();
+        let mut compression = None;
+        let mut data_window = None;
+
+        // The header is a list of attributes, terminated by an empty name.
+        let mut pos = 8;
+        loop {
+            let (name, next) = read_str(input, pos);
+            if name.is_empty() {
+                pos = next;
+                break;
+            }
+            let (_type_name, next) = read_str(input, next);
+            let size = read_i32(input, next) as usize;
+            let value = &input[next + 4..next + 4 + size];
+            pos = next + 4 + size;
+
+            match name {
+                "channels" => {
+                    let mut p = 0;
+                    loop {
+                        let (channel_name, next) = read_str(value, p);
+                        if channel_name.is_empty() {
+                            break;
+                        }
+                        let pixel_type = match read_i32(value, next) {
+                            1 => PixelType::Half,
+                            2 => PixelType::Float,
+                            _ => panic!("unsupported exr pixel type for channel '{}'", channel_name),
+                        };
+                        assert!(read_i32(value, next + 8) == 1 && read_i32(value, next + 12) == 1,
+                                "subsampled exr channels are not supported");
+                        channels.push(Channel {
+                            name: channel_name.to_string(),
+                            pixel_type: pixel_type,
+                        });
+                        p = next + 16;
+                    }
+                }
+                "compression" => compression = Some(value[0]),
+                "dataWindow" => {
+                    data_window = Some((read_i32(value, 0),
+                                        read_i32(value, 4),
+                                        read_i32(value, 8),
+                                        read_i32(value, 12)));
+                }
+                _ => {}
+            }
+        }
+
+        let (x_min, y_min, x_max, y_max) = data_window.expect("exr file has no data window");
+        let width = (x_max - x_min + 1) as usize;
+        let height = (y_max - y_min + 1) as usize;
+
+        // Without compression and with "zips" compression a block holds one
+        // scanline, with "zip" compression it holds 16.
+        let compression = compression.expect("exr file has no compression attribute");
+        let lines_per_block = match compression {
+            0 | 2 => 1,
+            3 => 16,
+            _ => panic!("unsupported exr compression method {}", compression),
+        };
+
+        // Map the channels that are stored to the channels in the output.
+        let has = |n: &str| channels.iter().any(|c| c.name == n);
+        let out_names: Vec<&str> = if has("R") && has("G") && has("B") {
+            if has("A") { vec!["R", "G", "B", "A"] } else { vec!["R", "G", "B"] }
+        } else if has("Y") {
+            if has("A") { vec!["Y", "A"] } else { vec!["Y"] }
+        } else {
+            panic!("exr file must have RGB or Y channels");
+        };
+        let num_out = out_names.len();
+
+        let mut data = vec![0.0f32; width * height * num_out];
+        let num_blocks = (height + lines_per_block - 1) / lines_per_block;
+
+        // After the header is a table with the offset of every block.
+        for block in 0..num_blocks {
+            let offset_pos = pos + block * 8;
+            let offset = read_u32(input, offset_pos) as usize;
+            let y_block = (read_i32(input, offset) - y_min) as usize;
+            let size = read_i32(input, offset + 4) as usize;
+            let packed = &input[offset + 8..offset + 8 + size];
+
+            let lines = lines_per_block.min(height - y_block);
+            let bytes_per_line: usize = channels.iter()
+                .map(|c| width * if c.pixel_type == PixelType::Half { 2 } else { 4 })
+                .sum();
+            let expected_size = bytes_per_line * lines;
+
+            // If compression does not make the data smaller, it is stored
+            // uncompressed.
+            let unpacked;
+            let block_data = if compression == 0 || size == expected_size {
+                packed
+            } else {
+                let mut inflated = Vec::with_capacity(expected_size);
+                ZlibDecoder::new(packed).read_to_end(&mut inflated).expect("invalid exr zip data");
+                unpacked = unpredict_and_deinterleave(&inflated);
+                &unpacked[..]
+            };
+            assert_eq!(expected_size, block_data.len(), "exr block has the wrong size");
+
+            // Within a scanline, the channels are stored one after another.
+            for line in 0..lines {
+                let mut p = line * bytes_per_line;
+                let y = y_block + line;
+                for channel in &channels {
+                    let out_index = out_names.iter().position(|&n| n == channel.name);
+                    for x in 0..width {
+                        let value = match channel.pixel_type {
+                            PixelType::Half => {
+                                let h = (block_data[p] as u16) | ((block_data[p + 1] as u16) << 8);
+                                p += 2;
+                                half_to_f32(h)
+                            }
+                            PixelType::Float => {
+                                let v: f32 = unsafe { transmute(read_u32(block_data, p)) };
+                                p += 4;
+                                v
+                            }
+                        };
+                        if let Some(c) = out_index {
+                            data[(y * width + x) * num_out + c] = value;
+                        }
+                    }
+                }
+            }
+        }
+
+        ExrImage {
+            width: width as u32,
+            height: height as u32,
+            channels: num_out as u32,
+            data: data,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> ExrImage {
+        let fbuffer = FileBuffer::open(path).expect("failed to open file");
+        ExrImage::decode(&fbuffer[..])
+    }
+}
+
+#[cfg(test)]
+fn push_attribute(out: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(type_name.as_bytes());
+    out.push(0);
+    let size = value.len() as u32;
+    out.extend_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+    out.extend_from_slice(value);
+}
+
+#[test]
+fn half_to_f32_converts_common_values() {
+    assert_eq!(0.0, half_to_f32(0x0000));
+    assert_eq!(1.0, half_to_f32(0x3c00));
+    assert_eq!(-2.0, half_to_f32(0xc000));
+    assert_eq!(0.5, half_to_f32(0x3800));
+    assert_eq!(65504.0, half_to_f32(0x7bff));
+    assert_eq!(1.0 / 16777216.0, half_to_f32(0x0001));
+}
+
+#[test]
+fn decode_uncompressed_exr() {
+    let mut input = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    // Three half channels, in alphabetical order.
+    let mut chlist = Vec::new();
+    for name in &["B", "G", "R"] {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+    }
+    chlist.push(0);
+    push_attribute(&mut input, "channels", "chlist", &chlist);
+    push_attribute(&mut input, "compression", "compression", &[0]);
+    push_attribute(&mut input, "dataWindow", "box2i", &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    input.push(0);
+
+    // One block for the single scanline of 2 pixels.
+    let offset = input.len() + 8;
+    input.extend_from_slice(&[offset as u8, 0, 0, 0, 0, 0, 0, 0]);
+    input.extend_from_slice(&[0, 0, 0, 0, 12, 0, 0, 0]);
+    input.extend_from_slice(&[0x00, 0x00, 0x00, 0x3c]); // B: 0, 1
+    input.extend_from_slice(&[0x00, 0x38, 0x00, 0x00]); // G: 0.5, 0
+    input.extend_from_slice(&[0x00, 0x3c, 0x00, 0x40]); // R: 1, 2
+
+    let image = ExrImage::decode(&input);
+    assert_eq!(2, image.width);
+    assert_eq!(1, image.height);
+    assert_eq!(3, image.channels);
+    assert_eq!(&[1.0, 0.5, 0.0, 2.0, 0.0, 1.0], &image.data[..]);
+}
+
+#[test]
+fn unpredict_and_deinterleave_restores_bytes() {
+    // Encode like the zip compressor does: interleave, then take differences.
+    let original = [10u8, 200, 30, 40, 250];
+    let half = (original.len() + 1) / 2;
+    let mut t = vec![0; original.len()];
+    for i in 0..original.len() {
+        if i % 2 == 0 { t[i / 2] = original[i] } else { t[half + i / 2] = original[i] }
+    }
+    let mut encoded = t.clone();
+    for i in 1..t.len() {
+        encoded[i] = t[i].wrapping_sub(t[i - 1]).wrapping_add(128);
+    }
+
+    assert_eq!(&original[..], &unpredict_and_deinterleave(&encoded)[..]);
+}
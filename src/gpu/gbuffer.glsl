@@ -12,31 +12,37 @@ out vec4 color;
 
 uniform sampler2D frame;
 uniform sampler2D gbuffer;
-uniform sampler2D texture1;
-uniform sampler2D texture2;
+uniform sampler2D surface_texture;
+
+// The texture index of the texture bound to surface_texture, plus one.
+uniform float texture_index;
 
 void main() {
-    color = texture(frame, v_tex_coords);
-    vec4 data = texture(gbuffer, v_tex_coords);
+    // The gbuffer consists of two layers stacked vertically, see
+    // `Renderer::store_pixels_gbuffer_16x4()`.
+    vec4 data = texture(gbuffer, vec2(v_tex_coords.x, v_tex_coords.y * 0.5f));
+    vec4 detail = texture(gbuffer, vec2(v_tex_coords.x, 0.5f + v_tex_coords.y * 0.5f));
+
+    // The alpha channel contains the texture index. This pass only handles
+    // the pixels with the bound texture, other pixels keep their color.
+    if (abs(data.a * 255.0f - texture_index) > 0.5f) {
+        discard;
+    }
 
     float fresnel = data.b;
     vec4 white = vec4(1.0f, 1.0f, 1.0f, 1.0f);
 
-    // The alpha channel contains the texture index. Texture index 0 indicates
-    // that the texture is not used, so the pixel is already correct. For the
-    // other textures, sample them and blend according to the Fresnel factor.
+    // Reassemble the 16-bit texture coordinates from the high and low bytes.
+    vec2 uv = (data.xy * 65280.0f + detail.xy * 255.0f) / 65536.0f;
 
-    if (data.a == 1.0f / 255.0f) {
-        vec4 tex_color = texture(texture1, data.xy);
-        vec4 surface_color = white * fresnel + tex_color * (1.0f - fresnel);
-        color = color * surface_color;
-    }
-
-    if (data.a == 2.0f / 255.0f) {
-        vec4 tex_color = texture(texture2, data.xy);
-        vec4 surface_color = white * fresnel + tex_color * (1.0f - fresnel);
-        color = color * surface_color;
-    }
+    // The footprint is stored as a base 2 logarithm with 3 fractional bits.
+    // At level n, a texel covers 2^n texels of the full resolution texture.
+    float log2_footprint = detail.z * 255.0f / 8.0f - 24.0f;
+    ivec2 size = textureSize(surface_texture, 0);
+    float lod = max(0.0f, log2_footprint + log2(float(max(size.x, size.y))));
 
-    // Texture index 3 is currently not used.
+    // Sample the texture and blend according to the Fresnel factor.
+    vec4 tex_color = textureLod(surface_texture, uv, lod);
+    vec4 surface_color = white * fresnel + tex_color * (1.0f - fresnel);
+    color = texture(frame, v_tex_coords) * surface_color;
 }
@@ -0,0 +1,185 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module reads Radiance HDR (.hdr, also known as .pic) files.
+//!
+//! The format stores every pixel as four bytes: a mantissa for red, green, and
+//! blue, and a shared exponent. Scanlines are usually run-length encoded per
+//! channel. Only the common orientation (`-Y height +X width`) and the "new"
+//! run-length encoding are supported, which covers files written by every
+//! tool I know of.
+
+use filebuffer::FileBuffer;
+use std::path::Path;
+use std::str::{FromStr, from_utf8};
+use vector3::SVector3;
+
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+
+    /// Linear RGB values, row by row, starting at the top left.
+    pub pixels: Vec<SVector3>,
+}
+
+/// Converts a pixel in RGBE format to linear RGB.
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> SVector3 {
+    if rgbe[3] == 0 {
+        return SVector3::zero();
+    }
+
+    // The exponent is biased by 128, and the mantissas are 8-bit fixed-point
+    // numbers, so shift by another 8.
+    let f = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    SVector3::new(rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f)
+}
+
+/// Reads a scanline that is not run-length encoded.
+fn read_flat_scanline(input: &[u8], width: usize, scanline: &mut Vec<[u8; 4]>) -> usize {
+    assert!(input.len() >= width * 4, "hdr image data is truncated");
+    for px in input[..width * 4].chunks(4) {
+        scanline.push([px[0], px[1], px[2], px[3]]);
+    }
+    width * 4
+}
+
+/// Reads a run-length encoded scanline, returns the number of bytes consumed.
+fn read_rle_scanline(input: &[u8], width: usize, scanline: &mut Vec<[u8; 4]>) -> usize {
+    let encoded_width = ((input[2] as usize) << 8) | input[3] as usize;
+    assert_eq!(width, encoded_width, "hdr scanline width does not match image width");
+
+    let start = scanline.len();
+    scanline.resize(start + width, [0; 4]);
+    let mut pos = 4;
+
+    // The channels are stored one after another. Within a channel, a count
+    // byte greater than 128 indicates a run of the next byte, otherwise the
+    // count is the number of literal bytes that follow.
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = input[pos] as usize;
+            pos += 1;
+            if count > 128 {
+                let count = count - 128;
+                assert!(x + count <= width, "hdr run exceeds scanline");
+                let value = input[pos];
+                pos += 1;
+                for i in 0..count {
+                    scanline[start + x + i][channel] = value;
+                }
+                x += count;
+            } else {
+                assert!(count > 0 && x + count <= width, "invalid hdr run length");
+                for i in 0..count {
+                    scanline[start + x + i][channel] = input[pos + i];
+                }
+                pos += count;
+                x += count;
+            }
+        }
+    }
+
+    pos
+}
+
+impl HdrImage {
+    /// Decodes an image from the contents of a .hdr file.
+    pub fn decode(input: &[u8]) -> HdrImage {
+        assert!(input.starts_with(b"#?"), "not a radiance hdr file");
+
+        // The header consists of lines of text, terminated by an empty line.
+        // After that comes a line with the resolution.
+        let mut pos = 0;
+        let mut lines = Vec::new();
+        loop {
+            let end = pos + input[pos..].iter()
+                .position(|&b| b == b'\n')
+                .expect("hdr header is not terminated");
+            let line = from_utf8(&input[pos..end]).expect("hdr header must be valid utf-8");
+            pos = end + 1;
+            if !lines.is_empty() && line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+
+        for line in &lines {
+            if line.starts_with("FORMAT=") {
+                assert_eq!("FORMAT=32-bit_rle_rgbe", *line, "unsupported hdr pixel format");
+            }
+        }
+
+        let end = pos + input[pos..].iter()
+            .position(|&b| b == b'\n')
+            .expect("missing hdr resolution");
+        let resolution = from_utf8(&input[pos..end]).expect("hdr resolution must be valid utf-8");
+        pos = end + 1;
+
+        let parts: Vec<&str> = resolution.split_whitespace().collect();
+        assert!(parts.len() == 4 && parts[0] == "-Y" && parts[2] == "+X",
+                "unsupported hdr orientation '{}'", resolution);
+        let height = u32::from_str(parts[1]).expect("invalid hdr height");
+        let width = u32::from_str(parts[3]).expect("invalid hdr width");
+
+        let mut rgbe = Vec::with_capacity((width * height) as usize);
+        for _ in 0..height {
+            let data = &input[pos..];
+
+            // Run-length encoded scanlines start with two bytes 2, and they
+            // can only encode widths between 8 and 32767.
+            let is_rle = width >= 8 && width < 32768 && data.len() >= 4 &&
+                         data[0] == 2 && data[1] == 2 && data[2] & 0x80 == 0;
+            pos += if is_rle {
+                read_rle_scanline(data, width as usize, &mut rgbe)
+            } else {
+                read_flat_scanline(data, width as usize, &mut rgbe)
+            };
+        }
+
+        HdrImage {
+            width: width,
+            height: height,
+            pixels: rgbe.into_iter().map(rgbe_to_rgb).collect(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> HdrImage {
+        let fbuffer = FileBuffer::open(path).expect("failed to open file");
+        HdrImage::decode(&fbuffer[..])
+    }
+}
+
+#[test]
+fn decode_flat_hdr() {
+    let mut input = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+    input.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+    let image = HdrImage::decode(&input);
+    assert_eq!(2, image.width);
+    assert_eq!(1, image.height);
+    assert_eq!(SVector3::new(1.0, 0.5, 0.0), image.pixels[0]);
+    assert_eq!(SVector3::zero(), image.pixels[1]);
+}
+
+#[test]
+fn decode_rle_hdr() {
+    let mut input = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+    input.extend_from_slice(&[2, 2, 0, 8]);
+    // Red: a run of 8 times 128.
+    input.extend_from_slice(&[128 + 8, 128]);
+    // Green: 8 literal values.
+    input.extend_from_slice(&[8, 0, 0, 0, 0, 64, 64, 64, 64]);
+    // Blue: two runs of zero.
+    input.extend_from_slice(&[128 + 4, 0, 128 + 4, 0]);
+    // Exponent: a run of 129.
+    input.extend_from_slice(&[128 + 8, 129]);
+
+    let image = HdrImage::decode(&input);
+    assert_eq!(8, image.pixels.len());
+    assert_eq!(SVector3::new(1.0, 0.0, 0.0), image.pixels[0]);
+    assert_eq!(SVector3::new(1.0, 0.5, 0.0), image.pixels[7]);
+}
@@ -0,0 +1,136 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module defines the interface between the renderer and the algorithms
+//! that compute light transport.
+//!
+//! The renderer takes care of everything around it: it generates camera rays
+//! for blocks of pixels, accumulates samples, and stores the result in the
+//! buffers for the GPU. An integrator only has to estimate the radiance that
+//! arrives along 8 camera rays. The path tracer is one integrator, the debug
+//! view that shows the cost of traversing the BVH is another one.
+
+use material::MisHeuristic;
+use random::Rng;
+use ray::MRay;
+use scene::Scene;
+use simd::{Mf32, Mi32};
+use spectral::SpectralTables;
+use vector3::MVector3;
+
+/// The result of tracing 8 camera rays.
+pub struct MPixelData {
+    pub color: MVector3,
+
+    /// The index of the texture at the first intersection, plus one, for
+    /// textures that are applied on the GPU. Zero means no texture.
+    pub tex_index: Mi32,
+    pub tex_coords: (Mf32, Mf32),
+
+    /// The width of the ray cone in texture coordinate units at the first
+    /// intersection, for selecting a mip level.
+    pub tex_footprint: Mf32,
+    pub fresnel: Mf32,
+}
+
+/// Settings of the renderer that integrators can take into account. They can
+/// be changed interactively.
+pub struct Settings {
+    /// The width of the image in pixels.
+    pub width: u32,
+
+    /// How to combine direct light sampling and BRDF sampling.
+    pub mis_heuristic: MisHeuristic,
+
+    /// The maximum number of bounces of a path, also when it survives Russian
+    /// roulette.
+    pub max_bounces: u32,
+
+    /// The number of bounces after which paths are subject to Russian
+    /// roulette.
+    pub roulette_depth: u32,
+
+    /// Whether to clamp the color modulation per bounce. This hides fireflies
+    /// in realtime mode, but it is biased.
+    pub clamp_fireflies: bool,
+
+    /// Whether textures at the first bounce are applied on the GPU. If not,
+    /// they are sampled on the CPU like for the other bounces, and the gbuffer
+    /// is not needed.
+    pub gpu_textures: bool,
+
+    /// The tables to convert between RGB and spectra in spectral mode, or
+    /// `None` in RGB mode.
+    pub spectral: Option<SpectralTables>,
+}
+
+/// An algorithm that computes the light that arrives at the camera.
+///
+/// Integrators are shared by the threads that render patches, so they cannot
+/// mutate their state while rendering. Everything that is per path lives on
+/// the stack. State that is per frame is built in `prepare()`.
+pub trait Integrator: Sync {
+    /// Returns a name to show when the integrator is selected.
+    fn name(&self) -> &'static str;
+
+    /// Does the work that is shared by all pixels of a frame, before the
+    /// patches are rendered. The pass is the number of frames that have been
+    /// accumulated before this one, it is 0 in realtime mode. Most integrators
+    /// do not need this.
+    fn prepare(&mut self, _scene: &Scene, _settings: &Settings, _pass: u32, _rng: &mut Rng) {}
+
+    /// Estimates the radiance that arrives along the camera rays. Integrators
+    /// that do not support applying textures on the GPU should return a zero
+    /// texture index, and a Fresnel factor of zero.
+    fn integrate(&self, scene: &Scene, ray: MRay, settings: &Settings, rng: &mut Rng) -> MPixelData;
+}
+
+/// Visualizes the number of AABB and triangle intersections per packet.
+pub struct DebugView;
+
+impl MPixelData {
+    /// Returns pixel data with only a color, for integrators that apply all
+    /// textures themselves.
+    pub fn with_color(color: MVector3) -> MPixelData {
+        MPixelData {
+            color: color,
+            tex_index: Mi32::zero(),
+            tex_coords: (Mf32::zero(), Mf32::zero()),
+            tex_footprint: Mf32::zero(),
+            fresnel: Mf32::zero(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn new(width: u32) -> Settings {
+        Settings {
+            width: width,
+            mis_heuristic: MisHeuristic::Power,
+            max_bounces: 16,
+            roulette_depth: 3,
+            clamp_fireflies: true,
+            gpu_textures: true,
+            spectral: None,
+        }
+    }
+}
+
+impl Integrator for DebugView {
+    fn name(&self) -> &'static str {
+        "debug view"
+    }
+
+    fn integrate(&self, scene: &Scene, ray: MRay, _settings: &Settings, _rng: &mut Rng) -> MPixelData {
+        let (numi_aabb, numi_tri) = scene.intersect_debug(&ray);
+
+        let g = Mf32::broadcast((numi_aabb as f32).log2() * 0.1);
+        let b = Mf32::broadcast((numi_tri as f32).log2() * 0.1);
+
+        MPixelData::with_color(MVector3::new(Mf32::zero(), g, b))
+    }
+}
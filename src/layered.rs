@@ -0,0 +1,451 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements layered materials.
+//!
+//! A 32-bit material has no room for more than one lobe, so materials that
+//! need more are described in a table in the scene, and the material only
+//! stores the index into the table. A layered material has a base, which is
+//! either an ordinary material or a translucent interior (see the
+//! `subsurface` module), and optionally a clear coat on top of it, like the
+//! lacquer on wood or the clear coat of car paint. The coat can have a thin
+//! film on it, which makes it iridescent. On top of everything there can be a
+//! sheen, the soft highlight at grazing angles of fabric like velvet.
+//!
+//! The layers are not evaluated together. At every intersection, a path picks
+//! one layer, and from then on the surface behaves as that layer for the path:
+//! the sheen or the coat with a probability equal to how much light they
+//! reflect in the incoming direction, and the base otherwise. Then the
+//! probability of picking a layer cancels the amount of light that the layer
+//! receives, so only light that reaches the base must be weighted, by the
+//! fraction that makes it out of the coat again. All of the code that samples
+//! and evaluates materials can stay as it is.
+//!
+//! A thin film reflects some wavelengths more than others, because light that
+//! reflects off the top and bottom of the film interferes. In spectral mode
+//! the reflectance is evaluated at the wavelengths of the path. In RGB mode it
+//! is evaluated at one wavelength per primary, which exaggerates the colors a
+//! bit, but it gives the right impression.
+
+use material::{MMaterial, SMaterial, microfacet_fresnel};
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::Scene;
+use simd::{Mask, Mf32};
+use spectral::MChannels;
+use std::f32::consts::PI;
+use subsurface::Subsurface;
+use vector3::{MVector3, SVector3};
+
+/// The wavelengths in nanometers at which thin films are evaluated in RGB
+/// mode, roughly those of the red, green, and blue primaries.
+const RGB_WAVELENGTHS: [f32; 3] = [630.0, 532.0, 465.0];
+
+/// What lies below the coat of a layered material.
+#[derive(Copy, Clone, Debug)]
+pub enum Base {
+    /// An ordinary material, which may be textured.
+    Opaque(SMaterial),
+
+    /// A translucent interior in which light scatters.
+    Translucent(Subsurface),
+}
+
+/// A clear dielectric coat.
+#[derive(Copy, Clone, Debug)]
+pub struct Coat {
+    /// The glossiness of the coat, like `SMaterial::with_glossiness()`.
+    pub glossiness: u32,
+
+    /// The index of refraction of the coat.
+    pub ior: f32,
+
+    /// A film on top of the coat.
+    pub film: Option<ThinFilm>,
+}
+
+/// A dielectric film with a thickness in the order of the wavelength of light.
+#[derive(Copy, Clone, Debug)]
+pub struct ThinFilm {
+    /// The thickness of the film in nanometers.
+    pub thickness: f32,
+
+    /// The index of refraction of the film.
+    pub ior: f32,
+}
+
+/// The retroreflective highlight of fibers at grazing angles.
+#[derive(Copy, Clone, Debug)]
+pub struct Sheen {
+    pub color: SVector3,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Layered {
+    pub base: Base,
+    pub coat: Option<Coat>,
+    pub sheen: Option<Sheen>,
+}
+
+/// The layers that 8 paths picked at an intersection.
+pub struct MLayerSample {
+    /// The material that the surface behaves as. For translucent lanes this is
+    /// still the layered material.
+    pub material: MMaterial,
+
+    /// The factor to multiply the path throughput by.
+    pub weight: MVector3,
+
+    /// The sign bit is 1 for lanes that picked a translucent base.
+    pub translucent: Mask,
+}
+
+impl Coat {
+    /// Constructs a coat with the given glossiness between 0 and 6 and index
+    /// of refraction. An index of refraction of 1 makes the coat invisible,
+    /// but a film on it still reflects.
+    pub fn new(glossiness: u32, ior: f32) -> Coat {
+        assert!(glossiness <= 6, "glossiness must be between 0 and 6");
+        assert!(ior >= 1.0, "index of refraction must be at least 1");
+        Coat {
+            glossiness: glossiness,
+            ior: ior,
+            film: None,
+        }
+    }
+
+    pub fn with_film(self, film: ThinFilm) -> Coat {
+        Coat { film: Some(film), ..self }
+    }
+
+    /// A glass lens with an anti-reflective coating, which makes it reflect
+    /// a faint purple.
+    pub fn coated_lens() -> Coat {
+        Coat::new(6, 1.5).with_film(ThinFilm::anti_reflective())
+    }
+
+    /// A glossy lacquer with the index of refraction of most varnishes.
+    pub fn lacquer() -> Coat {
+        Coat::new(5, 1.5)
+    }
+
+    /// Returns the reflectance at normal incidence.
+    pub fn r0(&self) -> f32 {
+        let r = (self.ior - 1.0) / (self.ior + 1.0);
+        r * r
+    }
+
+    /// Returns the cosine-weighted average over the hemisphere of Schlick's
+    /// approximation of the Fresnel factor. This is the fraction of diffuse
+    /// light from below that the coat reflects back.
+    pub fn average_reflectance(&self) -> f32 {
+        let r0 = self.r0();
+        r0 + (1.0 - r0) / 21.0
+    }
+
+    /// Returns the material of the coat on its own: a white lobe. The Fresnel
+    /// factor is accounted for by the probability of picking the coat.
+    pub fn material(&self) -> SMaterial {
+        SMaterial::white().with_glossiness(self.glossiness)
+    }
+}
+
+/// Returns the Fresnel amplitude coefficients for s and p polarized light at
+/// an interface between media with indices of refraction n1 and n2, given the
+/// cosines of the angles on both sides.
+fn fresnel_amplitudes(n1: f32, cos1: f32, n2: f32, cos2: f32) -> (f32, f32) {
+    let s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+    let p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+    (s, p)
+}
+
+impl ThinFilm {
+    /// Constructs a film with the given thickness in nanometers and index of
+    /// refraction.
+    pub fn new(thickness: f32, ior: f32) -> ThinFilm {
+        assert!(thickness >= 0.0, "film thickness must not be negative");
+        assert!(ior >= 1.0, "index of refraction must be at least 1");
+        ThinFilm {
+            thickness: thickness,
+            ior: ior,
+        }
+    }
+
+    /// A layer of magnesium fluoride of a quarter wavelength of green light,
+    /// the classic anti-reflective coating for glass.
+    pub fn anti_reflective() -> ThinFilm {
+        ThinFilm::new(550.0 / (4.0 * 1.38), 1.38)
+    }
+
+    /// Soapy water. A few hundred nanometers gives the brightest colors.
+    pub fn soap(thickness: f32) -> ThinFilm {
+        ThinFilm::new(thickness, 1.33)
+    }
+
+    /// Returns the reflectance of the film on a substrate with the given index
+    /// of refraction, for light of the wavelength in nanometers that arrives
+    /// from air at an angle with the given cosine. This is the Airy formula,
+    /// averaged over both polarizations.
+    pub fn reflectance(&self, lambda: f32, cos_theta: f32, substrate_ior: f32) -> f32 {
+        // Snell's law gives the angles inside the film and substrate. Coming
+        // from air there is no total internal reflection.
+        let sin2 = 1.0 - cos_theta * cos_theta;
+        let cos_film = (1.0 - sin2 / (self.ior * self.ior)).sqrt();
+        let cos_substrate = (1.0 - sin2 / (substrate_ior * substrate_ior)).sqrt();
+        let (r12s, r12p) = fresnel_amplitudes(1.0, cos_theta, self.ior, cos_film);
+        let (r23s, r23p) = fresnel_amplitudes(self.ior, cos_film, substrate_ior, cos_substrate);
+
+        // The phase difference between light reflected off the top and bottom
+        // of the film.
+        let phase = 4.0 * PI * self.ior * self.thickness * cos_film / lambda;
+        let airy = |a: f32, b: f32| {
+            let interference = 2.0 * a * b * phase.cos();
+            (a * a + b * b + interference) / (1.0 + a * a * b * b + interference)
+        };
+        0.5 * (airy(r12s, r23s) + airy(r12p, r23p))
+    }
+}
+
+impl Sheen {
+    /// Constructs a sheen with the given color, which is the reflectance at
+    /// exactly grazing angles.
+    pub fn new(color: SVector3) -> Sheen {
+        assert!(color.x >= 0.0 && color.y >= 0.0 && color.z >= 0.0);
+        assert!(color.x <= 1.0 && color.y <= 1.0 && color.z <= 1.0);
+        Sheen { color: color }
+    }
+
+    /// Returns the material of the sheen on its own. Fibers scatter light in
+    /// all directions, so this is a diffuse lobe rather than a sheen lobe in
+    /// the microfacet sense, but the sheen is only visible at grazing angles
+    /// anyway, where the shape of the lobe matters little.
+    pub fn material(&self) -> SMaterial {
+        SMaterial::white()
+    }
+}
+
+impl Layered {
+    /// Constructs a layered material with an ordinary base, without a coat.
+    pub fn opaque(base: SMaterial) -> Layered {
+        assert!(base.layered_index().is_none(), "layered materials cannot be nested");
+        Layered {
+            base: Base::Opaque(base),
+            coat: None,
+            sheen: None,
+        }
+    }
+
+    /// Constructs a translucent material without a coat.
+    pub fn translucent(subsurface: Subsurface) -> Layered {
+        Layered {
+            base: Base::Translucent(subsurface),
+            coat: None,
+            sheen: None,
+        }
+    }
+
+    pub fn with_coat(self, coat: Coat) -> Layered {
+        Layered { coat: Some(coat), ..self }
+    }
+
+    pub fn with_sheen(self, sheen: Sheen) -> Layered {
+        Layered { sheen: Some(sheen), ..self }
+    }
+
+    /// Red car paint: a glossy metallic base under a clear coat.
+    pub fn car_paint() -> Layered {
+        let base = SMaterial::diffuse(0.6, 0.03, 0.02).with_glossiness(3);
+        Layered::opaque(base).with_coat(Coat::lacquer())
+    }
+
+    /// Velvet of the given color, with a lighter sheen of the same hue.
+    pub fn velvet(r: f32, g: f32, b: f32) -> Layered {
+        let sheen = SVector3::new(r, g, b) * 0.5 + SVector3::new(0.5, 0.5, 0.5);
+        Layered::opaque(SMaterial::diffuse(r, g, b)).with_sheen(Sheen::new(sheen))
+    }
+
+    /// A thin soap film over a black base, for the colors without the bubble.
+    pub fn soap_film(thickness: f32) -> Layered {
+        let coat = Coat::new(6, 1.0).with_film(ThinFilm::soap(thickness));
+        Layered::opaque(SMaterial::diffuse(0.0, 0.0, 0.0)).with_coat(coat)
+    }
+
+    /// Returns the material of the base if it is opaque.
+    pub fn opaque_base(&self) -> Option<SMaterial> {
+        match self.base {
+            Base::Opaque(material) => Some(material),
+            Base::Translucent(..) => None,
+        }
+    }
+
+    /// Returns the subsurface parameters if the base is translucent.
+    pub fn subsurface(&self) -> Option<Subsurface> {
+        match self.base {
+            Base::Translucent(subsurface) => Some(subsurface),
+            Base::Opaque(..) => None,
+        }
+    }
+}
+
+/// Picks a layer of the layered materials at the intersections, for lanes
+/// where the sign bit of `active` is 0. Other lanes keep their material.
+pub fn pick_layers(scene: &Scene,
+                   ray: &MRay,
+                   isect: &MIntersection,
+                   active: Mask,
+                   rng: &mut Rng,
+                   channels: MChannels)
+                   -> MLayerSample {
+    let one = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+    let layered = isect.material.is_layered().pick(Mask::zero(), active);
+    if layered.all_sign_bits_positive() {
+        return MLayerSample {
+            material: isect.material,
+            weight: one,
+            translucent: Mask::zero(),
+        };
+    }
+
+    let index = isect.material.get_layered();
+    let is_layered = |i: usize| layered.get_coord(i).is_sign_negative();
+    let layers = |i: usize| scene.layered(index.get_coord(i) as u32);
+    let coat_of = |i: usize| if is_layered(i) { layers(i).coat } else { None };
+    let cos_theta = ray.direction.dot(isect.normal).abs();
+
+    // The Fresnel factor of the coat at the incoming direction is how much
+    // light the coat reflects. With a film on top it depends on the
+    // wavelength.
+    let r0 = Mf32::generate(|i| coat_of(i).map_or(0.0, |c| c.r0()));
+    let (fresnel, _) = microfacet_fresnel(ray.direction, isect.normal, MVector3::new(r0, r0, r0));
+    let wavelengths = channels.wavelengths().unwrap_or_else(|| {
+        MVector3::broadcast(SVector3::new(RGB_WAVELENGTHS[0], RGB_WAVELENGTHS[1], RGB_WAVELENGTHS[2]))
+    });
+    let reflectance = MVector3::generate(|i| {
+        match coat_of(i) {
+            Some(Coat { film: Some(film), ior, .. }) => {
+                let lambda = wavelengths.extract(i);
+                let c = cos_theta.get_coord(i);
+                SVector3::new(film.reflectance(lambda.x, c, ior),
+                              film.reflectance(lambda.y, c, ior),
+                              film.reflectance(lambda.z, c, ior))
+            }
+            Some(..) => fresnel.extract(i),
+            None => SVector3::zero(),
+        }
+    });
+    let u = rng.sample_unit();
+
+    // The weight of a lane has an RGB part that must be converted to the
+    // channels of the path, and a part that is already per channel.
+    let mut materials = [SMaterial::white(); 8];
+    let mut replaced = [false; 8];
+    let mut translucent = [false; 8];
+    let mut tints = [SVector3::one(); 8];
+    let mut factors = [SVector3::one(); 8];
+    for i in 0..8 {
+        if !is_layered(i) {
+            continue;
+        }
+        let layers = layers(i);
+        let mut u = u.get_coord(i);
+
+        // The sheen reflects up to its color at grazing angles. Pick it with
+        // the probability of its strongest channel.
+        if let Some(sheen) = layers.sheen {
+            let strength = sheen.color.x.max(sheen.color.y).max(sheen.color.z);
+            let grazing = 1.0 - cos_theta.get_coord(i);
+            let p_sheen = strength * grazing * grazing * grazing * grazing * grazing;
+            if u < p_sheen {
+                materials[i] = sheen.material();
+                replaced[i] = true;
+                tints[i] = sheen.color * (1.0 / strength);
+                continue;
+            }
+            u = (u - p_sheen) / (1.0 - p_sheen);
+        }
+
+        let r = reflectance.extract(i);
+        let p_coat = (r.x + r.y + r.z) * (1.0 / 3.0);
+        if u < p_coat {
+            materials[i] = layers.coat.unwrap().material();
+            replaced[i] = true;
+            factors[i] = r * (1.0 / p_coat);
+            continue;
+        }
+
+        // Light that reaches the base passes through the coat twice. On the
+        // way out it can leave in any direction, the average reflectance
+        // accounts for that. A film is only evaluated in the incoming
+        // direction, so assume that light leaves the way it came in.
+        if let Some(coat) = layers.coat {
+            let t = SVector3::one() - r;
+            let t_out = match coat.film {
+                Some(..) => t,
+                None => SVector3::one() * (1.0 - coat.average_reflectance()),
+            };
+            factors[i] = SVector3::new(t.x * t_out.x, t.y * t_out.y, t.z * t_out.z) * (1.0 / (1.0 - p_coat));
+        }
+
+        match layers.base {
+            Base::Opaque(base) => {
+                materials[i] = base;
+                replaced[i] = true;
+            }
+            Base::Translucent(..) => translucent[i] = true,
+        }
+    }
+
+    let to_mask = |flags: &[bool; 8]| Mf32::generate(|i| if flags[i] { -1.0 } else { 0.0 });
+    let picked = MMaterial::generate_material(|i| materials[i]);
+    let tint = channels.uplift(MVector3::generate(|i| tints[i]));
+    let factor = MVector3::generate(|i| factors[i]);
+
+    MLayerSample {
+        material: isect.material.pick(picked, to_mask(&replaced)),
+        weight: tint.mul_coords(factor),
+        translucent: to_mask(&translucent),
+    }
+}
+
+#[test]
+fn average_reflectance_matches_integral() {
+    let coat = Coat::lacquer();
+    let r0 = coat.r0();
+
+    // Integrate 2 cos(theta) F(theta) over the hemisphere numerically, with
+    // the substitution u = cos(theta)^2.
+    let n = 10_000;
+    let mut sum = 0.0;
+    for i in 0..n {
+        let u = (i as f32 + 0.5) / n as f32;
+        let x = 1.0 - u.sqrt();
+        sum += r0 + (1.0 - r0) * x * x * x * x * x;
+    }
+    let average = sum / n as f32;
+    assert!((average - coat.average_reflectance()).abs() < 1e-4);
+}
+
+#[test]
+fn film_without_thickness_is_bare_interface() {
+    let film = ThinFilm::new(0.0, 1.8);
+    for &cos_theta in &[1.0, 0.7, 0.2] {
+        // Without a film, the reflectance is that of air on the substrate.
+        let bare = ThinFilm::new(0.0, 1.5).reflectance(550.0, cos_theta, 1.5);
+        let r = film.reflectance(550.0, cos_theta, 1.5);
+        assert!((r - bare).abs() < 1e-5, "{} != {} at cos theta {}", r, bare, cos_theta);
+    }
+    assert!((film.reflectance(550.0, 1.0, 1.5) - 0.04).abs() < 1e-5);
+}
+
+#[test]
+fn anti_reflective_film_reflects_less_green() {
+    let film = ThinFilm::anti_reflective();
+    let green = film.reflectance(550.0, 1.0, 1.5);
+    assert!(green < 0.015, "reflectance is {}", green);
+    assert!(film.reflectance(450.0, 1.0, 1.5) > green);
+    assert!(film.reflectance(650.0, 1.0, 1.5) > green);
+}
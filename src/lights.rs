@@ -0,0 +1,186 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements analytic light sources.
+//!
+//! Unlike emissive triangles, these lights have no geometry: a point light is
+//! infinitely small, and a directional light is infinitely far away. A ray
+//! sampled from the BRDF can never hit them, so the only way to gather their
+//! light is to sample them directly. There is nothing to weigh against, so no
+//! multiple importance sampling is needed either. Every light is sampled at
+//! every bounce; a scene is not expected to have more than a handful of them.
+
+use scene::FAR_AWAY;
+use simd::Mf32;
+use vector3::{MVector3, SVector3};
+
+/// A light source without geometry.
+#[derive(Clone, Debug)]
+pub enum Light {
+    /// A light that emits equally in all directions from a single point.
+    Point {
+        position: SVector3,
+
+        /// The radiant intensity per color channel.
+        intensity: SVector3,
+    },
+
+    /// A point light that only emits in a cone.
+    Spot {
+        position: SVector3,
+
+        /// The axis of the cone. Must be normalized.
+        direction: SVector3,
+
+        /// The radiant intensity per color channel along the axis.
+        intensity: SVector3,
+
+        /// The cosine of the angle up to which the light emits at full
+        /// intensity.
+        cos_inner: f32,
+
+        /// The cosine of the angle beyond which the light emits nothing.
+        cos_outer: f32,
+    },
+
+    /// A light infinitely far away, that illuminates everything from the same
+    /// direction, like the sun.
+    Directional {
+        /// The direction in which the light travels. Must be normalized.
+        direction: SVector3,
+
+        /// The irradiance on a surface perpendicular to the direction.
+        irradiance: SVector3,
+    },
+}
+
+/// The light that arrives at a point from an analytic light source.
+pub struct MIllumination {
+    /// Normalized direction from the point towards the light.
+    pub direction: MVector3,
+
+    /// The distance to the light. Shadow rays should not look further.
+    pub distance: Mf32,
+
+    /// The irradiance on a surface perpendicular to the direction.
+    pub irradiance: MVector3,
+}
+
+impl Light {
+    pub fn point(position: SVector3, intensity: SVector3) -> Light {
+        Light::Point {
+            position: position,
+            intensity: intensity,
+        }
+    }
+
+    /// Constructs a spot light. The light falls off smoothly from full
+    /// intensity at `inner_angle` to nothing at `outer_angle` (in radians,
+    /// measured from the axis).
+    pub fn spot(position: SVector3,
+                direction: SVector3,
+                intensity: SVector3,
+                inner_angle: f32,
+                outer_angle: f32)
+                -> Light {
+        assert!(inner_angle <= outer_angle, "inner angle must not exceed outer angle");
+        Light::Spot {
+            position: position,
+            direction: direction.normalized(),
+            intensity: intensity,
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+        }
+    }
+
+    pub fn directional(direction: SVector3, irradiance: SVector3) -> Light {
+        Light::Directional {
+            direction: direction.normalized(),
+            irradiance: irradiance,
+        }
+    }
+
+    /// Returns the light that arrives at the given points, ignoring
+    /// occlusion.
+    pub fn illuminate(&self, position: MVector3) -> MIllumination {
+        match *self {
+            Light::Point { position: light_pos, intensity } => {
+                let (direction, distance, falloff) = towards_point(light_pos, position);
+                MIllumination {
+                    direction: direction,
+                    distance: distance,
+                    irradiance: MVector3::broadcast(intensity) * falloff,
+                }
+            }
+            Light::Spot { position: light_pos, direction: axis, intensity, cos_inner, cos_outer } => {
+                let (direction, distance, falloff) = towards_point(light_pos, position);
+
+                // Smoothly interpolate between the inner and outer cone with a
+                // smoothstep of the cosine.
+                let cos_theta = -direction.dot(MVector3::broadcast(axis));
+                let range_recip = Mf32::broadcast(1.0 / (cos_inner - cos_outer).max(1e-6));
+                let t = ((cos_theta - Mf32::broadcast(cos_outer)) * range_recip)
+                    .max(Mf32::zero())
+                    .min(Mf32::one());
+                let smooth = t * t * Mf32::broadcast(2.0).neg_mul_add(t, Mf32::broadcast(3.0));
+
+                MIllumination {
+                    direction: direction,
+                    distance: distance,
+                    irradiance: MVector3::broadcast(intensity) * (falloff * smooth),
+                }
+            }
+            Light::Directional { direction, irradiance } => {
+                // Shadow rays stop where the sky is.
+                MIllumination {
+                    direction: -MVector3::broadcast(direction),
+                    distance: Mf32::broadcast(FAR_AWAY),
+                    irradiance: MVector3::broadcast(irradiance),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the normalized direction from the points to the light, the
+/// distance, and the inverse square falloff.
+fn towards_point(light_position: SVector3, position: MVector3) -> (MVector3, Mf32, Mf32) {
+    let to_light = MVector3::broadcast(light_position) - position;
+    let distance_sqr = to_light.norm_squared();
+    let rnorm = distance_sqr.rsqrt();
+    let direction = to_light * rnorm;
+    let distance = distance_sqr * rnorm;
+    (direction, distance, distance_sqr.recip_fast())
+}
+
+#[test]
+fn point_light_falls_off_with_inverse_square() {
+    let light = Light::point(SVector3::new(0.0, 0.0, 2.0), SVector3::new(4.0, 4.0, 4.0));
+    let illum = light.illuminate(MVector3::zero());
+    assert!((illum.distance.0 - 2.0).abs() < 1e-3);
+    assert!((illum.direction.z.0 - 1.0).abs() < 1e-3);
+    assert!((illum.irradiance.x.0 - 1.0).abs() < 1e-2);
+}
+
+#[test]
+fn spot_light_is_dark_outside_cone() {
+    use std::f32::consts::PI;
+
+    let light = Light::spot(SVector3::new(0.0, 0.0, 1.0),
+                            SVector3::new(0.0, 0.0, -1.0),
+                            SVector3::new(1.0, 1.0, 1.0),
+                            PI / 8.0,
+                            PI / 6.0);
+
+    // Right below the light the intensity is not attenuated by the cone.
+    let below = light.illuminate(MVector3::zero());
+    assert!((below.irradiance.x.0 - 1.0).abs() < 1e-2);
+
+    // At 45 degrees the point is outside of the cone.
+    let aside = light.illuminate(MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0)));
+    assert_eq!(0.0, aside.irradiance.x.0);
+}
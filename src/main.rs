@@ -13,6 +13,7 @@
 
 extern crate alloc;
 extern crate filebuffer;
+extern crate flate2;
 extern crate imagefmt;
 extern crate num_cpus;
 extern crate rand;
@@ -26,62 +27,143 @@ extern crate time;
 extern crate glium;
 
 mod aabb;
+mod alias_table;
+mod bdpt;
 mod bvh;
+mod daylight;
+mod emitters;
+mod environment;
+mod exr;
+mod hdr;
+mod integrator;
+mod layered;
+mod lights;
 mod material;
+mod medium;
+mod path_tracer;
+mod photon_map;
+mod ppm;
+mod procedural;
 mod quaternion;
 mod random;
 mod ray;
 mod renderer;
 mod scene;
 mod simd;
+mod spectral;
 mod stats;
+mod subsurface;
+mod texture;
 mod trace;
 mod triangle;
 mod ui;
 mod util;
 mod vector3;
+mod voxel;
 mod wavefront;
 
 #[cfg(test)]
 mod bench;
 
+use environment::EnvironmentMap;
+use layered::{Coat, Layered};
+use lights::Light;
 use material::SMaterial;
+use medium::{GridVolume, Medium};
+use procedural::{Pattern, Procedural};
+use quaternion::SQuaternion;
 use renderer::{RenderBuffer, Renderer};
 use scene::Scene;
 use stats::GlobalStats;
 use std::collections::HashMap;
+use std::env;
 use std::mem;
+use subsurface::Subsurface;
+use texture::{Texture, TextureSet};
 use time::PreciseTime;
+use triangle::Sidedness;
 use ui::{Action, Window};
+use vector3::SVector3;
+use voxel::VoxelGrid;
 use wavefront::Mesh;
 
-fn load_textures() -> Vec<Vec<u8>> {
-    use imagefmt::ColFmt;
-
+fn build_scene() -> Scene {
     println!("loading textures");
-    let tex_floor = imagefmt::read("textures/floor.jpg", ColFmt::RGB);
-    let tex_wood = imagefmt::read("textures/wood_light.jpg", ColFmt::RGB);
-    let mut textures = Vec::with_capacity(2);
-    textures.push(tex_floor.expect("failed to read floor.jpeg").buf);
-    textures.push(tex_wood.expect("failed to read wood_light.jpg").buf);
-    textures
-}
+    let mut textures = TextureSet::new();
+    let floor = textures.load("floor", "textures/floor.jpg");
+    let wood_light = textures.load("wood_light", "textures/wood_light.jpg");
+
+    // The ceiling is made of panels of 60 cm, the seams between them are a
+    // pattern in world space, so the ceiling needs no texture coordinates.
+    let panels = Procedural::new(Pattern::Grid(0.02))
+        .in_world_space()
+        .with_scale(1.0 / 0.6)
+        .with_colors(SVector3::new(0.9, 0.9, 0.88), SVector3::new(0.4, 0.4, 0.4));
+    let ceiling = textures.insert_procedural("ceiling", panels);
 
-fn build_scene() -> Scene {
     println!("loading geometry");
+    let ceiling = SMaterial::white().with_glossiness(1).with_texture(ceiling);
+    let floor = SMaterial::white().with_glossiness(4).with_texture(floor);
+    let wall = SMaterial::diffuse(0.65, 0.7, 0.9).with_glossiness(1);
     let mut materials = HashMap::new();
     materials.insert("baseboard", SMaterial::white().with_glossiness(4));
-    materials.insert("ceiling", SMaterial::white().with_glossiness(1));
-    materials.insert("fauteuil", SMaterial::diffuse(1.0, 0.1, 0.4));
-    materials.insert("floor", SMaterial::diffuse(0.569, 0.494, 0.345).with_glossiness(4).with_texture(1));
+    materials.insert("ceiling", ceiling);
+    materials.insert("fauteuil", SMaterial::layered(1));
+    materials.insert("floor", floor);
     materials.insert("glass", SMaterial::sky());
-    materials.insert("wall", SMaterial::diffuse(0.65, 0.7, 0.9).with_glossiness(1));
-    materials.insert("wood_light", SMaterial::diffuse(0.6, 0.533, 0.455).with_glossiness(3).with_texture(2));
-    let indoor = Mesh::load_with_materials("models/indoor.obj", &materials);
-    let meshes = [indoor];
+    materials.insert("wall", wall);
+    materials.insert("wood_light", SMaterial::layered(0));
+
+    // The walls, floor, and ceiling all face into the room. Their outside
+    // should never be lit, so make it black rather than let light that leaks
+    // around the edges bounce off it.
+    let indoor = Mesh::load_with_materials("models/indoor.obj", &materials)
+        .with_sidedness(ceiling, Sidedness::OneSided)
+        .with_sidedness(floor, Sidedness::OneSided)
+        .with_sidedness(wall, Sidedness::OneSided);
+
+    // A jade bunny of 40 cm sits on the floor in the middle of the room.
+    let bunny = Mesh::load("models/stanford_bunny.obj")
+        .with_transform(SVector3::new(1.5, -0.01, 1.0), 0.04)
+        .with_material(SMaterial::layered(2));
+    let meshes = [indoor, bunny];
 
     println!("building bvh");
-    let scene = Scene::from_meshes(&meshes);
+    let mut scene = Scene::from_meshes(&meshes);
+    scene.set_textures(textures);
+
+    // The light wood is lacquered, the fauteuil is upholstered in velvet, and
+    // light scatters below the surface of the bunny.
+    let wood = SMaterial::white().with_glossiness(3).with_texture(wood_light);
+    scene.set_layered(vec![Layered::opaque(wood).with_coat(Coat::lacquer()),
+                           Layered::velvet(1.0, 0.1, 0.4),
+                           Layered::translucent(Subsurface::jade().scaled(0.04))]);
+
+    // A reading lamp shines down on the fauteuil from the ceiling.
+    scene.add_light(Light::spot(SVector3::new(-3.0, 3.2, 0.0),
+                                SVector3::new(0.1, -1.0, 0.0),
+                                SVector3::new(4.0, 3.4, 2.6),
+                                0.35,
+                                0.6));
+
+    if let Some(path) = env::args().nth(1) {
+        println!("loading environment map");
+        scene.set_environment(EnvironmentMap::load(&path));
+    }
+
+    // A voxel grid of smoke can be passed as the second argument, it is placed
+    // as a cube of a meter in the middle of the room.
+    if let Some(path) = env::args().nth(2) {
+        println!("loading voxel grid");
+        let grid = VoxelGrid::load(&path);
+        let smoke = Medium::new(0.2, 2.0).with_anisotropy(0.3);
+        let volume = GridVolume::new(grid, smoke)
+            .with_transform(SVector3::new(-0.5, 0.0, -0.5),
+                            SQuaternion::new(1.0, 0.0, 0.0, 0.0),
+                            SVector3::new(1.0, 1.0, 1.0));
+        scene.add_grid_volume(volume);
+    }
+
     scene.print_stats();
 
     scene
@@ -98,20 +180,40 @@ fn main() {
     let patch_width = 32;
 
     let mut window = Window::new(width, height, "Convector interactive path tracer");
-    let mut renderer = Renderer::new(build_scene(), width, height);
+    let scene = build_scene();
+
+    // The gbuffer stores the texture index in 8 bits. With more textures than
+    // that, the renderer applies all of them on the CPU.
+    let gpu_textures = scene.textures().len() < 256;
+    if gpu_textures {
+        // Procedural textures are evaluated on the CPU, but a placeholder is
+        // uploaded for them to keep the indices of the other textures intact.
+        let placeholder = Texture::from_linear(1, 1, 3, vec![1.0, 1.0, 1.0]);
+        for index in 0..scene.textures().len() as u32 {
+            window.upload_texture(scene.textures().get(index).unwrap_or(&placeholder));
+        }
+    }
+
+    let mut renderer = Renderer::new(scene, width, height);
+    renderer.set_gpu_textures(gpu_textures);
     let mut stats = GlobalStats::new();
     let mut trace_log = trace::TraceLog::with_limit(6 * 1024);
     let mut threadpool = scoped_threadpool::Pool::new(num_cpus::get() as u32);
     let mut backbuffer = RenderBuffer::new(width, height);
-    let mut backbuffer_g = RenderBuffer::new(width, height);
+    // The gbuffer has two layers, stacked vertically.
+    let mut backbuffer_g = RenderBuffer::new(width, height * 2);
     let mut f32_buffer = renderer.new_buffer_f32(); // TODO: Consistency.
     let mut f32_buffer_samples = 0;
     let mut should_continue = true;
     let mut render_realtime = true;
 
-    for texture in load_textures() {
-        window.upload_texture(texture);
-    }
+    // Whether the realtime preview clamps fireflies. Accumulated renders never
+    // clamp, but the choice made in realtime mode should survive a round trip.
+    let mut clamp_realtime = true;
+
+    // A thin fog that can be toggled, it scatters mostly forward, which
+    // makes the light shafts through the windows visible.
+    let fog = Medium::new(0.01, 0.05).with_anisotropy(0.6);
 
     backbuffer.fill_black();
     let epoch = PreciseTime::now();
@@ -129,20 +231,74 @@ fn main() {
         let time_delta = (stats.frame_us.median() as f32) * 1e-6;
 
         match window.handle_events() {
+            Action::AdjustMaxBounces(delta) => {
+                let max_bounces = (renderer.max_bounces() as i32 + delta).max(1) as u32;
+                let max_bounces = renderer.set_max_bounces(max_bounces);
+                println!("maximum path depth is now {} bounces", max_bounces);
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
             Action::DumpTrace => {
                 trace_log.export_to_file("trace.json").expect("failed to write trace");
                 println!("wrote trace to trace.json");
             }
+            Action::MoveSun(delta_elevation, delta_azimuth) => {
+                if let Some((elevation, azimuth)) = renderer.move_sun(delta_elevation, delta_azimuth) {
+                    println!("sun at elevation {:.2}, azimuth {:.2}", elevation, azimuth);
+                    // The old samples are no longer valid.
+                    f32_buffer = renderer.new_buffer_f32();
+                    f32_buffer_samples = 0;
+                }
+            }
             Action::Quit => should_continue = false,
             Action::PrintStats => stats.print(),
-            Action::ToggleDebugView => renderer.toggle_debug_view(),
+            Action::NextIntegrator => {
+                println!("using the {}", renderer.next_integrator());
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
+            Action::ToggleFog => {
+                let enabled = renderer.toggle_fog(fog);
+                println!("fog {}", if enabled { "enabled" } else { "disabled" });
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
+            Action::ToggleMisHeuristic => {
+                let heuristic = renderer.toggle_mis_heuristic();
+                println!("using the {:?} heuristic for multiple importance sampling", heuristic);
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
+            Action::ToggleClampFireflies => {
+                let enabled = renderer.toggle_clamp_fireflies();
+                println!("firefly clamping {}", if enabled { "enabled" } else { "disabled" });
+                if render_realtime {
+                    clamp_realtime = enabled;
+                }
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
+            Action::ToggleOutlierRejection => {
+                let enabled = renderer.toggle_reject_outliers();
+                println!("outlier rejection {}", if enabled { "enabled" } else { "disabled" });
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
+            }
             Action::ToggleRealtime => {
                 render_realtime = !render_realtime;
                 f32_buffer = renderer.new_buffer_f32();
                 f32_buffer_samples = 0;
                 // In accumulative mode the time is fixed and there is no motion
-                // blur.
+                // blur. Clamping is only for the realtime preview, accumulated
+                // renders should converge to the correct image.
                 renderer.set_time(time, 0.0);
+                renderer.set_clamp_fireflies(render_realtime && clamp_realtime);
+            }
+            Action::ToggleSpectral => {
+                let enabled = renderer.toggle_spectral();
+                println!("spectral rendering {}", if enabled { "enabled" } else { "disabled" });
+                f32_buffer = renderer.new_buffer_f32();
+                f32_buffer_samples = 0;
             }
             Action::None => {}
         }
@@ -161,8 +317,16 @@ fn main() {
             f32_buffer_samples += 1;
         }
 
+        // Some integrators, like the photon mapper, do work for the entire
+        // frame before the patches are rendered.
+        {
+            let _stw = trace_log.scoped("prepare_frame", 0);
+            let pass = if render_realtime { 0 } else { f32_buffer_samples - 1 };
+            renderer.prepare_frame(frame_number, pass);
+        }
+
         let new_backbuffer = RenderBuffer::new(width, height);
-        let new_backbuffer_g = RenderBuffer::new(width, height);
+        let new_backbuffer_g = RenderBuffer::new(width, height * 2);
         let frontbuffer = mem::replace(&mut backbuffer, new_backbuffer);
         let frontbuffer_g = mem::replace(&mut backbuffer_g, new_backbuffer_g);
         let renderer_ref = &renderer;
@@ -170,6 +334,7 @@ fn main() {
         let backbuffer_ref = &backbuffer;
         let backbuffer_g_ref = &backbuffer_g;
         let f32_buffer_ref = &f32_buffer[..];
+        let f32_buffer_prev_samples = if f32_buffer_samples > 0 { f32_buffer_samples - 1 } else { 0 };
 
         threadpool.scoped(|scope| {
 
@@ -196,7 +361,13 @@ fn main() {
                             let _stw = trace_log_ref.scoped("accumulate_patch_f32", j * w + i);
                             let buffer = unsafe { util::make_mutable(f32_buffer_ref) };
                             let gbuffer = unsafe { backbuffer_g_ref.get_mut_slice() };
-                            renderer_ref.accumulate_patch_f32(buffer, gbuffer, patch_width, x, y, frame_number);
+                            renderer_ref.accumulate_patch_f32(buffer,
+                                                              gbuffer,
+                                                              patch_width,
+                                                              x,
+                                                              y,
+                                                              frame_number,
+                                                              f32_buffer_prev_samples);
                         }
                     });
                 }
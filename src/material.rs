@@ -18,16 +18,27 @@
 //!  * Bit 30: if 1, a primitive with this material is eligible for direct
 //!    sampling.
 //!
-//!  * Bit 29: if 1, this material is a glass material.
+//!  * Bit 29: if 1, the material is layered, see the `layered` module. For a
+//!    layered material, bits 0-23 contain the index of its layers in the
+//!    scene. A layered material can also be translucent, see the `subsurface`
+//!    module.
 //!
 //!  * Bits 26-28: the 2-log of the exponent for the Blinn-Phong BRDF plus one.
 //!    Must be between 0 and 6 (inclusive), so the exponent can be 0, 1, 2, 4,
-//!    8, or 16.
+//!    8, 16, or 32.
 //!
-//!  * Bits 24-25: the texture index, ranging from 0 to 3.
+//!  * Bit 25: if 1, the material is a dielectric: it has a white glossy
+//!    highlight on top of a diffuse base in the material color. If 0, the
+//!    whole lobe is tinted by the color, like a metal.
+//!
+//!  * Bit 24: if 1, the material is textured.
 //!
 //!  * Bits 0-23 contain the RGB color of the material, red in the least
-//!    significant bits, blue in the most significant bits.
+//!    significant bits, blue in the most significant bits. The channels store
+//!    the square root of the linear color, which spends more of the 8 bits on
+//!    dark colors, where a step in the linear value is visible as banding.
+//!    For a textured material, these bits contain the index of the texture
+//!    instead, and the color comes from the texture.
 //!
 //! # A note on CPU and GPU shading
 //!
@@ -65,20 +76,36 @@
 
 use random::Rng;
 use ray::{MIntersection, MRay};
-use scene::Scene;
+use scene::{FAR_AWAY, Scene};
 use simd::{Mask, Mf32, Mi32};
+use spectral::MChannels;
 use std::f32::consts;
 use vector3::MVector3;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SMaterial(u32);
 
+/// The heuristic used to weigh samples for multiple importance sampling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MisHeuristic {
+    /// Weigh samples proportional to their probability density.
+    Balance,
+
+    /// Weigh samples proportional to the square of their probability density.
+    /// This reduces variance further when one of the strategies is a much
+    /// better match than the other, see section 9.2.4 of Veach, 1997.
+    Power,
+}
+
 pub type MMaterial = Mf32;
 
 pub struct MDirectSample {
     pub position: MVector3,
     pub normal: MVector3,
     pub area: Mf32,
+
+    /// The probability of picking the triangle that the point lies on.
+    pub pmf: Mf32,
 }
 
 impl SMaterial {
@@ -96,13 +123,16 @@ impl SMaterial {
 
     /// A diffuse material with the given color.
     pub fn diffuse(r: f32, g: f32, b: f32) -> SMaterial {
-        let mat = (((b * 255.0) as u32) << 16) | (((g * 255.0) as u32) << 8) | ((r * 255.0) as u32);
+        let encode = |x: f32| (x.max(0.0).min(1.0).sqrt() * 255.0 + 0.5) as u32;
+        let mat = (encode(b) << 16) | (encode(g) << 8) | encode(r);
         SMaterial(mat)
     }
 
-    /// A transparent and reflective material.
-    pub fn glass() -> SMaterial {
-        let mat = 0b0110_0000_00000000_00000000_00000000_u32;
+    /// A layered material, with the layers at the given index in the scene,
+    /// see `Scene::set_layered()`.
+    pub fn layered(index: u32) -> SMaterial {
+        assert!(index < (1 << 24), "layered index out of range");
+        let mat = 0b0010_0000_00000000_00000000_00000000_u32 | index;
         SMaterial(mat)
     }
 
@@ -117,16 +147,31 @@ impl SMaterial {
         SMaterial(mat)
     }
 
-    /// Sets the texture index of the material. Valid values are 0 through 3.
+    /// Makes the material textured, with the texture at the given index in
+    /// the scene's `TextureSet`. This replaces the color of the material.
     pub fn with_texture(self, texture_index: u32) -> SMaterial {
-        assert!(texture_index <= 3);
+        assert!(texture_index < (1 << 24), "texture index out of range");
         let SMaterial(mat) = self;
-        // Mask that resets the texture index to 0.
-        let no_tidx = 0b11111100_11111111_11111111_11111111_u32;
-        let mat = (mat & no_tidx) | (texture_index << 24);
+        // Mask that resets the color and texture bits to 0.
+        let no_color = 0b11111110_00000000_00000000_00000000_u32;
+        let mat = (mat & no_color) | (1 << 24) | texture_index;
         SMaterial(mat)
     }
 
+    /// Returns the index of the texture, or `None` if the material is not
+    /// textured.
+    pub fn texture(self) -> Option<u32> {
+        let SMaterial(mat) = self;
+        if mat & (1 << 24) != 0 { Some(mat & 0xffffff) } else { None }
+    }
+
+    /// Returns the index of the layers, or `None` if the material is not
+    /// layered.
+    pub fn layered_index(self) -> Option<u32> {
+        let SMaterial(mat) = self;
+        if mat & (1 << 29) != 0 { Some(mat & 0xffffff) } else { None }
+    }
+
     /// Returns whether the material is eligible for direct sampling.
     pub fn is_direct_sample(self) -> bool {
         let ds_mask = 0b01000000_00000000_00000000_00000000;
@@ -135,6 +180,22 @@ impl SMaterial {
     }
 }
 
+impl MisHeuristic {
+    /// Returns the weight of a sample drawn with probability density
+    /// `pd_this`, when the other strategy draws it with density `pd_other`.
+    #[inline(always)]
+    pub fn weight(self, pd_this: Mf32, pd_other: Mf32) -> Mf32 {
+        let (a, b) = match self {
+            MisHeuristic::Balance => (pd_this, pd_other),
+            MisHeuristic::Power => (pd_this * pd_this, pd_other * pd_other),
+        };
+
+        // If neither strategy could have produced the sample, the weight does
+        // not matter, but it should not be NaN.
+        a.div((a + b).max(Mf32::broadcast(1.0e-30)))
+    }
+}
+
 impl MMaterial {
     pub fn broadcast_material(material: SMaterial) -> MMaterial {
         use std::mem::transmute;
@@ -143,6 +204,17 @@ impl MMaterial {
         Mf32::broadcast(matf)
     }
 
+    /// Builds a material by applying the function to the numbers 0-7.
+    pub fn generate_material<F>(mut f: F) -> MMaterial
+        where F: FnMut(usize) -> SMaterial
+    {
+        use std::mem::transmute;
+        Mf32::generate(|i| {
+            let SMaterial(mat) = f(i);
+            unsafe { transmute::<u32, f32>(mat) }
+        })
+    }
+
     pub fn sky() -> MMaterial {
         MMaterial::broadcast_material(SMaterial::sky())
     }
@@ -163,8 +235,79 @@ impl MMaterial {
         let mfg255 = mig255.into_mf32();
         let mfb255 = mib255.into_mf32();
 
-        // Convert to a color in the range [0.0, 1.0].
-        MVector3::new(mfr255, mfg255, mfb255) * Mf32::broadcast(1.0 / 255.0)
+        // Convert to a color in the range [0.0, 1.0], and square it to undo
+        // the square root of the encoding.
+        let sqrt_color = MVector3::new(mfr255, mfg255, mfb255) * Mf32::broadcast(1.0 / 255.0);
+        sqrt_color.mul_coords(sqrt_color)
+    }
+
+    /// Returns the material with the color replaced by the given color. The
+    /// color is encoded like `SMaterial` does, with 8 bits for the square root
+    /// of every channel. The result is not textured.
+    pub fn with_color(&self, color: MVector3) -> MMaterial {
+        use std::mem::transmute;
+        let range = Mf32::broadcast(255.0);
+        let mask = Mi32::broadcast(0xff);
+
+        let clamp = |x: Mf32| {
+            let x = x.max(Mf32::zero()).min(Mf32::one());
+            (x.sqrt() * range).into_mi32() & mask
+        };
+        let r = clamp(color.x);
+        let g = clamp(color.y).map(|x| x << 8);
+        let b = clamp(color.z).map(|x| x << 16);
+
+        // Clear the color bits and the texture bit.
+        let mati: Mi32 = unsafe { transmute(*self) };
+        let without_color = mati & Mi32::broadcast(!0x1ffffff);
+        unsafe { transmute(without_color | (r | g) | b) }
+    }
+
+    /// Returns the material with the glossiness replaced per lane. Values
+    /// must be between 0 and 5.
+    pub fn with_glossiness(&self, glossiness: Mi32) -> MMaterial {
+        use std::mem::transmute;
+        let mati: Mi32 = unsafe { transmute(*self) };
+        let no_gloss = mati & Mi32::broadcast(!(0b111 << 26));
+        unsafe { transmute(no_gloss | glossiness.map(|x| x << 26)) }
+    }
+
+    /// Returns the material with the dielectric bit set where the sign bit of
+    /// the mask is 1, and cleared elsewhere.
+    pub fn with_dielectric(&self, dielectric: Mask) -> MMaterial {
+        use std::mem::transmute;
+        let mati: Mi32 = unsafe { transmute(*self) };
+        let maski: Mi32 = unsafe { transmute(dielectric) };
+        let bit = maski.map(|x| if x < 0 { 1 << 25 } else { 0 });
+        unsafe { transmute((mati & Mi32::broadcast(!(1 << 25))) | bit) }
+    }
+
+    /// Sets the sign bit to 1 if the material is a dielectric, or 0 if it is
+    /// not.
+    pub fn is_dielectric(&self) -> Mask {
+        use std::mem::transmute;
+
+        // Move the dielectric bit into the sign bit.
+        let mati: Mi32 = unsafe { transmute(*self) };
+        unsafe { transmute(mati.map(|x| x << 6)) }
+    }
+
+    /// Sets the sign bit to 1 if the material is layered, or 0 if it is not.
+    pub fn is_layered(&self) -> Mask {
+        use std::mem::transmute;
+
+        // Move the layered bit into the sign bit.
+        let mati: Mi32 = unsafe { transmute(*self) };
+        unsafe { transmute(mati.map(|x| x << 2)) }
+    }
+
+    /// Unpacks the index of the layers. The result is only meaningful for
+    /// layered materials.
+    pub fn get_layered(&self) -> Mi32 {
+        use std::mem::transmute;
+
+        let mati: Mi32 = unsafe { transmute(*self) };
+        mati & Mi32::broadcast(0xffffff)
     }
 
     /// Unpacks the Blinn-Phong glossiness exponent.
@@ -177,43 +320,41 @@ impl MMaterial {
         exponent & Mi32::broadcast(0b111)
     }
 
-    /// Unpacks the texture index.
+    /// Returns the angle in radians by which a ray cone widens when it
+    /// reflects off the material. This is roughly the width of the Blinn-Phong
+    /// lobe, sqrt(2 / (n + 2)) for exponent n, so a diffuse bounce spreads the
+    /// cone a lot and a glossy bounce less.
+    pub fn get_lobe_spread(&self) -> Mf32 {
+        // Indexed by the glossiness, for exponents 0, 1, 2, 4, 8, 16, and 32.
+        // The constructors never produce glossiness 7, but the field has three
+        // bits, so the table covers it by repeating the glossiest lobe.
+        let spreads = [1.0, 0.8165, 0.7071, 0.5774, 0.4472, 0.3333, 0.2425, 0.2425];
+        let gloss = self.get_glossiness();
+        Mf32::generate(|i| spreads[gloss.get_coord(i) as usize])
+    }
+
+    /// Unpacks the texture index plus one, or 0 if the material is not
+    /// textured.
     pub fn get_texture(&self) -> Mi32 {
         use std::mem::transmute;
 
-        // Extract the two texture index bits.
         let mati: Mi32 = unsafe { transmute(*self) };
-        let tidx = mati.map(|x| x >> 24);
-        tidx & Mi32::broadcast(0b11)
+        mati.map(|x| if x & (1 << 24) != 0 { (x & 0xffffff) + 1 } else { 0 })
     }
 
-    /// Sets the sign bit to 1 if the surface has a texture, or 0 if the texture
-    /// index is 0 (indicating no texture).
+    /// Sets the sign bit to 1 if the surface has a texture, or 0 if it does
+    /// not.
     pub fn has_texture(&self) -> Mask {
         use std::mem::transmute;
 
-        // Take the bitwise or of all bits that determine the texture ID. Only
-        // if the texture ID was zero will this result in 0.
+        // Move the texture bit into the sign bit.
         let mati: Mi32 = unsafe { transmute(*self) };
-        let has_tex = mati.map(|x| x << 6) | mati.map(|x| x << 7);
+        let has_tex = mati.map(|x| x << 7);
 
         unsafe { transmute(has_tex) }
     }
 }
 
-/// Returns the sky color for a ray in the given direction.
-pub fn sky_intensity(ray_direction: MVector3) -> MVector3 {
-    // TODO: Better sky model.
-    let up = MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one());
-    let half = Mf32::broadcast(0.5);
-    let two = Mf32::broadcast(2.0);
-    let d = ray_direction.dot(up).mul_add(half, half);
-    let r = d;
-    let g = d * d;
-    let b = d * (d * d);
-    MVector3::new(r, g, b).mul_add(two, MVector3::new(half, half, half))
-}
-
 /// Continues the path of a photon by sampling the BRDF.
 ///
 /// This samples the hemisphere in a cosine-weighted distribution, it does not
@@ -242,94 +383,294 @@ fn continue_path_brdf(isect: &MIntersection, rng: &mut Rng) -> MRay {
 }
 
 /// Returns the probability density for the BRDF sampler at a given ray.
-fn pd_brdf(isect: &MIntersection, ray: &MRay) -> Mf32 {
+pub fn pd_brdf(isect: &MIntersection, ray: &MRay) -> Mf32 {
     // The probability density for the ray is dot(normal, direction) divided by
     // the intgral of that over the hemisphere (which happens to be pi).
     let dot_surface = isect.normal.dot(ray.direction).max(Mf32::zero());
     dot_surface * Mf32::broadcast(1.0 / consts::PI)
 }
 
-/// Continues the path of a photon by sampling a point on a surface.
-fn continue_path_direct_sample(scene: &Scene, isect: &MIntersection, rng: &mut Rng) -> MRay {
-    let ds = scene.get_direct_sample(rng);
-    let direction = (ds.position - isect.position).normalized();
+/// Samples light from an emitter directly (next event estimation).
+///
+/// This picks a point on an emissive triangle and traces a shadow ray towards
+/// it. If nothing blocks the shadow ray, the light that the emitter sends
+/// towards the surface is returned, weighted for multiple importance sampling
+/// with BRDF sampling. The light must still be multiplied by the path
+/// throughput.
+///
+/// When `ignore_fresnel` is set, the BRDF is evaluated without texture, like
+/// in `continue_path`. The Fresnel factor that is sent to the GPU is the one
+/// for the BRDF-sampled ray, so for textured surfaces the Fresnel factor of
+/// the first bounce is slightly off for direct light. It is not noticeable.
+pub fn sample_direct_light(material: MMaterial,
+                           scene: &Scene,
+                           ray: &MRay,
+                           isect: &MIntersection,
+                           rng: &mut Rng,
+                           heuristic: MisHeuristic,
+                           ignore_fresnel: bool,
+                           channels: MChannels)
+                           -> MVector3 {
+    if !scene.has_emitters() {
+        return MVector3::zero();
+    }
 
-    // Build a new ray, offset by an epsilon from the intersection so we
-    // don't intersect the same surface again.
-    let origin = direction.mul_add(Mf32::epsilon(), isect.position);
-    MRay {
-        origin: origin,
+    let ds = scene.get_direct_sample(isect, rng);
+    let to_light = ds.position - isect.position;
+    let distance_sqr = to_light.norm_squared();
+    let distance = distance_sqr.sqrt();
+    let direction = to_light * distance_sqr.rsqrt();
+
+    // There is no point in tracing shadow rays for inactive rays, for rays
+    // that hit an emissive surface, or when the light is behind the surface.
+    // The cosine has its sign bit set in that case, so or-ing it into the
+    // mask deactivates those rays.
+    let cos_theta_signed = isect.normal.dot(direction);
+    let active = ray.active | isect.material | cos_theta_signed;
+
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    // Offset the origin by an epsilon so we don't intersect the same surface
+    // again.
+    let shadow_ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), isect.position),
         direction: direction,
-        active: Mf32::zero(),
+        active: active,
+    };
+
+    // The sample was drawn with respect to surface area, but BRDF sampling
+    // works with solid angle. See `Emitters::pd()` for the conversion factor.
+    let dot_emissive = ds.normal.dot(direction).abs() + Mf32::broadcast(0.0001);
+    let pd_light = ds.pmf * distance_sqr * (ds.area * dot_emissive).recip_fast();
+    let pd_brdf = pd_brdf(isect, &shadow_ray);
+    let weight = heuristic.weight(pd_light, pd_brdf);
+
+    let cos_theta = cos_theta_signed.max(Mf32::zero());
+    let (brdf_term, _fresnel) = microfacet_brdf(material, &shadow_ray, ray, isect, ignore_fresnel);
+    let emission = channels.uplift(scene.sky_radiance(direction));
+    let factor = weight * cos_theta * pd_light.recip_fast();
+    let light = brdf_term.mul_coords(emission) * factor;
+
+    debug_assert!(pd_light.all_finite());
+    debug_assert!(weight.all_finite());
+
+    // Fog and other media between the surface and the light attenuate it.
+    let light = light * scene.transmittance(&shadow_ray, distance, rng);
+    let occluded = scene.is_occluded(&shadow_ray, distance);
+    light.pick(MVector3::zero(), active | occluded)
+}
+
+/// Samples light from the environment map directly.
+///
+/// Picks a direction proportional to the luminance of the environment, and
+/// traces a shadow ray to see if the sky is visible in that direction. The
+/// light is weighted for multiple importance sampling with BRDF sampling, and
+/// must still be multiplied by the path throughput.
+pub fn sample_environment_light(material: MMaterial,
+                                scene: &Scene,
+                                ray: &MRay,
+                                isect: &MIntersection,
+                                rng: &mut Rng,
+                                heuristic: MisHeuristic,
+                                ignore_fresnel: bool,
+                                channels: MChannels)
+                                -> MVector3 {
+    if !scene.has_environment() {
+        return MVector3::zero();
     }
+
+    let (direction, pd_env) = scene.sample_environment(rng);
+
+    // As for direct sampling, skip rays that are inactive, that hit an
+    // emitter, or for which the direction is below the surface.
+    let cos_theta_signed = isect.normal.dot(direction);
+    let active = ray.active | isect.material | cos_theta_signed;
+
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    let shadow_ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), isect.position),
+        direction: direction,
+        active: active,
+    };
+
+    let pd_brdf = pd_brdf(isect, &shadow_ray);
+    let weight = heuristic.weight(pd_env, pd_brdf);
+
+    // A density of zero can only occur at the poles, where the weight is zero
+    // too. Avoid dividing by zero there.
+    let cos_theta = cos_theta_signed.max(Mf32::zero());
+    let (brdf_term, _fresnel) = microfacet_brdf(material, &shadow_ray, ray, isect, ignore_fresnel);
+    let emission = channels.uplift(scene.sky_radiance(direction));
+    let factor = weight * cos_theta * pd_env.max(Mf32::broadcast(1.0e-20)).recip_fast();
+    let light = brdf_term.mul_coords(emission) * factor;
+
+    debug_assert!(light.all_finite());
+
+    let light = light * scene.transmittance(&shadow_ray, Mf32::broadcast(FAR_AWAY), rng);
+    let occluded = scene.is_occluded(&shadow_ray, Mf32::broadcast(FAR_AWAY));
+    light.pick(MVector3::zero(), active | occluded)
+}
+
+/// Samples light from the sun directly.
+///
+/// Picks a direction in the sun disk, and traces a shadow ray to see if the
+/// sun is visible. Windows do not block the sun, only opaque geometry does.
+/// Like for `sample_environment_light()`, the light is weighted for multiple
+/// importance sampling with BRDF sampling, and must still be multiplied by the
+/// path throughput.
+pub fn sample_sun_light(material: MMaterial,
+                        scene: &Scene,
+                        ray: &MRay,
+                        isect: &MIntersection,
+                        rng: &mut Rng,
+                        heuristic: MisHeuristic,
+                        ignore_fresnel: bool,
+                        channels: MChannels)
+                        -> MVector3 {
+    if !scene.has_sun() {
+        return MVector3::zero();
+    }
+
+    let (direction, pd_sun) = scene.sample_sun(rng);
+
+    // As for direct sampling, skip rays that are inactive, that hit an
+    // emitter, or for which the sun is below the surface.
+    let cos_theta_signed = isect.normal.dot(direction);
+    let active = ray.active | isect.material | cos_theta_signed;
+
+    if active.all_sign_bits_negative() {
+        return MVector3::zero();
+    }
+
+    let shadow_ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), isect.position),
+        direction: direction,
+        active: active,
+    };
+
+    let pd_brdf = pd_brdf(isect, &shadow_ray);
+    let weight = heuristic.weight(pd_sun, pd_brdf);
+
+    let cos_theta = cos_theta_signed.max(Mf32::zero());
+    let (brdf_term, _fresnel) = microfacet_brdf(material, &shadow_ray, ray, isect, ignore_fresnel);
+    let emission = channels.uplift(scene.sun_radiance(direction));
+    let factor = weight * cos_theta * pd_sun.recip_fast();
+    let light = brdf_term.mul_coords(emission) * factor;
+
+    debug_assert!(light.all_finite());
+
+    let light = light * scene.transmittance(&shadow_ray, Mf32::broadcast(FAR_AWAY), rng);
+    let occluded = scene.is_occluded_by_opaque(&shadow_ray, Mf32::broadcast(FAR_AWAY));
+    light.pick(MVector3::zero(), active | occluded)
 }
 
-/// Asserts that the values where active has sign bit 0 (positive) are nonzero.
-fn debug_assert_all_nonzero(x: Mf32, active: Mf32, tag: &str) {
-    debug_assert!(x.0 != 0.0 || active.0.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.1 != 0.0 || active.1.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.2 != 0.0 || active.2.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.3 != 0.0 || active.3.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.4 != 0.0 || active.4.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.5 != 0.0 || active.5.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.6 != 0.0 || active.6.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
-    debug_assert!(x.7 != 0.0 || active.7.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
+/// Gathers light from the analytic light sources in the scene.
+///
+/// Every light is visited and gets its own shadow ray. These lights cannot be
+/// hit by BRDF sampling, so the light is not weighted. Like for
+/// `sample_direct_light()`, the light must still be multiplied by the path
+/// throughput.
+pub fn sample_analytic_lights(material: MMaterial,
+                              scene: &Scene,
+                              ray: &MRay,
+                              isect: &MIntersection,
+                              rng: &mut Rng,
+                              ignore_fresnel: bool,
+                              channels: MChannels)
+                              -> MVector3 {
+    let mut light = MVector3::zero();
+
+    for source in scene.lights() {
+        let illum = source.illuminate(isect.position);
+
+        // As for direct sampling, skip rays that are inactive, that hit an
+        // emitter, or for which the light is behind the surface.
+        let cos_theta_signed = isect.normal.dot(illum.direction);
+        let active = ray.active | isect.material | cos_theta_signed;
+
+        if active.all_sign_bits_negative() {
+            continue;
+        }
+
+        let shadow_ray = MRay {
+            origin: illum.direction.mul_add(Mf32::epsilon(), isect.position),
+            direction: illum.direction,
+            active: active,
+        };
+
+        let cos_theta = cos_theta_signed.max(Mf32::zero());
+        let (brdf_term, _fresnel) = microfacet_brdf(material, &shadow_ray, ray, isect, ignore_fresnel);
+        let transmitted = scene.transmittance(&shadow_ray, illum.distance, rng);
+        let irradiance = channels.uplift(illum.irradiance);
+        let contribution = brdf_term.mul_coords(irradiance) * (cos_theta * transmitted);
+
+        debug_assert!(contribution.all_finite());
+
+        let occluded = scene.is_occluded_by_opaque(&shadow_ray, illum.distance);
+        light = light + contribution.pick(MVector3::zero(), active | occluded);
+    }
+
+    light
 }
 
-/// Returns the probability density for the given ray, for the direct sampling
-/// distribution.
-fn pd_direct_sample(scene: &Scene, ray: &MRay) -> Mf32 {
-    let mut pd_total = Mf32::zero();
-    scene.foreach_direct_sample(|triangle| {
-        // The probability density for the point on the triangle is simply
-        // 1/area, but we want to know the probability of the ray direction, not
-        // the probability of the point. The conversion factor is cos(phi)/r^2,
-        // where phi is the angle between the ray and the surface normal. A
-        // hand-waving justification: imagine a small triangle on a unit
-        // hemisphere, small enough that its area equals the solid angle it
-        // subtends. Then the pdf for the point and the ray will be equal
-        // (1/area). Now move the triangle away. The solid angle decreases
-        // proportional to r^2, so we must compensate the pdf to keep it
-        // normalized. Now rotate the small triangle. When cos(phi) is 0, the
-        // projection is a line of zero surface area, but it needs to integrate
-        // to 1, so the pdf goes to infinity as cos(phi) goes to 0.
-        let sample_isect = triangle.intersect_direct(ray);
-        let distance_sqr = sample_isect.distance * sample_isect.distance;
-        // Add a small constant to avoid division by zero later on.
-        let dot_emissive = sample_isect.normal.dot(ray.direction).abs() + Mf32::broadcast(0.0001);
-        let pd = distance_sqr * (sample_isect.area * dot_emissive).recip_fast();
-
-        debug_assert_all_nonzero(sample_isect.area, ray.active, "area");
-        debug_assert_all_nonzero(dot_emissive, ray.active, "dot_emissive");
-
-        // Add the probability density if the triangle was intersected. If the
-        // triangle was not intersected, the probability of sampling it directly
-        // was 0.
-        pd_total = (pd_total + pd).pick(pd_total, sample_isect.mask);
-
-        debug_assert!(pd_total.all_finite());
-        debug_assert!(pd_total.all_sign_bits_positive(),
-                      "probability density must be positive");
-    });
-
-    // So far we computed the probability density per triangle, but we picked
-    // one triangle uniformly, so compensate for that too.
-    // TODO: I can make my pick non-uniform but take the angle into account.
-    // That should reduce variance.
-    pd_total * Mf32::broadcast(1.0 / scene.direct_sample_num() as f32)
+/// Returns the light emitted towards `isect` by the emitter that a ray sampled
+/// from the BRDF there hit at the given distance, or by the sky if the ray
+/// escaped. The light is weighted for multiple importance sampling, and must
+/// still be multiplied by the path throughput.
+///
+/// A path that ends on an emissive triangle can also be found by direct
+/// sampling, and a path that escapes can also be found by sampling the
+/// environment, but no path can be found by both. The sun is a separate
+/// source of light on top of the sky, that shines through windows. Only sun
+/// sampling can find its light, so it gets its own weight.
+pub fn weighted_emission(scene: &Scene,
+                         isect: &MIntersection,
+                         ray: &MRay,
+                         distance: Mf32,
+                         heuristic: MisHeuristic,
+                         channels: MChannels)
+                         -> MVector3 {
+    let pd_brdf = pd_brdf(isect, ray);
+    let pd_light = scene.pd_direct_sample(isect, ray, distance) + scene.pd_environment(ray, distance);
+
+    debug_assert!(pd_brdf.all_sign_bits_positive(), "probability density cannot be negative");
+    debug_assert!(pd_light.all_sign_bits_positive(), "probability density cannot be negative");
+
+    let sky = channels.uplift(scene.sky_radiance(ray.direction));
+    let sky = sky * heuristic.weight(pd_brdf, pd_light);
+
+    if !scene.has_sun() {
+        return sky;
+    }
+
+    let pd_sun = scene.pd_sun(ray.direction);
+    let sun = channels.uplift(scene.sun_radiance(ray.direction));
+    let sun = sun * heuristic.weight(pd_brdf, pd_sun);
+    sky + sun
 }
 
 /// Continues the path of a photon.
 ///
 /// If a ray intersected a surface with a certain material, then this will
-/// compute the ray that continues the light path. A factor to multiply the
-/// final color by is returned as well, and the Fresnel factor.
+/// compute the ray that continues the light path by sampling the BRDF. A
+/// factor to multiply the path throughput by is returned as well, and the
+/// Fresnel factor. Light from emitters is gathered separately with
+/// `sample_direct_light()`.
+///
+/// If `max_color_mod` is set, the color modulation is clamped to it. This
+/// avoids fireflies, at the cost of losing energy, so it should not be used
+/// for reference renders.
 pub fn continue_path(material: MMaterial,
-                     scene: &Scene,
                      ray: &MRay,
                      isect: &MIntersection,
                      rng: &mut Rng,
-                     ignore_fresnel: bool)
+                     ignore_fresnel: bool,
+                     max_color_mod: Option<f32>)
                      -> (MRay, MVector3, Mf32) {
 
     // Emissive materials have the sign bit set to 1, and a sign bit of 1
@@ -337,65 +678,30 @@ pub fn continue_path(material: MMaterial,
     // deactivates the ray: there is no need for an additional bounce.
     let active = ray.active | isect.material;
 
-    // Generate one ray by sampling the BRDF, and one ray for direct light
-    // sampling.
-    let ray_brdf = continue_path_brdf(isect, rng);
-    let ray_direct = continue_path_direct_sample(scene, isect, rng);
-
-    // Randomly pick one of the two rays to use, then compute the weight for
-    // multiple importance sampling. **Cheat Alert** with which probability do
-    // we pick BRDF sampling or direct sampling? All my light sources are on one
-    // side of the scene, so for surfaces that face the light source, direct
-    // sampling is going to work well. For surfaces that do not face the light
-    // source, direct sampling is going to return black and the only
-    // contribution comes from indirect light. So there we want to sample the
-    // BRDF. Solution: pick with a probability proportional to the z-component
-    // of the normal.
-    let rr = isect.normal.z.mul_add(Mf32::broadcast(0.8), rng.sample_biunit());
-    let new_ray = MRay {
-        origin: ray_brdf.origin.pick(ray_direct.origin, rr),
-        direction: ray_brdf.direction.pick(ray_direct.direction, rr),
-        active: Mf32::zero(),
-    };
-    let pd_brdf = pd_brdf(isect, &new_ray);
-    let pd_direct = pd_direct_sample(scene, &new_ray);
-    // Add a small constant to avoid division by zero later on.
-    let weight_denom = pd_brdf + pd_direct + Mf32::broadcast(0.01);
+    let new_ray = continue_path_brdf(isect, rng);
 
-    debug_assert!(weight_denom.all_finite());
-    debug_assert!(pd_brdf.all_sign_bits_positive(), "probability density cannot be negative");
-    debug_assert!(pd_direct.all_sign_bits_positive(), "probability density cannot be negative");
-
-    // There is a compensation factor 1 / (probability that sampling method was
-    // chosen) in the multiple importance sampler. There is a probability of
-    // (0.5 + normal.z * 0.4) of picking BRDF sampling.
-    let half = Mf32::broadcast(0.5);
-    let p_brdf = isect.normal.z.mul_add(Mf32::broadcast(0.4), half);
-    let p_direct = isect.normal.z.neg_mul_add(Mf32::broadcast(0.4), half);
-    let p = p_brdf.pick(p_direct, rr);
-
-    // Compute the contribution using the one-sample multiple importance
-    // sampler. This is equation 9.15 from section 9.2.4 of Veach, 1997. The
-    // probability densities in the weight and denominator cancel, so they have
-    // been left out. Finally, there is the correction factor for the incident
-    // angle.
-    let modulation = (weight_denom * p).recip_fast();
-    let cos_theta = isect.normal.dot(new_ray.direction).max(Mf32::zero());
+    // The ray was sampled proportional to the cosine of the angle with the
+    // normal, so the cosine factor and the probability density cancel, up to
+    // the normalization factor of pi.
     let (brdf_term, fresnel) = microfacet_brdf(material, &new_ray, ray, isect, ignore_fresnel);
-    let color_mod = brdf_term * (modulation * cos_theta);
+    let color_mod = brdf_term * Mf32::broadcast(consts::PI);
 
-    debug_assert!(modulation.all_finite());
     debug_assert!(brdf_term.all_finite());
-    debug_assert!(modulation.all_sign_bits_positive(), "color modulation can never be negative");
     debug_assert!(brdf_term.x.all_sign_bits_positive(), "red brdf term can never be negative");
     debug_assert!(brdf_term.y.all_sign_bits_positive(), "green brdf term can never be negative");
     debug_assert!(brdf_term.z.all_sign_bits_positive(), "blue brdf term can never be negative");
 
     // Limit the color modulation to avoid fireflies in the final image.
-    let color_mod = MVector3 {
-        x: color_mod.x.min(Mf32::broadcast(2.0)),
-        y: color_mod.y.min(Mf32::broadcast(2.0)),
-        z: color_mod.z.min(Mf32::broadcast(2.0)),
+    let color_mod = match max_color_mod {
+        Some(max) => {
+            let max = Mf32::broadcast(max);
+            MVector3 {
+                x: color_mod.x.min(max),
+                y: color_mod.y.min(max),
+                z: color_mod.z.min(max),
+            }
+        }
+        None => color_mod,
     };
 
     let new_ray = MRay {
@@ -410,6 +716,28 @@ pub fn continue_path(material: MMaterial,
     (new_ray, color_mod, fresnel)
 }
 
+/// Evaluates the BRDF for light that arrives at the surface in the direction
+/// `incoming`, and leaves it in the direction `outgoing`. The BRDF is
+/// symmetric, so it does not matter which way the light actually flows.
+pub fn eval_brdf(material: MMaterial,
+                 isect: &MIntersection,
+                 incoming: MVector3,
+                 outgoing: MVector3)
+                 -> MVector3 {
+    let ray_in = MRay {
+        origin: isect.position,
+        direction: outgoing,
+        active: Mask::zero(),
+    };
+    let ray_out = MRay {
+        origin: isect.position,
+        direction: incoming,
+        active: Mask::zero(),
+    };
+    let (brdf, _fresnel) = microfacet_brdf(material, &ray_in, &ray_out, isect, false);
+    brdf
+}
+
 /// Returns the color modulation for the microfacet BRDF and also the raw
 /// Fresnel factor.
 fn microfacet_brdf(material: MMaterial,
@@ -443,14 +771,30 @@ fn microfacet_brdf(material: MMaterial,
     // 4 * dot(n, l) * dot(n, v) has been absorbed into the geometry factor,
     // which is set to 1 now. (I tried a Kelemen-Szirmay-Kalos geometry term,
     // but it gave unrealistic results with hemisphere sampling.)
-    (f_color * d, f_raw)
+    let brdf = f_color * d;
+
+    let dielectric = material.is_dielectric();
+    if dielectric.all_sign_bits_positive() {
+        return (brdf, f_raw);
+    }
+
+    // A dielectric reflects about 4% of the light at normal incidence in a
+    // white highlight. The light that is not reflected enters the surface and
+    // scatters out diffusely in the surface color. The diffuse lobe uses the
+    // same normalization as glossiness 0.
+    let spec = f_raw.mul_add(Mf32::broadcast(0.96), Mf32::broadcast(0.04));
+    let diffuse_color = if ignore_fresnel { color.pick(white, material.has_texture()) } else { color };
+    let diffuse = diffuse_color * ((Mf32::one() - spec) * Mf32::broadcast(0.5 / consts::PI));
+    let brdf_dielectric = white * (spec * d) + diffuse;
+
+    (brdf.pick(brdf_dielectric, dielectric), f_raw.pick(spec, dielectric))
 }
 
 /// Computes the Fresnel factor using Schlick’s approximation. Also returns the
 /// raw interpolation value, where 0.0 means material color, and 1.0 means
 /// white.
 #[inline(always)]
-fn microfacet_fresnel(incoming: MVector3, half_way: MVector3, color: MVector3) -> (MVector3, Mf32) {
+pub fn microfacet_fresnel(incoming: MVector3, half_way: MVector3, color: MVector3) -> (MVector3, Mf32) {
     let r0 = color;
     let r1 = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one()) - r0;
     let ct = Mf32::one() - half_way.dot(incoming).abs();
@@ -482,19 +826,21 @@ fn microfacet_normal_dist(half_way: MVector3,
     let c4 = c2 * c2;
     let c8 = c4 * c4;
     let c16 = c8 * c8;
+    let c32 = c16 * c16;
 
     // The normalization factor for Blinn-Phong with exponent n in (n + 1)/2pi.
 
     // Blinn-Phong with exponents alpha = 0 (just Lambertian diffuse) through
-    // 16 (a bit glossy, but not yet mirror-like).
+    // 32 (glossy, but not yet mirror-like).
     let a0 = Mf32::broadcast(0.5 / consts::PI);
     let a1 = c1.max(Mf32::zero()) * Mf32::broadcast(1.0 / consts::PI);
     let a2 = c2 * Mf32::broadcast(1.5 / consts::PI);
     let a4 = c4 * Mf32::broadcast(2.5 / consts::PI);
     let a8 = c8 * Mf32::broadcast(4.5 / consts::PI);
     let a16 = c16 * Mf32::broadcast(8.5 / consts::PI);
+    let a32 = c32 * Mf32::broadcast(16.5 / consts::PI);
 
-    let values = [a0, a1, a2, a4, a8, a16];
+    let values = [a0, a1, a2, a4, a8, a16, a32];
 
     // For simplicity (or rather, to keep myself sane), I stray from the SIMD
     // path here and pick the correct value for every element separately. If it
@@ -502,12 +848,111 @@ fn microfacet_normal_dist(half_way: MVector3,
     // steps is not so much worse.
 
     Mf32::generate(|i| {
-        // The exponent index ranges from 0 through 5, so this should be within
+        // The exponent index ranges from 0 through 6, so this should be within
         // bounds. If it is not, then that is a bug in how the material was
         // constructed. In release, we don't want to pay the bounds check
         // overhead here.
         let index = glossiness_exponent_index.get_coord(i);
-        debug_assert!(0 <= index && index <= 5);
+        debug_assert!(0 <= index && index <= 6);
         unsafe { values.get_unchecked(index as usize).get_coord(i) }
     })
 }
+
+#[test]
+fn analytic_light_falls_off_with_inverse_square_distance() {
+    use lights::Light;
+    use vector3::SVector3;
+    use wavefront::quad;
+
+    // Look straight down at the origin, with a point light right above it.
+    let ray = MRay {
+        origin: MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0)),
+        direction: MVector3::broadcast(SVector3::new(0.0, -1.0, 0.0)),
+        active: Mf32::zero(),
+    };
+    let isect = MIntersection {
+        position: MVector3::zero(),
+        normal: MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0)),
+        distance: Mf32::one(),
+        material: MMaterial::broadcast_material(SMaterial::white()),
+        tex_coords: (Mf32::zero(), Mf32::zero()),
+        tex_density: Mf32::zero(),
+        tangent: MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0)),
+        bitangent: MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0)),
+    };
+    let mut rng = Rng::with_seed(2, 3, 5);
+
+    // Only the distance to the light changes, so the light that arrives must
+    // be proportional to 1/r^2.
+    let mut light_at = |height: f32| {
+        let floor = quad(SVector3::new(-10.0, 0.0, -10.0),
+                         SVector3::new(0.0, 0.0, 20.0),
+                         SVector3::new(20.0, 0.0, 0.0),
+                         SMaterial::white());
+        let mut scene = Scene::from_meshes(&[floor]);
+        scene.add_light(Light::point(SVector3::new(0.0, height, 0.0), SVector3::new(1.0, 1.0, 1.0)));
+        let light = sample_analytic_lights(isect.material, &scene, &ray, &isect, &mut rng,
+                                           false, MChannels::Rgb);
+        light.x.0
+    };
+
+    let l1 = light_at(1.0);
+    let l2 = light_at(2.0);
+    let l4 = light_at(4.0);
+    assert!(l1 > 0.0);
+    assert!((l1 / l2 - 4.0).abs() < 1e-2, "1/r^2 falloff violated: {} vs {}", l1, l2);
+    assert!((l1 / l4 - 16.0).abs() < 4e-2, "1/r^2 falloff violated: {} vs {}", l1, l4);
+}
+
+#[test]
+fn directional_light_shines_through_window() {
+    use lights::Light;
+    use vector3::SVector3;
+    use wavefront::quad;
+
+    let ray = MRay {
+        origin: MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0)),
+        direction: MVector3::broadcast(SVector3::new(0.0, -1.0, 0.0)),
+        active: Mf32::zero(),
+    };
+    let isect = MIntersection {
+        position: MVector3::zero(),
+        normal: MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0)),
+        distance: Mf32::one(),
+        material: MMaterial::broadcast_material(SMaterial::white()),
+        tex_coords: (Mf32::zero(), Mf32::zero()),
+        tex_density: Mf32::zero(),
+        tangent: MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0)),
+        bitangent: MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0)),
+    };
+    let mut rng = Rng::with_seed(2, 3, 5);
+
+    // A window hangs above the floor, and the light shines straight down
+    // through it. Windows let the sky in, they must not cast shadows.
+    let floor = quad(SVector3::new(-10.0, 0.0, -10.0),
+                     SVector3::new(0.0, 0.0, 20.0),
+                     SVector3::new(20.0, 0.0, 0.0),
+                     SMaterial::white());
+    let window = quad(SVector3::new(-1.0, 2.0, -1.0),
+                      SVector3::new(0.0, 0.0, 2.0),
+                      SVector3::new(2.0, 0.0, 0.0),
+                      SMaterial::sky());
+    let mut scene = Scene::from_meshes(&[floor, window]);
+    scene.add_light(Light::directional(SVector3::new(0.0, -1.0, 0.0), SVector3::new(1.0, 1.0, 1.0)));
+    let light = sample_analytic_lights(isect.material, &scene, &ray, &isect, &mut rng,
+                                       false, MChannels::Rgb);
+
+    assert!(light.x.0 > 0.0, "the window blocked the light");
+}
+
+#[test]
+fn with_color_preserves_dark_colors() {
+    let material = MMaterial::broadcast_material(SMaterial::white());
+    for &c in &[0.005, 0.05, 0.5, 1.0] {
+        let color = MVector3::new(Mf32::broadcast(c), Mf32::broadcast(c * 0.5), Mf32::zero());
+        let decoded = material.with_color(color).get_color();
+        assert!((decoded.x.0 - c).abs() < 0.05 * c, "{} decoded as {}", c, decoded.x.0);
+        assert!((decoded.y.0 - c * 0.5).abs() < 0.025 * c, "{} decoded as {}", c * 0.5, decoded.y.0);
+        assert_eq!(0.0, decoded.z.0);
+    }
+}
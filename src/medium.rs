@@ -0,0 +1,658 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements participating media.
+//!
+//! Without media, light travels unhindered between surfaces. A medium absorbs
+//! some of the light that passes through it, and scatters some of it into
+//! other directions. There are two kinds of media in a scene: fog that fills
+//! the bounding box of the scene, and volumes inside a closed mesh. Where they
+//! overlap, their coefficients add up.
+//!
+//! The coefficients are the same for all color channels. That keeps the
+//! transmittance a scalar, and it means that the distance to the next
+//! scattering event can be sampled proportional to the transmittance exactly,
+//! so the transmittance and the probability density cancel. Sampling is done
+//! with delta tracking, against a majorant that is the sum of the extinction
+//! of the media that a ray passes through. For homogeneous media every
+//! tentative collision is real, except where the media do not overlap.
+//!
+//! Volumes can also have a density that varies in space, given by a voxel
+//! grid. For those the majorant is the extinction at the largest density in
+//! the grid, and at a tentative collision the density decides whether the
+//! collision is real. Their transmittance has no closed form, it is estimated
+//! with ratio tracking instead.
+//!
+//! The direction of scattered light follows the Henyey-Greenstein phase
+//! function.
+
+use aabb::Aabb;
+
+use material::MisHeuristic;
+use quaternion::{MQuaternion, SQuaternion, rotate};
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::{FAR_AWAY, Scene};
+use simd::{Mask, Mf32};
+use spectral::MChannels;
+use std::f32::consts::PI;
+use triangle::Triangle;
+use vector3::{MVector3, SVector3};
+use voxel::VoxelGrid;
+use wavefront::Mesh;
+
+/// A homogeneous medium.
+#[derive(Copy, Clone, Debug)]
+pub struct Medium {
+    /// The fraction of light absorbed per unit of distance.
+    pub absorption: f32,
+
+    /// The fraction of light scattered into other directions per unit of
+    /// distance.
+    pub scattering: f32,
+
+    /// The Henyey-Greenstein asymmetry parameter g, between -1 and 1. Positive
+    /// values scatter light mostly forward, negative values mostly backward,
+    /// and 0 scatters uniformly in all directions.
+    pub anisotropy: f32,
+}
+
+/// A medium inside a closed mesh.
+///
+/// The boundary is intersected triangle by triangle, without a BVH, so it
+/// should be a simple shape. Only the first interval along a ray that lies
+/// inside the mesh is taken into account, this is exact for convex meshes.
+pub struct Volume {
+    boundary: Vec<Triangle>,
+    medium: Medium,
+}
+
+/// A medium with a density given by a voxel grid.
+///
+/// The coefficients of the medium are those at density 1. The grid fills the
+/// unit cube in its local space, which is placed in the scene by scaling it,
+/// then rotating it, and then translating it.
+pub struct GridVolume {
+    grid: VoxelGrid,
+    medium: Medium,
+    translation: SVector3,
+    rotation: SQuaternion,
+    scale: SVector3,
+}
+
+/// The part of 8 rays that lies inside a medium, with the coefficients of the
+/// medium. If a ray does not pass through the medium, the exit distance is not
+/// larger than the entry distance.
+///
+/// For a heterogeneous medium the coefficients are those at the largest
+/// density, and the density lookup gives the actual density relative to it.
+pub struct MMediumInterval<'a> {
+    pub enter: Mf32,
+    pub exit: Mf32,
+    pub extinction: Mf32,
+    pub scattering: Mf32,
+    pub anisotropy: Mf32,
+    pub density: Option<MDensityLookup<'a>>,
+}
+
+/// The rays in the local space of a voxel grid, to look up the density along
+/// them.
+pub struct MDensityLookup<'a> {
+    grid: &'a VoxelGrid,
+    origin: MVector3,
+    direction: MVector3,
+    max_density_recip: f32,
+}
+
+/// The result of sampling a scattering event along 8 rays.
+pub struct MMediumSample {
+    /// The sign bit is 1 for rays that scattered before the given distance.
+    pub scattered: Mask,
+
+    /// The distance along the ray at which the ray scattered.
+    pub distance: Mf32,
+
+    /// The ratio of scattering to extinction at the scattering event: the
+    /// factor to multiply the path throughput by.
+    pub albedo: Mf32,
+
+    /// The asymmetry parameter of the phase function at the scattering event.
+    pub anisotropy: Mf32,
+}
+
+impl Medium {
+    /// Constructs an isotropic medium with the given coefficients.
+    pub fn new(absorption: f32, scattering: f32) -> Medium {
+        assert!(absorption >= 0.0 && scattering >= 0.0, "coefficients must not be negative");
+        Medium {
+            absorption: absorption,
+            scattering: scattering,
+            anisotropy: 0.0,
+        }
+    }
+
+    pub fn with_anisotropy(self, anisotropy: f32) -> Medium {
+        assert!(anisotropy > -1.0 && anisotropy < 1.0, "anisotropy must be between -1 and 1");
+        Medium { anisotropy: anisotropy, ..self }
+    }
+
+    /// Returns the fraction of light that is absorbed or scattered per unit of
+    /// distance.
+    pub fn extinction(&self) -> f32 {
+        self.absorption + self.scattering
+    }
+
+    /// Returns the interval with the coefficients of this medium. The medium
+    /// is homogeneous, so the interval does not borrow anything.
+    pub fn interval<'a>(&self, enter: Mf32, exit: Mf32) -> MMediumInterval<'a> {
+        MMediumInterval {
+            enter: enter,
+            exit: exit,
+            extinction: Mf32::broadcast(self.extinction()),
+            scattering: Mf32::broadcast(self.scattering),
+            anisotropy: Mf32::broadcast(self.anisotropy),
+            density: None,
+        }
+    }
+}
+
+impl Volume {
+    /// Fills the closed mesh with the medium. The materials of the mesh are
+    /// not used, and the triangles must face outward.
+    pub fn new(mesh: &Mesh, medium: Medium) -> Volume {
+        let boundary = mesh.triangles.iter().map(|tri| {
+            let (i0, i1, i2) = tri.vertices;
+            Triangle::new(mesh.vertices[i0 as usize],
+                          mesh.vertices[i1 as usize],
+                          mesh.vertices[i2 as usize],
+                          tri.material)
+        }).collect();
+
+        Volume {
+            boundary: boundary,
+            medium: medium,
+        }
+    }
+
+    fn intersect_boundary(&self, ray: &MRay, far_away: f32) -> MIntersection {
+        let mut isect = MIntersection::with_max_distance(far_away);
+        for triangle in &self.boundary {
+            isect = triangle.intersect(ray, isect);
+        }
+        isect
+    }
+
+    /// Returns the part of the rays that lies inside the volume.
+    pub fn interval(&self, ray: &MRay, far_away: f32) -> MMediumInterval {
+        let far = Mf32::broadcast(far_away);
+        let first = self.intersect_boundary(ray, far_away);
+
+        // The boundary faces outward, so a ray that hits its front side enters
+        // the volume there, and a ray that hits its back side started inside.
+        // The sign bit of the dot product is 1 for entering rays.
+        let hit = first.distance - far;
+        let cos_theta = first.normal.dot(ray.direction);
+        let entering = (hit & cos_theta).pick(Mask::zero(), ray.active);
+        let inside = (hit & (cos_theta ^ Mask::ones())).pick(Mask::zero(), ray.active);
+
+        // Rays that entered leave at the next intersection with the boundary.
+        let mut exit = far.pick(first.distance, inside);
+        if !entering.all_sign_bits_positive() {
+            let behind = MRay {
+                origin: ray.direction.mul_add(Mf32::epsilon(), first.position),
+                direction: ray.direction,
+                active: entering ^ Mask::ones(),
+            };
+            let second = self.intersect_boundary(&behind, far_away);
+            exit = exit.pick(first.distance + second.distance, entering);
+        }
+
+        let enter = far.pick(first.distance, entering).pick(Mf32::zero(), inside);
+        self.medium.interval(enter, exit)
+    }
+}
+
+impl GridVolume {
+    /// Fills the unit cube with the medium, scaled by the densities of the
+    /// grid.
+    pub fn new(grid: VoxelGrid, medium: Medium) -> GridVolume {
+        GridVolume {
+            grid: grid,
+            medium: medium,
+            translation: SVector3::zero(),
+            rotation: SQuaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: SVector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Places the grid in the scene. The scale is the size of the grid along
+    /// its local axes, and the rotation must be a unit quaternion.
+    pub fn with_transform(self,
+                          translation: SVector3,
+                          rotation: SQuaternion,
+                          scale: SVector3)
+                          -> GridVolume {
+        assert!(scale.x > 0.0 && scale.y > 0.0 && scale.z > 0.0, "scale must be positive");
+        GridVolume {
+            translation: translation,
+            rotation: rotation,
+            scale: scale,
+            ..self
+        }
+    }
+
+    /// Transforms the rays into the local space of the grid. The directions
+    /// are not normalized there, so distances along the rays stay the same.
+    fn to_local(&self, ray: &MRay) -> (MVector3, MVector3) {
+        let q = self.rotation;
+        let inverse = MQuaternion::broadcast(SQuaternion::new(q.a, -q.b, -q.c, -q.d));
+        let inv_scale = MVector3::broadcast(SVector3::new(1.0 / self.scale.x,
+                                                          1.0 / self.scale.y,
+                                                          1.0 / self.scale.z));
+        let origin = ray.origin - MVector3::broadcast(self.translation);
+        let origin = rotate(&origin, &inverse).mul_coords(inv_scale);
+        let direction = rotate(&ray.direction, &inverse).mul_coords(inv_scale);
+        (origin, direction)
+    }
+
+    /// Returns the part of the rays that lies inside the bounds of the grid.
+    pub fn interval(&self, ray: &MRay) -> MMediumInterval {
+        let (origin, direction) = self.to_local(ray);
+        let local_ray = MRay {
+            origin: origin,
+            direction: direction,
+            active: ray.active,
+        };
+        let bounds = Aabb::new(SVector3::zero(), SVector3::new(1.0, 1.0, 1.0));
+        let (enter, exit) = bounds.intersect(&local_ray).distances();
+
+        let max_density = self.grid.max_density();
+        let medium = Medium {
+            absorption: self.medium.absorption * max_density,
+            scattering: self.medium.scattering * max_density,
+            anisotropy: self.medium.anisotropy,
+        };
+        let mut interval = medium.interval(enter.max(Mf32::zero()), exit);
+        interval.density = Some(MDensityLookup {
+            grid: &self.grid,
+            origin: origin,
+            direction: direction,
+            max_density_recip: 1.0 / max_density.max(1.0e-20),
+        });
+        interval
+    }
+}
+
+impl<'a> MDensityLookup<'a> {
+    /// Returns the density at the given distances along the rays, relative to
+    /// the largest density in the grid.
+    fn relative_density(&self, t: Mf32) -> Mf32 {
+        let p = self.direction.mul_add(t, self.origin);
+        let density = Mf32::generate(|i| self.grid.density_at(p.extract(i)));
+        density * Mf32::broadcast(self.max_density_recip)
+    }
+}
+
+impl<'a> MMediumInterval<'a> {
+    /// Returns the density at the given distances along the rays, relative to
+    /// the density that the coefficients are for.
+    fn relative_density(&self, t: Mf32) -> Mf32 {
+        match self.density {
+            Some(ref lookup) => lookup.relative_density(t),
+            None => Mf32::one(),
+        }
+    }
+}
+
+/// Estimates the transmittance through a heterogeneous medium with ratio
+/// tracking, up to the given distances along the rays.
+///
+/// Like delta tracking it takes tentative steps against the majorant, but
+/// rather than stopping at the first real collision, it multiplies the
+/// estimate by the probability of a null collision at every step. The result
+/// is unbiased, and unlike the all-or-nothing estimate of delta tracking it is
+/// smooth, which matters for shadow rays.
+fn ratio_tracking(interval: &MMediumInterval,
+                  distance: Mf32,
+                  active: Mask,
+                  rng: &mut Rng)
+                  -> Mf32 {
+    let end = interval.exit.min(distance);
+    let majorant_recip = interval.extinction.max(Mf32::broadcast(1.0e-20)).recip_precise();
+
+    let mut t = interval.enter;
+    let mut ratio = Mf32::one();
+    let mut done = active | t.geq(end) | Mf32::zero().geq(interval.extinction);
+
+    while !done.all_sign_bits_negative() {
+        let u = rng.sample_unit();
+        let step = (Mf32::one() - u).map(|x| -x.ln()) * majorant_recip;
+        t = (t + step).pick(t, done);
+        done = done | t.geq(end);
+
+        let density = interval.relative_density(t);
+        ratio = (ratio * (Mf32::one() - density)).pick(ratio, done);
+
+        // Where the density reaches the majorant, nothing passes any more.
+        done = done | Mf32::zero().geq(ratio);
+    }
+
+    ratio.max(Mf32::zero())
+}
+
+/// Returns the fraction of light that passes through the media along the rays
+/// up to the given distance. For homogeneous media this is exact, through
+/// heterogeneous media it is an unbiased estimate.
+pub fn transmittance(intervals: &[MMediumInterval],
+                     distance: Mf32,
+                     active: Mask,
+                     rng: &mut Rng)
+                     -> Mf32 {
+    let mut optical_depth = Mf32::zero();
+    let mut ratio = Mf32::one();
+    for interval in intervals {
+        if interval.density.is_some() {
+            ratio = ratio * ratio_tracking(interval, distance, active, rng);
+        } else {
+            let length = (interval.exit.min(distance) - interval.enter).max(Mf32::zero());
+            optical_depth = interval.extinction.mul_add(length, optical_depth);
+        }
+    }
+    ratio * optical_depth.map(|x| (-x).exp())
+}
+
+/// Samples the distance to the next scattering event along the rays, with a
+/// probability proportional to the transmittance. Rays that reach the given
+/// distance without scattering pass on to the surface there, their throughput
+/// does not change.
+pub fn sample_distance(intervals: &[MMediumInterval],
+                       distance: Mf32,
+                       active: Mask,
+                       rng: &mut Rng)
+                       -> MMediumSample {
+    // Only the media that a ray actually passes through contribute to its
+    // majorant. There is no need to step through vacuum before the first
+    // medium, or after the last one.
+    let mut majorant = Mf32::zero();
+    let mut begin = distance;
+    let mut end = Mf32::zero();
+    for interval in intervals {
+        let exit = interval.exit.min(distance);
+        let passes = interval.enter - exit;
+        majorant = majorant + Mf32::zero().pick(interval.extinction, passes);
+        begin = begin.pick(begin.min(interval.enter.max(Mf32::zero())), passes);
+        end = end.pick(end.max(exit), passes);
+    }
+    let majorant_recip = majorant.max(Mf32::broadcast(1.0e-20)).recip_precise();
+
+    let mut t = begin;
+    let mut done = active | Mf32::zero().geq(majorant);
+    let mut sample = MMediumSample {
+        scattered: Mask::zero(),
+        distance: distance,
+        albedo: Mf32::one(),
+        anisotropy: Mf32::zero(),
+    };
+
+    while !done.all_sign_bits_negative() {
+        let u_step = rng.sample_unit();
+        let u_accept = rng.sample_unit();
+        let step = (Mf32::one() - u_step).map(|x| -x.ln()) * majorant_recip;
+        t = (t + step).pick(t, done);
+        done = done | t.geq(end);
+
+        let mut extinction = Mf32::zero();
+        let mut scattering = Mf32::zero();
+        let mut weighted_g = Mf32::zero();
+        for interval in intervals {
+            let inside = t.geq(interval.enter) & (t.geq(interval.exit) ^ Mask::ones());
+            let density = interval.relative_density(t);
+            let interval_scattering = interval.scattering * density;
+            extinction = extinction + Mf32::zero().pick(interval.extinction * density, inside);
+            scattering = scattering + Mf32::zero().pick(interval_scattering, inside);
+            weighted_g = weighted_g +
+                         Mf32::zero().pick(interval_scattering * interval.anisotropy, inside);
+        }
+
+        // The tentative collision is real with probability extinction over
+        // majorant, for heterogeneous media this is where the density comes
+        // in. Where media overlap, the phase function is approximated by one
+        // with the average asymmetry.
+        let collide = (u_accept * majorant - extinction).pick(Mask::zero(), done);
+        let albedo = scattering.div(extinction.max(Mf32::broadcast(1.0e-20)));
+        let g = weighted_g.div(scattering.max(Mf32::broadcast(1.0e-20)));
+        sample.scattered = sample.scattered | collide;
+        sample.distance = sample.distance.pick(t, collide);
+        sample.albedo = sample.albedo.pick(albedo, collide);
+        sample.anisotropy = sample.anisotropy.pick(g, collide);
+        done = done | collide;
+    }
+
+    sample
+}
+
+/// Evaluates the Henyey-Greenstein phase function, for the cosine of the angle
+/// between the direction of the incoming ray and the scattered direction.
+pub fn phase_hg(cos_theta: Mf32, g: Mf32) -> Mf32 {
+    let g2 = g * g;
+    let denom = (Mf32::one() + g2) - (g + g) * cos_theta;
+    let norm = Mf32::broadcast(0.25 / PI);
+    ((Mf32::one() - g2) * norm).div(denom * denom.sqrt())
+}
+
+/// Samples scattered directions proportional to the Henyey-Greenstein phase
+/// function, for rays travelling in the given directions. The phase function
+/// is normalized, so its value is also the probability density.
+pub fn sample_hg(direction: MVector3, g: Mf32, rng: &mut Rng) -> MVector3 {
+    let u = rng.sample_unit();
+    let phi = rng.sample_angle();
+
+    // Invert the cumulative distribution of the cosine. For g close to zero
+    // the inversion is numerically unstable, but the distribution is uniform
+    // there anyway.
+    let g2 = g * g;
+    let s = (Mf32::one() - g2).div((Mf32::one() - g) + (g + g) * u);
+    let cos_hg = ((Mf32::one() + g2) - s * s).div(g + g);
+    let cos_uniform = Mf32::one() - (u + u);
+    let isotropic = Mf32::broadcast(1.0e-3).geq(g.abs());
+    let cos_theta = cos_hg.pick(cos_uniform, isotropic).max(-Mf32::one()).min(Mf32::one());
+    let sin_theta = (Mf32::one() - cos_theta * cos_theta).max(Mf32::zero()).sqrt();
+
+    let local = MVector3::new(phi.sin() * sin_theta, phi.cos() * sin_theta, cos_theta);
+    local.rotate_hemisphere(direction)
+}
+
+/// Samples light from the sun directly at scattering events.
+///
+/// This is the counterpart of `sample_sun_light()` for a point in a medium,
+/// with the phase function in place of the BRDF. Where the sun shines through
+/// a window into fog, this is what makes the light shafts visible. The light
+/// is weighted for multiple importance sampling with phase function sampling,
+/// see `weighted_emission_in_medium()`, and it must still be multiplied by the
+/// path throughput. The sign bit of `active` is 1 for rays that did not
+/// scatter.
+pub fn sample_sun_in_medium(scene: &Scene,
+                            ray: &MRay,
+                            position: MVector3,
+                            g: Mf32,
+                            active: Mask,
+                            rng: &mut Rng,
+                            heuristic: MisHeuristic,
+                            channels: MChannels)
+                            -> MVector3 {
+    if !scene.has_sun() {
+        return MVector3::zero();
+    }
+
+    let (direction, pd_sun) = scene.sample_sun(rng);
+    let shadow_ray = MRay {
+        origin: position,
+        direction: direction,
+        active: active,
+    };
+
+    let phase = phase_hg(ray.direction.dot(direction), g);
+    let weight = heuristic.weight(pd_sun, phase);
+    let radiance = channels.uplift(scene.sun_radiance(direction));
+    let light = radiance * (weight * phase * pd_sun.recip_fast());
+
+    debug_assert!(light.all_finite());
+
+    let far = Mf32::broadcast(FAR_AWAY);
+    let light = light * scene.transmittance(&shadow_ray, far, rng);
+    let occluded = scene.is_occluded_by_opaque(&shadow_ray, far);
+    light.pick(MVector3::zero(), active | occluded)
+}
+
+/// Gathers light from the analytic light sources at scattering events, like
+/// `sample_analytic_lights()` does for surfaces.
+pub fn sample_analytic_lights_in_medium(scene: &Scene,
+                                        ray: &MRay,
+                                        position: MVector3,
+                                        g: Mf32,
+                                        active: Mask,
+                                        rng: &mut Rng,
+                                        channels: MChannels)
+                                        -> MVector3 {
+    let mut light = MVector3::zero();
+
+    for source in scene.lights() {
+        let illum = source.illuminate(position);
+        let shadow_ray = MRay {
+            origin: position,
+            direction: illum.direction,
+            active: active,
+        };
+
+        let phase = phase_hg(ray.direction.dot(illum.direction), g);
+        let transmitted = scene.transmittance(&shadow_ray, illum.distance, rng);
+        let contribution = channels.uplift(illum.irradiance) * (phase * transmitted);
+
+        let occluded = scene.is_occluded_by_opaque(&shadow_ray, illum.distance);
+        light = light + contribution.pick(MVector3::zero(), active | occluded);
+    }
+
+    light
+}
+
+/// Returns the light that arrives at a scattering event along a ray sampled
+/// from the phase function, for a ray that hit an emitter or escaped. The
+/// direction is the direction of the ray that scattered.
+///
+/// At scattering events only the sun is sampled directly, so only the sun is
+/// weighted for multiple importance sampling. Light from the sky through
+/// windows can only be found by phase function sampling.
+pub fn weighted_emission_in_medium(scene: &Scene,
+                                   ray: &MRay,
+                                   direction: MVector3,
+                                   g: Mf32,
+                                   heuristic: MisHeuristic,
+                                   channels: MChannels)
+                                   -> MVector3 {
+    let sky = channels.uplift(scene.sky_radiance(ray.direction));
+
+    if !scene.has_sun() {
+        return sky;
+    }
+
+    let pd_phase = phase_hg(direction.dot(ray.direction), g);
+    let pd_sun = scene.pd_sun(ray.direction);
+    let sun = channels.uplift(scene.sun_radiance(ray.direction));
+    sky + sun * heuristic.weight(pd_phase, pd_sun)
+}
+
+#[test]
+fn phase_hg_is_normalized() {
+    for &g in &[-0.7, 0.0, 0.3, 0.8] {
+        // Integrate over the sphere: 2 pi times the integral over the cosine.
+        let n = 10000;
+        let mut sum = 0.0;
+        for i in 0..n {
+            let cos_theta = -1.0 + (i as f32 + 0.5) * 2.0 / n as f32;
+            let p = phase_hg(Mf32::broadcast(cos_theta), Mf32::broadcast(g)).0;
+            sum += p * 2.0 * PI * 2.0 / n as f32;
+        }
+        assert!((sum - 1.0).abs() < 1e-2, "phase function for g = {} integrates to {}", g, sum);
+    }
+}
+
+#[test]
+fn sample_hg_has_mean_cosine_g() {
+    let mut rng = Rng::with_seed(2, 3, 5);
+    let direction = MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero());
+    for &g in &[-0.5, 0.0, 0.6] {
+        let mut sum = 0.0;
+        let n = 4096;
+        for _ in 0..n {
+            let d = sample_hg(direction, Mf32::broadcast(g), &mut rng);
+            for i in 0..8 {
+                sum += d.y.get_coord(i);
+            }
+        }
+        let mean = sum / (n * 8) as f32;
+        assert!((mean - g).abs() < 2e-2, "mean cosine for g = {} is {}", g, mean);
+    }
+}
+
+#[test]
+fn sample_distance_matches_transmittance() {
+    let mut rng = Rng::with_seed(7, 11, 13);
+    let medium = Medium::new(0.2, 0.3);
+    let intervals = [medium.interval(Mf32::broadcast(1.0), Mf32::broadcast(3.0))];
+    let distance = Mf32::broadcast(10.0);
+
+    // The medium is 2 units thick, so the fraction of rays that pass is
+    // exp(-2 * 0.5).
+    let transmitted = transmittance(&intervals, distance, Mask::zero(), &mut rng).0;
+    assert!((transmitted - (-1.0f32).exp()).abs() < 1e-5);
+
+    let mut passed = 0;
+    let n = 4096;
+    for _ in 0..n {
+        let sample = sample_distance(&intervals, distance, Mask::zero(), &mut rng);
+        for i in 0..8 {
+            if !sample.scattered.get_coord(i).is_sign_negative() {
+                passed += 1;
+            } else {
+                let t = sample.distance.get_coord(i);
+                assert!(t >= 1.0 && t < 3.0);
+                assert!((sample.albedo.get_coord(i) - 0.6).abs() < 1e-5);
+            }
+        }
+    }
+    let fraction = passed as f32 / (n * 8) as f32;
+    assert!((fraction - transmitted).abs() < 2e-2, "{} of the rays passed", fraction);
+}
+
+#[test]
+fn ratio_tracking_matches_homogeneous_grid() {
+    let mut rng = Rng::with_seed(3, 5, 7);
+    let grid = VoxelGrid::new(2, 2, 2, vec![0.5; 8]);
+    let volume = GridVolume::new(grid, Medium::new(0.5, 1.5))
+        .with_transform(SVector3::new(0.0, -1.0, -1.0),
+                        SQuaternion::new(1.0, 0.0, 0.0, 0.0),
+                        SVector3::new(2.0, 2.0, 2.0));
+    let ray = MRay {
+        origin: MVector3::broadcast(SVector3::new(-1.0, 0.0, 0.0)),
+        direction: MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0)),
+        active: Mask::zero(),
+    };
+    let intervals = [volume.interval(&ray)];
+
+    // The grid is 2 units thick with an extinction of 2 * 0.5 everywhere.
+    let expected = (-2.0f32).exp();
+    let mut sum = 0.0;
+    let n = 4096;
+    for _ in 0..n {
+        let t = transmittance(&intervals, Mf32::broadcast(10.0), Mask::zero(), &mut rng);
+        for i in 0..8 {
+            sum += t.get_coord(i);
+        }
+    }
+    let mean = sum / (n * 8) as f32;
+    assert!((mean - expected).abs() < 1e-2, "mean transmittance is {}", mean);
+}
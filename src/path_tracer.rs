@@ -0,0 +1,351 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements a unidirectional path tracer.
+//!
+//! Paths start at the camera and bounce around the scene until they hit a
+//! light source, or until Russian roulette terminates them. At every bounce,
+//! light sources are also sampled directly, and the two strategies are
+//! combined with multiple importance sampling.
+
+use integrator::{Integrator, MPixelData, Settings};
+use layered::pick_layers;
+use material::{MMaterial, SMaterial, continue_path, sample_analytic_lights};
+use material::{sample_direct_light, sample_environment_light, sample_sun_light, weighted_emission};
+use medium::{sample_analytic_lights_in_medium, sample_hg, sample_sun_in_medium};
+use medium::weighted_emission_in_medium;
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::Scene;
+use simd::{Mask, Mf32, Mi32};
+use spectral::{MChannels, SpectralTables};
+use subsurface::random_walk;
+use vector3::MVector3;
+
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn name(&self) -> &'static str {
+        "path tracer"
+    }
+
+    fn integrate(&self, scene: &Scene, mut ray: MRay, settings: &Settings, rng: &mut Rng) -> MPixelData {
+        let mut color = MVector3::zero();
+        let mut throughput = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+        let mut texture_index = Mi32::zero();
+        let mut texture_coords = (Mf32::zero(), Mf32::zero());
+        let mut texture_footprint = Mf32::zero();
+        let mut fresnel = Mf32::zero();
+
+        // Track a cone around the ray to estimate the area that a path covers,
+        // so texture lookups can pick an appropriate mip level. This is a cheap
+        // isotropic form of ray differentials. The cone starts as wide as a
+        // pixel, and every bounce widens it by the spread of the BRDF lobe.
+        let mut cone_width = Mf32::zero();
+        let mut cone_spread = Mf32::broadcast(scene.camera.pixel_spread(settings.width));
+
+        // The surface that the current ray was sampled from. It is only used
+        // after the first bounce, the initial value does not matter.
+        let mut prev_isect = MIntersection::with_max_distance(0.0);
+
+        // The sign bit is 1 for paths that have not bounced off a surface yet.
+        // Paths can pass through transparent surfaces, and then they are still
+        // camera rays after the first iteration.
+        let mut camera_path = Mask::ones();
+
+        // The sign bit is 1 for paths whose current ray was sampled from the
+        // phase function at a scattering event in a medium, rather than from
+        // the BRDF at `prev_isect`. For those, the direction of the ray that
+        // scattered and the asymmetry of the phase function are kept.
+        let mut prev_scattered = Mask::zero();
+        let mut prev_direction = MVector3::zero();
+        let mut prev_anisotropy = Mf32::zero();
+
+        // In spectral mode every path carries its own wavelengths. The GPU
+        // multiplies by texture colors in RGB, so then the textures of the
+        // first bounce are applied here.
+        let channels = match settings.spectral {
+            Some(ref tables) => MChannels::Spectral(tables, SpectralTables::sample_wavelengths(rng)),
+            None => MChannels::Rgb,
+        };
+        let gpu_textures = settings.gpu_textures && settings.spectral.is_none();
+
+        // The ray after the last bounce is traced too, but only to collect
+        // the emission that it hits. Direct light at the last vertex is
+        // weighted for multiple importance sampling against that ray, so
+        // without it the last vertex would lose part of its light.
+        for i in 0..settings.max_bounces + 1 {
+            // Stop when every path was terminated by Russian roulette.
+            if ray.active.all_sign_bits_negative() {
+                break;
+            }
+
+            let mut isect = scene.intersect_nearest(&ray);
+
+            // Do not allow NaNs to creep in.
+            debug_assert!(ray.direction.all_finite(), "infinite ray direction at iteration {}", i);
+            debug_assert!(isect.position.all_finite(), "infinite intersection at iteration {}", i);
+            debug_assert!(isect.distance.all_finite(), "infinite distance at iteration {}", i);
+
+            // The normal follows from the winding order of the triangle, but
+            // the ray can hit either side. Shade the side that the ray hit,
+            // otherwise the bounce would go into the surface.
+            isect.face_towards(&ray);
+
+            // In fog or another medium, a ray might scatter before it reaches
+            // the surface. Such rays do not interact with the surface at all.
+            let medium = scene.sample_medium(&ray, isect.distance, rng);
+            let scattered = medium.scattered;
+
+            // Gather light from emitters that the ray hit. After the first
+            // bounce, these could also have been found by direct sampling, so
+            // weigh them for multiple importance sampling. Skip this if no ray
+            // hit an emitter, computing the weight is not cheap.
+            if !isect.material.all_sign_bits_positive() {
+                let sky = scene.sky_radiance(ray.direction);
+                let unweighted = sky + scene.sun_radiance(ray.direction);
+                let emission = if i == 0 {
+                    channels.uplift(unweighted)
+                } else {
+                    let emission = weighted_emission(scene,
+                                                     &prev_isect,
+                                                     &ray,
+                                                     isect.distance,
+                                                     settings.mis_heuristic,
+                                                     channels)
+                        .pick(channels.uplift(unweighted), camera_path);
+                    if prev_scattered.all_sign_bits_positive() {
+                        emission
+                    } else {
+                        let in_medium = weighted_emission_in_medium(scene,
+                                                                    &ray,
+                                                                    prev_direction,
+                                                                    prev_anisotropy,
+                                                                    settings.mis_heuristic,
+                                                                    channels);
+                        emission.pick(in_medium, prev_scattered)
+                    }
+                };
+                let emission = emission.mul_coords(throughput);
+                let emission = emission.pick(MVector3::zero(), ray.active | scattered);
+                color = color + MVector3::zero().pick(emission, isect.material);
+            }
+
+            // Stop when every ray hit a light source or was terminated. Rays
+            // that scattered before reaching the light go on.
+            let surface_emissive = isect.material.pick(Mask::zero(), scattered);
+            if (ray.active | surface_emissive).all_sign_bits_negative() {
+                break;
+            }
+            if i == settings.max_bounces {
+                break;
+            }
+
+            // A path sees only one layer of a layered material, from here on
+            // the surface behaves as that layer. Only lanes that picked a
+            // translucent base still have the layered material.
+            let layers = pick_layers(scene, &ray, &isect, ray.active | scattered, rng, channels);
+            isect.material = layers.material;
+            throughput = throughput.mul_coords(layers.weight);
+
+            // Grow the cone up to the intersection, and project its width onto
+            // the surface. At grazing angles the footprint is stretched, but
+            // not indefinitely. Textures convert it into texture space, except
+            // for procedural textures in world space.
+            let segment = isect.distance.pick(medium.distance, scattered);
+            cone_width = cone_spread.mul_add(segment, cone_width);
+            let cos_theta = ray.direction.dot(isect.normal).abs().max(Mf32::broadcast(0.05));
+            let footprint = cone_width * cos_theta.recip_fast();
+
+            // Perturb the shading normal by the normal map, if there is one.
+            // Everything that follows, sampling the BRDF and evaluating it,
+            // sees the shading normal.
+            isect.normal = scene.apply_normal_maps(&ray, &isect, footprint);
+
+            // Look up the surface color in the texture, if there is one. For
+            // the first bounce image textures are normally applied on the GPU,
+            // but procedural textures are always evaluated here.
+            let procedural_only = i == 0 && gpu_textures;
+            let material = scene.apply_textures(&isect, footprint, procedural_only);
+
+            // Textures can drive other material parameters too. Surfaces with
+            // an emission map add their own light, and where a surface is
+            // transparent the path continues straight through it, without
+            // gathering light or changing the throughput.
+            let ignore_texture = i == 0 && gpu_textures;
+            let (material, surface_emission, transparent) =
+                scene.apply_parameter_maps(material, &isect, footprint, rng, ignore_texture);
+            let transparent = transparent.pick(Mask::zero(), scattered);
+            let no_surface = transparent | scattered;
+            let surface_emission = surface_emission.pick(MVector3::zero(), ray.active | no_surface);
+            color = color + channels.uplift(surface_emission).mul_coords(throughput);
+
+            // In spectral mode the color of the surface is replaced by its
+            // reflectance at the wavelengths of the path. Emissive and
+            // layered materials do not store a color.
+            let material = match channels {
+                MChannels::Rgb => material,
+                MChannels::Spectral(..) => {
+                    let keep = material | material.is_layered();
+                    material.with_color(channels.uplift(material.get_color())).pick(material, keep)
+                }
+            };
+
+            // Light that enters a translucent surface scatters below it, and
+            // leaves the surface somewhere else. From there the path goes on
+            // as if it bounced off a white diffuse surface at the exit, the
+            // color of the material is in the weight of the walk.
+            let subsurface = layers.translucent.pick(Mask::zero(), ray.active | no_surface);
+            let material = if subsurface.all_sign_bits_positive() {
+                material
+            } else {
+                let in_surface = subsurface ^ Mask::ones();
+                let walk = random_walk(scene, &isect, material, in_surface, rng, channels);
+                let white = MMaterial::broadcast_material(SMaterial::white());
+                isect = isect.pick(&walk.exit, subsurface);
+                isect.material = isect.material.pick(white, subsurface);
+                throughput = throughput.pick(throughput.mul_coords(walk.weight), subsurface);
+                ray.active = ray.active | (walk.absorbed & subsurface);
+                material.pick(white, subsurface)
+            };
+
+            // Sample light sources directly. For the first bounce, the Fresnel
+            // term and texture should not contribute to the color modulation
+            // because that is handled on the GPU.
+            let direct = sample_direct_light(material,
+                                             scene,
+                                             &ray,
+                                             &isect,
+                                             rng,
+                                             settings.mis_heuristic,
+                                             i == 0,
+                                             channels);
+            let environment = sample_environment_light(material,
+                                                       scene,
+                                                       &ray,
+                                                       &isect,
+                                                       rng,
+                                                       settings.mis_heuristic,
+                                                       i == 0,
+                                                       channels);
+            let sun = sample_sun_light(material,
+                                       scene,
+                                       &ray,
+                                       &isect,
+                                       rng,
+                                       settings.mis_heuristic,
+                                       i == 0,
+                                       channels);
+            let analytic =
+                sample_analytic_lights(material, scene, &ray, &isect, rng, i == 0, channels);
+            let gathered = (direct + environment + sun + analytic).pick(MVector3::zero(), no_surface);
+            color = color + gathered.mul_coords(throughput);
+
+            // At scattering events the medium takes the role of the surface: it
+            // attenuates the path by its albedo, light is gathered with the
+            // phase function, and the path continues in a direction sampled
+            // from it.
+            let scatter_throughput = throughput * medium.albedo;
+            let scatter_position = ray.direction.mul_add(medium.distance, ray.origin);
+            let scatter_direction = if scattered.all_sign_bits_positive() {
+                ray.direction
+            } else {
+                let in_medium = ray.active | (scattered ^ Mask::ones());
+                let g = medium.anisotropy;
+                let sun = sample_sun_in_medium(scene,
+                                               &ray,
+                                               scatter_position,
+                                               g,
+                                               in_medium,
+                                               rng,
+                                               settings.mis_heuristic,
+                                               channels);
+                let analytic = sample_analytic_lights_in_medium(scene,
+                                                                &ray,
+                                                                scatter_position,
+                                                                g,
+                                                                in_medium,
+                                                                rng,
+                                                                channels);
+                color = color + (sun + analytic).mul_coords(scatter_throughput);
+                sample_hg(ray.direction, g, rng)
+            };
+            let incoming_direction = ray.direction;
+
+            // Get a new ray and the color modulation.
+            let max_color_mod = if settings.clamp_fireflies { Some(2.0) } else { None };
+            let (new_ray, color_mod, fr) = continue_path(material, &ray, &isect, rng, i == 0, max_color_mod);
+            let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+            let pass_origin = ray.direction.mul_add(Mf32::epsilon(), isect.position);
+            let origin = new_ray.origin.pick(pass_origin, transparent);
+            let direction = new_ray.direction.pick(ray.direction, transparent);
+            ray = MRay {
+                origin: origin.pick(scatter_position, scattered),
+                direction: direction.pick(scatter_direction, scattered),
+                active: new_ray.active.pick(ray.active, scattered),
+            };
+            throughput = throughput.mul_coords(color_mod.pick(white, transparent));
+            throughput = throughput.pick(scatter_throughput, scattered);
+
+            // The phase function spreads the cone like a BRDF lobe, from not at
+            // all for strongly forward scattering, to as much as a diffuse
+            // surface for isotropic scattering.
+            let phase_spread = Mf32::one() - medium.anisotropy.abs();
+            let lobe_spread = material.get_lobe_spread().pick(Mf32::zero(), transparent);
+            cone_spread = cone_spread + lobe_spread.pick(phase_spread, scattered);
+
+            if i == 0 {
+                // Paths that passed through the first surface do not get the
+                // texture of the surface behind it on the GPU, that one has
+                // been applied on the CPU already.
+                let untextured = MMaterial::broadcast_material(SMaterial::white());
+                texture_index = material.pick(untextured, transparent | scattered).get_texture();
+                texture_coords = isect.tex_coords;
+                texture_footprint = footprint * isect.tex_density;
+                fresnel = fr;
+            }
+            camera_path = camera_path & transparent;
+
+            // After a few bounces, terminate paths randomly with a probability
+            // based on their throughput. Paths that carry little light are
+            // likely to be terminated, and the surviving paths are divided by
+            // the survival probability to compensate, so the estimate remains
+            // unbiased. A path is never killed with certainty though, the
+            // throughput only measures how much the path has been attenuated,
+            // not how much light it would find.
+            if i + 1 >= settings.roulette_depth {
+                let max_throughput = throughput.x.max(throughput.y).max(throughput.z);
+                let survival = max_throughput.max(Mf32::broadcast(0.05)).min(Mf32::one());
+                let u = rng.sample_unit();
+
+                // The sign bit of survival - u is 1 where u exceeds the survival
+                // probability, and that is a sign bit of 1 for inactive.
+                ray.active = ray.active | (survival - u);
+                throughput = throughput * survival.recip_precise();
+            }
+
+            prev_isect = isect.pick(&prev_isect, transparent);
+            prev_direction = incoming_direction.pick(prev_direction, transparent);
+            prev_anisotropy = medium.anisotropy.pick(prev_anisotropy, transparent);
+            prev_scattered = scattered | (prev_scattered & transparent);
+        }
+
+        // Project the radiance at the wavelengths back onto RGB.
+        let color = match channels {
+            MChannels::Rgb => color,
+            MChannels::Spectral(tables, wavelengths) => tables.to_rgb(color, wavelengths),
+        };
+
+        MPixelData {
+            color: color,
+            tex_index: texture_index,
+            tex_coords: texture_coords,
+            tex_footprint: texture_footprint,
+            fresnel: fresnel,
+        }
+    }
+}
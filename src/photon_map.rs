@@ -0,0 +1,217 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements a hash grid to look up photons near a point.
+//!
+//! All lookups in a photon map use the same radius, so a uniform grid with
+//! cells twice as wide as the radius works well: a sphere of that radius
+//! overlaps at most two cells along every axis, so a lookup visits at most 8
+//! cells. The scene is mostly empty space, so instead of allocating all cells
+//! of the grid, the cells are hashed into a table. Cells that hash to the same
+//! bucket share it, a lookup checks the distance of every photon anyway.
+//!
+//! The photons are sorted by bucket, so the photons in a bucket are adjacent,
+//! and the table only stores where every bucket starts.
+
+use vector3::SVector3;
+
+/// A photon that arrived at a surface.
+#[derive(Copy, Clone)]
+pub struct Photon {
+    pub position: SVector3,
+
+    /// The direction in which the photon was travelling.
+    pub direction: SVector3,
+
+    /// The flux that the photon carries.
+    pub power: SVector3,
+}
+
+pub struct PhotonMap {
+    /// The photons, sorted by bucket.
+    photons: Vec<Photon>,
+
+    /// For every bucket the index of its first photon. There is one more
+    /// element than there are buckets, the end of the last bucket.
+    bucket_start: Vec<u32>,
+
+    /// The reciprocal of the width of a cell.
+    cell_size_recip: f32,
+
+    radius: f32,
+}
+
+/// Hashes the coordinates of a grid cell into a bucket index, with the
+/// multipliers of Teschner et al., "Optimized Spatial Hashing for Collision
+/// Detection of Deformable Objects", 2003.
+fn hash(x: i32, y: i32, z: i32, num_buckets: usize) -> usize {
+    let h = (x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663) ^ z.wrapping_mul(83492791)) as u32;
+    h as usize % num_buckets
+}
+
+impl PhotonMap {
+    /// Returns a map without photons.
+    pub fn empty() -> PhotonMap {
+        PhotonMap {
+            photons: Vec::new(),
+            bucket_start: vec![0, 0],
+            cell_size_recip: 1.0,
+            radius: 0.0,
+        }
+    }
+
+    /// Builds a map for lookups within the given radius.
+    pub fn new(photons: Vec<Photon>, radius: f32) -> PhotonMap {
+        assert!(radius > 0.0, "lookup radius must be positive");
+
+        let cell_size_recip = 0.5 / radius;
+        let num_buckets = photons.len().max(1);
+        let buckets: Vec<usize> = photons.iter()
+            .map(|p| PhotonMap::bucket_at(p.position, cell_size_recip, num_buckets))
+            .collect();
+
+        // Count the photons per bucket, then sort them with a counting sort.
+        let mut bucket_start = vec![0; num_buckets + 1];
+        for &b in &buckets {
+            bucket_start[b + 1] += 1;
+        }
+        for i in 0..num_buckets {
+            bucket_start[i + 1] += bucket_start[i];
+        }
+        let mut next = bucket_start.clone();
+        let mut sorted = photons.clone();
+        for (photon, &b) in photons.iter().zip(buckets.iter()) {
+            sorted[next[b] as usize] = *photon;
+            next[b] += 1;
+        }
+
+        PhotonMap {
+            photons: sorted,
+            bucket_start: bucket_start,
+            cell_size_recip: cell_size_recip,
+            radius: radius,
+        }
+    }
+
+    fn cell_coords(position: SVector3, cell_size_recip: f32) -> (i32, i32, i32) {
+        let x = (position.x * cell_size_recip).floor() as i32;
+        let y = (position.y * cell_size_recip).floor() as i32;
+        let z = (position.z * cell_size_recip).floor() as i32;
+        (x, y, z)
+    }
+
+    fn bucket_at(position: SVector3, cell_size_recip: f32, num_buckets: usize) -> usize {
+        let (x, y, z) = PhotonMap::cell_coords(position, cell_size_recip);
+        hash(x, y, z, num_buckets)
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Calls `f` for every photon within the radius of the position.
+    pub fn for_each_near<F>(&self, position: SVector3, mut f: F)
+        where F: FnMut(&Photon)
+    {
+        if self.photons.is_empty() {
+            return;
+        }
+
+        let num_buckets = self.bucket_start.len() - 1;
+        let r = SVector3::new(self.radius, self.radius, self.radius);
+        let (x0, y0, z0) = PhotonMap::cell_coords(position - r, self.cell_size_recip);
+        let (x1, y1, z1) = PhotonMap::cell_coords(position + r, self.cell_size_recip);
+        let radius_sqr = self.radius * self.radius;
+
+        // The sphere is exactly one cell wide, but rounding can put its ends
+        // three cells apart. The third cell would only contain photons at
+        // exactly the radius, so skip it, that bounds the number of cells.
+        let (x1, y1, z1) = (x1.min(x0 + 1), y1.min(y0 + 1), z1.min(z0 + 1));
+
+        // Different cells can hash to the same bucket, every bucket must be
+        // visited only once.
+        let mut visited = [0usize; 8];
+        let mut num_visited = 0;
+
+        for x in x0..x1 + 1 {
+            for y in y0..y1 + 1 {
+                for z in z0..z1 + 1 {
+                    let b = hash(x, y, z, num_buckets);
+                    if visited[..num_visited].contains(&b) {
+                        continue;
+                    }
+                    visited[num_visited] = b;
+                    num_visited += 1;
+
+                    let begin = self.bucket_start[b] as usize;
+                    let end = self.bucket_start[b + 1] as usize;
+                    for photon in &self.photons[begin..end] {
+                        if (photon.position - position).norm_squared() <= radius_sqr {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn photon_at(x: f32, y: f32, z: f32) -> Photon {
+    Photon {
+        position: SVector3::new(x, y, z),
+        direction: SVector3::new(0.0, -1.0, 0.0),
+        power: SVector3::new(1.0, 1.0, 1.0),
+    }
+}
+
+#[test]
+fn for_each_near_finds_photons_within_radius() {
+    let mut photons = Vec::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            photons.push(photon_at(i as f32 * 0.1 - 1.0, 0.0, j as f32 * 0.1 - 1.0));
+        }
+    }
+    let map = PhotonMap::new(photons.clone(), 0.15);
+    assert_eq!(map.len(), photons.len());
+
+    let queries = [SVector3::new(0.0, 0.0, 0.0),
+                   SVector3::new(-0.95, 0.05, 0.33),
+                   SVector3::new(0.9, 0.1, 0.9)];
+    for &q in &queries {
+        let expected = photons.iter()
+            .filter(|p| (p.position - q).norm_squared() <= 0.15 * 0.15)
+            .count();
+        let mut found = 0;
+        map.for_each_near(q, |_| found += 1);
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn for_each_near_handles_lookups_on_cell_boundaries() {
+    // With a radius of 0.1 cells are 0.2 wide. Around these positions the
+    // ends of the lookup sphere lie on cell boundaries, where rounding could
+    // make the lookup span three cells along every axis.
+    let map = PhotonMap::new(vec![photon_at(0.3, 0.3, 0.3)], 0.1);
+    for i in 0..100 {
+        let x = 0.1 + i as f32 * 0.2;
+        let q = SVector3::new(x, x, x);
+        let mut found = 0;
+        map.for_each_near(q, |_| found += 1);
+        assert!(found <= 1);
+    }
+}
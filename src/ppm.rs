@@ -0,0 +1,488 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements stochastic progressive photon mapping.
+//!
+//! Light that reflects off a glossy floor and lands on a wall makes a caustic.
+//! A path from the camera that hits the wall has to bounce off the floor in
+//! exactly the right direction to reach a window, and direct sampling from the
+//! wall does not help either, because the light has to go via the floor. So
+//! the path tracer finds these paths only rarely, and renders them as
+//! fireflies. Photons that leave the windows do find them: they bounce off the
+//! floor like they bounce off anything else, and where they land, the wall is
+//! lit.
+//!
+//! Every frame, before the patches are rendered, photons are shot from the
+//! emissive triangles and stored where they land on a surface that is not too
+//! glossy. Paths from the camera bounce off glossy surfaces until they hit one
+//! that is not, and there they estimate the light from the photons within a
+//! small radius. This is biased, because the photons are not exactly at the
+//! point. The bias vanishes as the radius shrinks, but then fewer photons are
+//! found, so the noise grows. The radius therefore shrinks slowly with every
+//! frame that is accumulated, such that both vanish in the limit. Because
+//! every frame uses its own photon map, no statistics have to be kept per
+//! pixel, and averaging the frames is enough, see Knaus and Zwicker,
+//! "Progressive Photon Mapping: A Probabilistic Approach", 2011. This only
+//! makes sense in the accumulative mode, in the realtime mode every frame
+//! uses the initial radius.
+//!
+//! There are some limitations:
+//!
+//!  * The analytic lights are sampled directly from the vertex where the
+//!    camera path gathers photons, they do not shoot photons. The sun does
+//!    shoot photons through the windows, but those only carry its indirect
+//!    light, direct sunlight is sampled at the vertex too.
+//!
+//!  * Photons carry RGB, so spectral mode is ignored.
+//!
+//!  * Participating media and translucent materials are ignored, and normal
+//!    maps and texture filtering are not applied. Use the path tracer for
+//!    those.
+
+use integrator::{Integrator, MPixelData, Settings};
+use layered::pick_layers;
+use material::{MMaterial, continue_path, eval_brdf, sample_analytic_lights, sample_sun_light};
+use photon_map::{Photon, PhotonMap};
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::{FAR_AWAY, Scene};
+use simd::{Mask, Mf32};
+use spectral::MChannels;
+use std::f32::consts::PI;
+use util::generate_slice8;
+use vector3::{MVector3, SVector3};
+
+/// The number of packets of 8 photon paths to shoot per frame.
+const PHOTON_PACKETS: u32 = 4096;
+
+/// The lookup radius in the first frame, in scene units (meters).
+const INITIAL_RADIUS: f32 = 0.05;
+
+/// The rate at which the radius shrinks. With a lower value it shrinks faster,
+/// which reduces bias faster at the cost of more noise. Knaus and Zwicker
+/// suggest 2/3.
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Surfaces up to this glossiness store photons, and camera paths gather
+/// photons there. Glossier surfaces reflect the paths instead.
+const MAX_GATHER_GLOSSINESS: i32 = 3;
+
+pub struct ProgressivePhotonMapper {
+    photons: PhotonMap,
+
+    /// The number of photon paths that were shot to build the map.
+    num_paths: u32,
+}
+
+/// Returns the lookup radius for the given number of frames accumulated
+/// before. The squared radius is multiplied by (i + alpha) / (i + 1) after
+/// frame i, counting from 1.
+fn radius_at_pass(pass: u32) -> f32 {
+    let mut radius_sqr = INITIAL_RADIUS * INITIAL_RADIUS;
+    for i in 1..pass + 1 {
+        radius_sqr = radius_sqr * (i as f32 + ALPHA) / (i as f32 + 1.0);
+    }
+    radius_sqr.sqrt()
+}
+
+/// Returns a mask with the sign bit set to 1 for materials that are too glossy
+/// to gather photons at. Dielectrics have a diffuse lobe, so they always
+/// gather.
+fn is_too_glossy(material: MMaterial) -> Mask {
+    let gloss = material.get_glossiness();
+    let glossy = Mf32::generate(|i| if gloss.get_coord(i) > MAX_GATHER_GLOSSINESS { -1.0 } else { 1.0 });
+    glossy.pick(Mask::zero(), material.is_dielectric())
+}
+
+/// Shoots 8 photon paths with the light of the sky from the emitters, and
+/// stores the photons where they land on surfaces that gather photons.
+fn trace_photons(scene: &Scene, settings: &Settings, rng: &mut Rng, photons: &mut Vec<Photon>) {
+    let ds = scene.get_emitter_sample(rng);
+
+    // Leave into either side of the window, with a cosine distribution. The
+    // cosine and the density of the direction cancel up to a factor 2 pi.
+    let u = rng.sample_unit();
+    let side = ds.normal.pick(-ds.normal, u - Mf32::broadcast(0.5));
+    let direction = rng.sample_hemisphere_vector().rotate_hemisphere(side);
+    let emission = scene.sky_radiance(-direction);
+    let power = emission * (ds.area * ds.pmf.recip_precise() * Mf32::broadcast(2.0 * PI));
+    let ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), ds.position),
+        direction: direction,
+        active: Mask::zero(),
+    };
+
+    follow_photons(scene, settings, rng, ray, power, Mask::zero(), photons);
+}
+
+/// Shoots 8 photon paths with the light of the sun through the emitters.
+///
+/// The sun is sampled directly at the vertices where camera paths gather, so
+/// these photons are only stored after they reflected off a surface at least
+/// once. They still carry the indirect sunlight, and the caustics of the sun,
+/// which direct sampling cannot find.
+fn trace_sun_photons(scene: &Scene, settings: &Settings, rng: &mut Rng, photons: &mut Vec<Photon>) {
+    // Pick a point on a window, and a direction towards the sun. The sun only
+    // shines through the window if nothing blocks the way to it: not the
+    // things outside, and not the room itself if the sun is on its side.
+    let ds = scene.get_emitter_sample(rng);
+    let (to_sun, pd_sun) = scene.sample_sun(rng);
+    let shadow_ray = MRay {
+        origin: to_sun.mul_add(Mf32::epsilon(), ds.position),
+        direction: to_sun,
+        active: Mask::zero(),
+    };
+    let blocked = scene.is_occluded_by_opaque(&shadow_ray, Mf32::broadcast(FAR_AWAY));
+
+    // The density of the point is pmf / area, and the cosine is the one at
+    // the window, because the flux through the window is what the photons
+    // carry.
+    let cos_theta = ds.normal.dot(to_sun).abs();
+    let density = ds.pmf * pd_sun;
+    let power = scene.sun_radiance(to_sun) * (cos_theta * ds.area * density.recip_precise());
+    let direction = -to_sun;
+    let ray = MRay {
+        origin: direction.mul_add(Mf32::epsilon(), ds.position),
+        direction: direction,
+        active: blocked,
+    };
+
+    follow_photons(scene, settings, rng, ray, power, Mask::ones(), photons);
+}
+
+/// Follows photon paths through the scene, and stores the photons where they
+/// land on surfaces that gather photons, except for lanes where the sign bit
+/// of `direct` is 1, until they reflect off a surface.
+fn follow_photons(scene: &Scene,
+                  settings: &Settings,
+                  rng: &mut Rng,
+                  mut ray: MRay,
+                  initial_power: MVector3,
+                  mut direct: Mask,
+                  photons: &mut Vec<Photon>) {
+    let mut power = initial_power;
+
+    for i in 0..settings.max_bounces {
+        if ray.active.all_sign_bits_negative() {
+            break;
+        }
+
+        let mut isect = scene.intersect_nearest(&ray);
+        isect.face_towards(&ray);
+
+        // Photons that reach a window or escape leave the scene.
+        ray.active = ray.active | isect.material;
+
+        let layers = pick_layers(scene, &ray, &isect, ray.active, rng, MChannels::Rgb);
+        isect.material = layers.material;
+        power = power.mul_coords(layers.weight);
+        ray.active = ray.active | layers.translucent;
+
+        let material = scene.apply_textures(&isect, Mf32::zero(), false);
+        let (material, _emission, transparent) =
+            scene.apply_parameter_maps(material, &isect, Mf32::zero(), rng, false);
+
+        let skip = (ray.active | transparent) | (is_too_glossy(material) | direct);
+        for k in 0..8 {
+            if !skip.get_coord(k).is_sign_negative() {
+                photons.push(Photon {
+                    position: isect.position.extract(k),
+                    direction: ray.direction.extract(k),
+                    power: power.extract(k),
+                });
+            }
+        }
+
+        // Photons pass straight through transparent surfaces, and reflect off
+        // the others.
+        let max_color_mod = if settings.clamp_fireflies { Some(2.0) } else { None };
+        let (new_ray, color_mod, _fresnel) = continue_path(material, &ray, &isect, rng, false, max_color_mod);
+        let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+        let pass_origin = ray.direction.mul_add(Mf32::epsilon(), isect.position);
+        ray = MRay {
+            origin: new_ray.origin.pick(pass_origin, transparent),
+            direction: new_ray.direction.pick(ray.direction, transparent),
+            active: new_ray.active,
+        };
+        power = power.mul_coords(color_mod.pick(white, transparent));
+        direct = direct & transparent;
+
+        // Terminate photons with Russian roulette, like paths in the path
+        // tracer.
+        if i + 1 >= settings.roulette_depth {
+            let max_power = power.x.max(power.y).max(power.z);
+            let max_initial = initial_power.x.max(initial_power.y).max(initial_power.z);
+            let survival = (max_power * max_initial.max(Mf32::broadcast(1e-10)).recip_precise())
+                .max(Mf32::broadcast(0.05))
+                .min(Mf32::one());
+            let u = rng.sample_unit();
+            ray.active = ray.active | (survival - u);
+            power = power * survival.recip_precise();
+        }
+    }
+}
+
+impl ProgressivePhotonMapper {
+    pub fn new() -> ProgressivePhotonMapper {
+        ProgressivePhotonMapper {
+            photons: PhotonMap::empty(),
+            num_paths: 0,
+        }
+    }
+
+    /// Estimates the light that leaves the surfaces in the direction opposite
+    /// to `incoming` from the photons nearby, for lanes where the sign bit of
+    /// `active` is 0. The photons found are collected in `near`, which is only
+    /// passed in so its allocation can be reused.
+    fn gather(&self,
+              material: MMaterial,
+              isect: &MIntersection,
+              incoming: MVector3,
+              active: Mask,
+              near: &mut Vec<Photon>)
+              -> MVector3 {
+        if self.photons.is_empty() {
+            return MVector3::zero();
+        }
+
+        let radius = self.photons.radius();
+        let scale = 1.0 / (self.num_paths as f32 * PI * radius * radius);
+
+        let gathered = generate_slice8(|i| {
+            if active.get_coord(i).is_sign_negative() {
+                return SVector3::zero();
+            }
+
+            let position = isect.position.extract(i);
+            near.clear();
+            self.photons.for_each_near(position, |p| near.push(*p));
+
+            // Evaluate the BRDF for 8 photons at a time, with the surface of
+            // this lane in every lane.
+            let lane_isect = MIntersection {
+                position: MVector3::broadcast(position),
+                normal: MVector3::broadcast(isect.normal.extract(i)),
+                distance: Mf32::broadcast(isect.distance.get_coord(i)),
+                material: Mf32::broadcast(material.get_coord(i)),
+                tex_coords: (Mf32::broadcast(isect.tex_coords.0.get_coord(i)),
+                             Mf32::broadcast(isect.tex_coords.1.get_coord(i))),
+                tex_density: Mf32::broadcast(isect.tex_density.get_coord(i)),
+                tangent: MVector3::broadcast(isect.tangent.extract(i)),
+                bitangent: MVector3::broadcast(isect.bitangent.extract(i)),
+            };
+            let lane_incoming = MVector3::broadcast(incoming.extract(i));
+            let mut sum = MVector3::zero();
+
+            for batch in near.chunks(8) {
+                let n = batch.len();
+                let pad = incoming.extract(i);
+                let direction = MVector3::generate(|j| if j < n { batch[j].direction } else { pad });
+                let power = MVector3::generate(|j| if j < n { batch[j].power } else { SVector3::zero() });
+                let brdf = eval_brdf(lane_isect.material, &lane_isect, lane_incoming, -direction);
+
+                // Photons that arrived at the other side of the surface do not
+                // light this side.
+                let cos_theta = lane_isect.normal.dot(direction);
+                sum = sum + brdf.mul_coords(power).pick(MVector3::zero(), -cos_theta);
+            }
+
+            (0..8).fold(SVector3::zero(), |acc, j| acc + sum.extract(j)) * scale
+        });
+
+        MVector3::generate(|i| gathered[i])
+    }
+}
+
+impl Integrator for ProgressivePhotonMapper {
+    fn name(&self) -> &'static str {
+        "progressive photon mapper"
+    }
+
+    fn prepare(&mut self, scene: &Scene, settings: &Settings, pass: u32, rng: &mut Rng) {
+        if !scene.has_emitters() {
+            self.photons = PhotonMap::empty();
+            self.num_paths = 0;
+            return;
+        }
+
+        // As many paths are shot with sunlight as with skylight, so both sets
+        // of photons are normalized by the same number of paths.
+        let mut photons = Vec::new();
+        for _ in 0..PHOTON_PACKETS {
+            trace_photons(scene, settings, rng, &mut photons);
+            if scene.has_sun() {
+                trace_sun_photons(scene, settings, rng, &mut photons);
+            }
+        }
+        self.photons = PhotonMap::new(photons, radius_at_pass(pass));
+        self.num_paths = PHOTON_PACKETS * 8;
+    }
+
+    fn integrate(&self, scene: &Scene, mut ray: MRay, settings: &Settings, rng: &mut Rng) -> MPixelData {
+        let mut color = MVector3::zero();
+        let mut throughput = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+        let mut near = Vec::new();
+
+        for i in 0..settings.max_bounces {
+            if ray.active.all_sign_bits_negative() {
+                break;
+            }
+
+            let mut isect = scene.intersect_nearest(&ray);
+            isect.face_towards(&ray);
+
+            // Camera paths only bounce off glossy surfaces, and nothing else
+            // finds the light that they reach, so it is not weighted.
+            if !isect.material.all_sign_bits_positive() {
+                let emission = scene.sky_radiance(ray.direction) + scene.sun_radiance(ray.direction);
+                let emission = emission.mul_coords(throughput).pick(MVector3::zero(), ray.active);
+                color = color + MVector3::zero().pick(emission, isect.material);
+            }
+            ray.active = ray.active | isect.material;
+
+            if ray.active.all_sign_bits_negative() {
+                break;
+            }
+
+            let layers = pick_layers(scene, &ray, &isect, ray.active, rng, MChannels::Rgb);
+            isect.material = layers.material;
+            throughput = throughput.mul_coords(layers.weight);
+            ray.active = ray.active | layers.translucent;
+
+            let material = scene.apply_textures(&isect, Mf32::zero(), false);
+            let (material, surface_emission, transparent) =
+                scene.apply_parameter_maps(material, &isect, Mf32::zero(), rng, false);
+            let surface_emission = surface_emission.pick(MVector3::zero(), ray.active | transparent);
+            color = color + surface_emission.mul_coords(throughput);
+
+            // Gather photons at surfaces that are not too glossy, and sample
+            // the sun and analytic lights there. The sun disk is so small that
+            // BRDF sampling would hardly ever find it, so its weight for
+            // multiple importance sampling is practically one, even though
+            // the path does not go on.
+            let skip = (ray.active | transparent) | is_too_glossy(material);
+            if !skip.all_sign_bits_negative() {
+                let arriving = MRay {
+                    origin: ray.origin,
+                    direction: ray.direction,
+                    active: skip,
+                };
+                let photons = self.gather(material, &isect, ray.direction, skip, &mut near);
+                let sun = sample_sun_light(material,
+                                           scene,
+                                           &arriving,
+                                           &isect,
+                                           rng,
+                                           settings.mis_heuristic,
+                                           false,
+                                           MChannels::Rgb);
+                let analytic =
+                    sample_analytic_lights(material, scene, &arriving, &isect, rng, false, MChannels::Rgb);
+                color = color + (photons + sun + analytic).mul_coords(throughput);
+            }
+
+            // Paths that gathered end here, the others pass through transparent
+            // surfaces or reflect off glossy ones.
+            let gathered = skip ^ Mask::ones();
+            ray.active = ray.active | gathered;
+            let max_color_mod = if settings.clamp_fireflies { Some(2.0) } else { None };
+            let (new_ray, color_mod, _fresnel) =
+                continue_path(material, &ray, &isect, rng, false, max_color_mod);
+            let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+            let pass_origin = ray.direction.mul_add(Mf32::epsilon(), isect.position);
+            ray = MRay {
+                origin: new_ray.origin.pick(pass_origin, transparent),
+                direction: new_ray.direction.pick(ray.direction, transparent),
+                active: new_ray.active,
+            };
+            throughput = throughput.mul_coords(color_mod.pick(white, transparent));
+
+            if i + 1 >= settings.roulette_depth {
+                let max_throughput = throughput.x.max(throughput.y).max(throughput.z);
+                let survival = max_throughput.max(Mf32::broadcast(0.05)).min(Mf32::one());
+                let u = rng.sample_unit();
+                ray.active = ray.active | (survival - u);
+                throughput = throughput * survival.recip_precise();
+            }
+        }
+
+        MPixelData::with_color(color)
+    }
+}
+
+#[test]
+fn radius_shrinks_at_the_expected_rate() {
+    // The squared radius is proportional to i^(alpha - 1) in the limit.
+    let r1000 = radius_at_pass(1000);
+    let r2000 = radius_at_pass(2000);
+    assert!(r2000 < r1000 && r1000 < INITIAL_RADIUS);
+    let expected = (2000.0_f32 / 1000.0).powf(ALPHA - 1.0);
+    let ratio = (r2000 * r2000) / (r1000 * r1000);
+    assert!((ratio - expected).abs() < 1e-3, "ratio is {}, expected {}", ratio, expected);
+}
+
+#[test]
+fn photons_estimate_irradiance_of_square_emitter() {
+    use environment::EnvironmentMap;
+    use hdr::HdrImage;
+    use material::SMaterial;
+    use wavefront::quad;
+
+    // A white floor, lit by a square window of 2 by 2 at a height of 1, with
+    // a sky of radiance 1 in every direction behind it.
+    let floor = quad(SVector3::new(-10.0, 0.0, -10.0),
+                     SVector3::new(0.0, 0.0, 20.0),
+                     SVector3::new(20.0, 0.0, 0.0),
+                     SMaterial::white());
+    let window = quad(SVector3::new(-1.0, 1.0, -1.0),
+                      SVector3::new(2.0, 0.0, 0.0),
+                      SVector3::new(0.0, 0.0, 2.0),
+                      SMaterial::sky());
+    let mut scene = Scene::from_meshes(&[floor, window]);
+    let sky = HdrImage {
+        width: 4,
+        height: 2,
+        pixels: vec![SVector3::new(1.0, 1.0, 1.0); 8],
+    };
+    scene.set_environment(EnvironmentMap::new(sky));
+
+    // The irradiance below a corner of a rectangle parallel to the surface at
+    // distance 1, with sides x and y, and radiance 1.
+    let corner = |x: f32, y: f32| {
+        let (sx, sy) = ((1.0 + x * x).sqrt(), (1.0 + y * y).sqrt());
+        0.5 * (x / sx * (y / sx).atan() + y / sy * (x / sy).atan())
+    };
+
+    // Below the window the rectangle splits into four with a corner above the
+    // point. The query points are further apart than twice the radius, so
+    // every photon counts only once.
+    let mut ppm = ProgressivePhotonMapper::new();
+    let mut settings = Settings::new(64);
+    settings.clamp_fireflies = false;
+    let mut estimated = 0.0;
+    let mut expected = 0.0;
+    for pass in 0..4 {
+        let mut rng = Rng::with_seed(3, 17, pass);
+        ppm.prepare(&scene, &settings, 0, &mut rng);
+        let radius = ppm.photons.radius();
+        let scale = 1.0 / (ppm.num_paths as f32 * PI * radius * radius);
+
+        for i in 0..5 {
+            for j in 0..4 {
+                let (x, z) = (i as f32 * 0.15 - 0.3, j as f32 * 0.15 - 0.225);
+                let mut flux = 0.0;
+                ppm.photons.for_each_near(SVector3::new(x, 0.0, z), |p| flux += p.power.x);
+                estimated += flux * scale;
+                expected += corner(1.0 + x, 1.0 + z) + corner(1.0 - x, 1.0 + z) +
+                            corner(1.0 + x, 1.0 - z) + corner(1.0 - x, 1.0 - z);
+            }
+        }
+    }
+
+    let ratio = estimated / expected;
+    assert!((ratio - 1.0).abs() < 0.1, "photons estimate {} times the irradiance", ratio);
+}
@@ -0,0 +1,301 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements procedural textures.
+//!
+//! A procedural texture is a pattern that is evaluated at the shading point,
+//! rather than looked up in an image. That way a test scene does not need
+//! any image files, and a pattern evaluated in world space has no seams and
+//! no stretching, even on geometry without texture coordinates.
+//!
+//! Procedural textures live in the `TextureSet` next to image textures, so
+//! they can be used for anything that a texture can be used for. They are
+//! always evaluated on the CPU, also for the first bounce.
+
+use vector3::SVector3;
+
+/// The pattern of a procedural texture. Every pattern produces a value
+/// between 0 and 1, which selects a color between the two colors of the
+/// texture, except for `UvDebug`, which has its own colors.
+#[derive(Copy, Clone, Debug)]
+pub enum Pattern {
+    /// Alternating cells of size 1.
+    Checkerboard,
+
+    /// Lines of the given width at every integer coordinate. The value is 1 on
+    /// the lines.
+    Grid(f32),
+
+    /// Perlin noise.
+    Noise,
+
+    /// Fractional Brownian motion: Perlin noise summed over the given number
+    /// of octaves.
+    Fbm(u32),
+
+    /// Growth rings around the y-axis, distorted by noise. In texture space
+    /// these are stripes along the v direction, like the grain of a plank.
+    Wood,
+
+    /// The fractional part of the texture coordinates in red and green, with
+    /// a checkerboard in blue, to visualize the texture mapping.
+    UvDebug,
+}
+
+/// The coordinates that a procedural texture is evaluated at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Domain {
+    /// The interpolated texture coordinates, as (u, v, 0).
+    TexCoords,
+
+    /// The position of the intersection.
+    World,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Procedural {
+    pattern: Pattern,
+    domain: Domain,
+    scale: f32,
+    color_0: SVector3,
+    color_1: SVector3,
+}
+
+/// Hashes integer lattice coordinates into 32 pseudorandom bits.
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6b343) ^
+                (y as u32).wrapping_mul(0xd8163841) ^
+                (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^ (h >> 15)
+}
+
+/// Returns the dot product of the offset with one of the 12 gradients from
+/// Ken Perlin's improved noise, selected by the hash.
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    match h & 15 {
+        0 | 12 => x + y,
+        1 | 13 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 14 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+/// The quintic interpolation curve of improved noise, which has zero first
+/// and second derivatives at 0 and 1.
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Evaluates 3D Perlin noise. The result is roughly between -1 and 1, and it
+/// is zero at integer coordinates.
+pub fn noise(p: SVector3) -> f32 {
+    let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (xf as i32, yf as i32, zf as i32);
+    let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let g = |dx: i32, dy: i32, dz: i32| {
+        let h = hash(xi + dx, yi + dy, zi + dz);
+        gradient(h, x - dx as f32, y - dy as f32, z - dz as f32)
+    };
+
+    let x00 = lerp(g(0, 0, 0), g(1, 0, 0), u);
+    let x10 = lerp(g(0, 1, 0), g(1, 1, 0), u);
+    let x01 = lerp(g(0, 0, 1), g(1, 0, 1), u);
+    let x11 = lerp(g(0, 1, 1), g(1, 1, 1), u);
+    lerp(lerp(x00, x10, v), lerp(x01, x11, v), w)
+}
+
+/// Sums octaves of Perlin noise, every octave with double the frequency and
+/// half the amplitude of the previous one. The result is roughly between -1
+/// and 1.
+pub fn fbm(p: SVector3, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(p * frequency);
+        total_amplitude += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total_amplitude
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+/// Maps a value that is roughly between -1 and 1 onto [0, 1].
+fn unsigned(x: f32) -> f32 {
+    (0.5 + 0.5 * x).max(0.0).min(1.0)
+}
+
+fn checkerboard(p: SVector3) -> f32 {
+    let sum = p.x.floor() as i32 + p.y.floor() as i32 + p.z.floor() as i32;
+    (sum & 1) as f32
+}
+
+impl Procedural {
+    /// Constructs a procedural texture in texture space at scale 1, from black
+    /// to white.
+    pub fn new(pattern: Pattern) -> Procedural {
+        Procedural {
+            pattern: pattern,
+            domain: Domain::TexCoords,
+            scale: 1.0,
+            color_0: SVector3::zero(),
+            color_1: SVector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Evaluates the pattern at the position of the intersection rather than
+    /// at the texture coordinates.
+    pub fn in_world_space(self) -> Procedural {
+        Procedural { domain: Domain::World, ..self }
+    }
+
+    /// Sets the frequency of the pattern: the number of repetitions per unit
+    /// of texture coordinates or world space.
+    pub fn with_scale(self, scale: f32) -> Procedural {
+        Procedural { scale: scale, ..self }
+    }
+
+    /// Sets the colors for the pattern values 0 and 1. The colors are linear.
+    pub fn with_colors(self, color_0: SVector3, color_1: SVector3) -> Procedural {
+        Procedural {
+            color_0: color_0,
+            color_1: color_1,
+            ..self
+        }
+    }
+
+    /// Evaluates the pattern at the scaled point. The footprint is the size of
+    /// the area to filter over in the same units. Only the patterns with hard
+    /// edges need filtering, they fade to their average when the footprint
+    /// approaches the size of a cell.
+    fn value(&self, p: SVector3, footprint: f32) -> f32 {
+        let blur = (footprint * 2.0 - 1.0).max(0.0).min(1.0);
+        let fade_to = |x: f32, mean: f32| lerp(x, mean, blur);
+        match self.pattern {
+            Pattern::Checkerboard => fade_to(checkerboard(p), 0.5),
+            Pattern::Grid(width) => {
+                let on_line = fract(p.x) < width || fract(p.y) < width ||
+                              (self.domain == Domain::World && fract(p.z) < width);
+                let dims = if self.domain == Domain::World { 3 } else { 2 };
+                let coverage = 1.0 - (1.0 - width.min(1.0)).powi(dims);
+                fade_to(if on_line { 1.0 } else { 0.0 }, coverage)
+            }
+            Pattern::Noise => unsigned(noise(p)),
+            Pattern::Fbm(octaves) => unsigned(fbm(p, octaves)),
+            Pattern::Wood => {
+                let r = (p.x * p.x + p.z * p.z).sqrt();
+                let ring = fract(r + 0.35 * fbm(p * 0.5, 4));
+
+                // Early wood is light and grows gradually darker, late wood
+                // is dark and ends abruptly.
+                ring * ring
+            }
+            Pattern::UvDebug => 0.0,
+        }
+    }
+
+    /// Returns the coordinate space that the pattern is evaluated in.
+    pub fn domain(&self) -> Domain {
+        self.domain
+    }
+
+    /// Evaluates the texture at the given texture coordinates or position,
+    /// depending on the domain. The footprint is the size of the ray cone in
+    /// the units of the domain: texture coordinate units in texture space, and
+    /// units of length in world space.
+    pub fn sample(&self, u: f32, v: f32, position: SVector3, footprint: f32) -> SVector3 {
+        let p = match self.domain {
+            Domain::TexCoords => SVector3::new(u, v, 0.0) * self.scale,
+            Domain::World => position * self.scale,
+        };
+        let footprint = footprint * self.scale;
+
+        if let Pattern::UvDebug = self.pattern {
+            let blue = 0.5 * checkerboard(p * 8.0);
+            return SVector3::new(fract(p.x), fract(p.y), blue);
+        }
+
+        let t = self.value(p, footprint);
+        self.color_0 * (1.0 - t) + self.color_1 * t
+    }
+
+    /// Evaluates one channel of the texture, like `sample()`. Channels beyond
+    /// blue are 1, procedural textures are opaque.
+    pub fn sample_channel(&self, u: f32, v: f32, position: SVector3, footprint: f32, channel: u32) -> f32 {
+        let c = self.sample(u, v, position, footprint);
+        match channel {
+            0 => c.x,
+            1 => c.y,
+            2 => c.z,
+            _ => 1.0,
+        }
+    }
+}
+
+#[test]
+fn noise_is_zero_at_lattice_points() {
+    for &(x, y, z) in &[(0.0, 0.0, 0.0), (1.0, 2.0, 3.0), (-4.0, 7.0, -1.0)] {
+        assert_eq!(0.0, noise(SVector3::new(x, y, z)));
+    }
+}
+
+#[test]
+fn noise_is_bounded_and_continuous() {
+    let mut prev = noise(SVector3::new(0.0, 0.3, 0.7));
+    for i in 1..1000 {
+        let x = i as f32 * 0.01;
+        let n = noise(SVector3::new(x, 0.3, 0.7));
+        assert!(n.abs() <= 1.5);
+        assert!((n - prev).abs() < 0.1);
+        prev = n;
+    }
+}
+
+#[test]
+fn checkerboard_alternates_and_fades() {
+    let checker = Procedural::new(Pattern::Checkerboard).with_scale(2.0);
+    let origin = SVector3::zero();
+    assert_eq!(0.0, checker.sample(0.25, 0.25, origin, 0.0).x);
+    assert_eq!(1.0, checker.sample(0.75, 0.25, origin, 0.0).x);
+    assert_eq!(0.0, checker.sample(0.75, 0.75, origin, 0.0).x);
+
+    // With a footprint larger than a cell, the average is returned.
+    assert_eq!(0.5, checker.sample(0.75, 0.25, origin, 1.0).x);
+}
+
+#[test]
+fn world_space_ignores_tex_coords() {
+    let checker = Procedural::new(Pattern::Checkerboard).in_world_space();
+    let p = SVector3::new(1.5, 0.5, 0.5);
+    assert_eq!(checker.sample(0.0, 0.0, p, 0.0), checker.sample(0.7, 0.2, p, 0.0));
+    assert_eq!(1.0, checker.sample(0.0, 0.0, p, 0.0).x);
+
+    // The footprint is in world units, and it is scaled like the position.
+    let fine = checker.with_scale(4.0);
+    assert_eq!(0.5, fine.sample(0.0, 0.0, p, 0.25).x);
+}
@@ -36,7 +36,9 @@ pub struct MIntersection {
     /// The position at which the ray intersected the surface.
     pub position: MVector3,
 
-    /// The surface normal at the intersection point.
+    /// The surface normal at the intersection point. If the surface has a
+    /// normal map, this is the perturbed shading normal after
+    /// `Scene::apply_normal_maps()`.
     pub normal: MVector3,
 
     /// This distance between the ray origin and the position.
@@ -47,6 +49,15 @@ pub struct MIntersection {
 
     /// Texture coordinates at the intersection point.
     pub tex_coords: (Mf32, Mf32),
+
+    /// Texture coordinate units per unit of length at the intersection point,
+    /// see `Triangle::tex_density`.
+    pub tex_density: Mf32,
+
+    /// The directions in which the texture coordinates increase, see
+    /// `Triangle::tangent`.
+    pub tangent: MVector3,
+    pub bitangent: MVector3,
 }
 
 impl SRay {
@@ -99,9 +110,20 @@ impl MIntersection {
             distance: Mf32::broadcast(max_dist),
             material: MMaterial::sky(),
             tex_coords: (Mf32::zero(), Mf32::zero()),
+            tex_density: Mf32::zero(),
+            tangent: MVector3::zero(),
+            bitangent: MVector3::zero(),
         }
     }
 
+    /// Flips the normals that point away from the ray origin, such that the
+    /// normal is on the side of the surface that the ray hit.
+    pub fn face_towards(&mut self, ray: &MRay) {
+        // The sign bit of the cosine is 1 where the normal faces the ray.
+        let cos_theta = ray.direction.dot(self.normal);
+        self.normal = (-self.normal).pick(self.normal, cos_theta);
+    }
+
     pub fn pick(&self, other: &MIntersection, mask: Mask) -> MIntersection {
         let u = self.tex_coords.0.pick(other.tex_coords.0, mask);
         let v = self.tex_coords.1.pick(other.tex_coords.1, mask);
@@ -111,6 +133,9 @@ impl MIntersection {
             distance: self.distance.pick(other.distance, mask),
             material: self.material.pick(other.material, mask),
             tex_coords: (u, v),
+            tex_density: self.tex_density.pick(other.tex_density, mask),
+            tangent: self.tangent.pick(other.tangent, mask),
+            bitangent: self.bitangent.pick(other.bitangent, mask),
         }
     }
 }
@@ -5,10 +5,16 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
-use material::{continue_path, sky_intensity};
+use bdpt::Bdpt;
+use integrator::{DebugView, Integrator, MPixelData, Settings};
+use material::MisHeuristic;
+use medium::Medium;
+use path_tracer::PathTracer;
+use ppm::ProgressivePhotonMapper;
 use random::Rng;
 use scene::Scene;
 use simd::{Mf32, Mi32};
+use spectral::SpectralTables;
 use std::cell::UnsafeCell;
 use util::{cache_line_aligned_vec, generate_slice8};
 use vector3::{MVector3, SVector3};
@@ -17,7 +23,18 @@ pub struct Renderer {
     scene: Scene,
     width: u32,
     height: u32,
-    enable_debug_view: bool,
+
+    /// The integrators that can be selected, and the index of the selected
+    /// one.
+    integrators: Vec<Box<Integrator>>,
+    integrator: usize,
+
+    /// Settings that the integrators take into account.
+    settings: Settings,
+
+    /// Whether to reject outliers when accumulating samples, see
+    /// `reject_outliers()`.
+    reject_outliers: bool,
 
     /// A value that increases at a rate of 1 per second.
     time: f32,
@@ -31,13 +48,6 @@ pub struct RenderBuffer {
     buffer: UnsafeCell<Vec<Mi32>>,
 }
 
-struct MPixelData {
-    color: MVector3,
-    tex_index: Mi32,
-    tex_coords: (Mf32, Mf32),
-    fresnel: Mf32,
-}
-
 impl RenderBuffer {
     /// Allocates a new buffer to render into, memory uninitialized.
     ///
@@ -123,7 +133,13 @@ impl Renderer {
             scene: scene,
             width: width,
             height: height,
-            enable_debug_view: false,
+            integrators: vec![Box::new(PathTracer),
+                              Box::new(Bdpt),
+                              Box::new(ProgressivePhotonMapper::new()),
+                              Box::new(DebugView)],
+            integrator: 0,
+            settings: Settings::new(width),
+            reject_outliers: false,
             time: 0.0,
             time_delta: 0.0,
         }
@@ -147,8 +163,95 @@ impl Renderer {
         self.scene.camera.set_rotation(alpha, alpha_delta);
     }
 
-    pub fn toggle_debug_view(&mut self) {
-        self.enable_debug_view = !self.enable_debug_view;
+    /// Selects the next integrator, and returns its name.
+    pub fn next_integrator(&mut self) -> &'static str {
+        self.integrator = (self.integrator + 1) % self.integrators.len();
+        self.integrators[self.integrator].name()
+    }
+
+    /// Lets the selected integrator do its work for the frame that is shared
+    /// by all pixels. `pass` is the number of frames that have been
+    /// accumulated before this one.
+    pub fn prepare_frame(&mut self, frame_number: u32, pass: u32) {
+        // The patches seed their generators with their coordinates, none of
+        // them is at the far corner of the image.
+        let mut rng = Rng::with_seed(self.width, self.height, frame_number);
+        let integrator = &mut self.integrators[self.integrator];
+        integrator.prepare(&self.scene, &self.settings, pass, &mut rng);
+    }
+
+    /// Sets the maximum number of bounces of a path. Returns the new maximum,
+    /// which is at least 1.
+    pub fn set_max_bounces(&mut self, max_bounces: u32) -> u32 {
+        self.settings.max_bounces = max_bounces.max(1);
+        self.settings.max_bounces
+    }
+
+    pub fn max_bounces(&self) -> u32 {
+        self.settings.max_bounces
+    }
+
+    /// Sets whether textures at the first bounce are applied on the GPU. This
+    /// must be disabled when the image is not displayed with the gbuffer
+    /// shader.
+    pub fn set_gpu_textures(&mut self, enable: bool) {
+        self.settings.gpu_textures = enable;
+    }
+
+    /// Enables or disables clamping of the color modulation per bounce.
+    pub fn set_clamp_fireflies(&mut self, enable: bool) {
+        self.settings.clamp_fireflies = enable;
+    }
+
+    /// Toggles clamping of the color modulation, returns whether it is now
+    /// enabled.
+    pub fn toggle_clamp_fireflies(&mut self) -> bool {
+        self.settings.clamp_fireflies = !self.settings.clamp_fireflies;
+        self.settings.clamp_fireflies
+    }
+
+    /// Toggles outlier rejection in accumulative mode, returns whether it is
+    /// now enabled.
+    pub fn toggle_reject_outliers(&mut self) -> bool {
+        self.reject_outliers = !self.reject_outliers;
+        self.reject_outliers
+    }
+
+    /// Moves the sun of a daylight sky by the given angles in radians. Returns
+    /// the new elevation and azimuth, or `None` if the sky has no sun.
+    pub fn move_sun(&mut self, delta_elevation: f32, delta_azimuth: f32) -> Option<(f32, f32)> {
+        self.scene.adjust_sun(delta_elevation, delta_azimuth)
+    }
+
+    /// Fills the scene with the given fog if it has none, or removes the fog.
+    /// Returns whether there is fog now.
+    pub fn toggle_fog(&mut self, fog: Medium) -> bool {
+        let new_fog = match self.scene.fog() {
+            Some(..) => None,
+            None => Some(fog),
+        };
+        self.scene.set_fog(new_fog);
+        new_fog.is_some()
+    }
+
+    /// Switches between RGB and spectral rendering, returns whether spectral
+    /// rendering is now enabled.
+    pub fn toggle_spectral(&mut self) -> bool {
+        self.settings.spectral = match self.settings.spectral {
+            Some(..) => None,
+            None => Some(SpectralTables::new()),
+        };
+        self.settings.spectral.is_some()
+    }
+
+    /// Switches between the balance heuristic and the power heuristic, and
+    /// returns the new heuristic.
+    pub fn toggle_mis_heuristic(&mut self) -> MisHeuristic {
+        self.settings.mis_heuristic = match self.settings.mis_heuristic {
+            MisHeuristic::Balance => MisHeuristic::Power,
+            MisHeuristic::Power => MisHeuristic::Balance,
+        };
+        self.settings.mis_heuristic
     }
 
     /// Returns the screen coordinates of the block of 16x4 pixels where (x, y)
@@ -257,6 +360,12 @@ impl Renderer {
 
     /// Converts floating-point texture coordinates to integers and stores the
     /// values in the bitmap.
+    ///
+    /// The gbuffer consists of two layers of the size of the frame, stacked
+    /// vertically. The first layer holds the high bytes of the 16-bit texture
+    /// coordinates, the Fresnel factor, and the texture index. The second layer
+    /// holds the low bytes of the texture coordinates, and the logarithm of
+    /// the texture footprint to select a mip level.
     fn store_pixels_gbuffer_16x4(&self,
                                  gbuffer: &mut [Mi32],
                                  x: u32,
@@ -264,25 +373,39 @@ impl Renderer {
                                  data: &[MPixelData; 8]) {
         // Generate the pixels for texture coordinates and the Fresnel factor.
         let range = Mf32::broadcast(255.0);
+        let range_uv = Mf32::broadcast(65536.0);
+        let mut detail = [Mi32::zero(); 8];
         let uvs = generate_slice8(|i| {
             let tex_index = data[i].tex_index;
-            let tex_x = data[i].tex_coords.0 * range;
-            let tex_y = data[i].tex_coords.1 * range;
             let fresnel = data[i].fresnel * range;
 
             // Do not clamp the texture coordinates, make them wrap instead.
-            let wrap = Mi32::broadcast(0xff);
-            let r = tex_x.into_mi32() & wrap;
-            let g = (tex_y.into_mi32() & wrap).map(|x| x << 8);
+            let wrap = Mi32::broadcast(0xffff);
+            let tex_x = (data[i].tex_coords.0 * range_uv).into_mi32() & wrap;
+            let tex_y = (data[i].tex_coords.1 * range_uv).into_mi32() & wrap;
+            let r = tex_x.map(|x| x >> 8);
+            let g = tex_y.map(|x| (x >> 8) << 8);
             let b = fresnel.into_mi32().map(|x| x << 16);
 
             // Store the texture index in the alpha channel.
             let a = tex_index.map(|x| x << 24);
 
+            // Store the base 2 logarithm of the footprint with 3 fractional
+            // bits, for footprints from 2^-24 up to 2^8.
+            let log_footprint = data[i].tex_footprint.map(|f| {
+                let lf = f.log2().max(-24.0).min(8.0);
+                ((lf + 24.0) * 8.0).min(255.0)
+            });
+            let lo_x = tex_x & Mi32::broadcast(0xff);
+            let lo_y = (tex_y & Mi32::broadcast(0xff)).map(|x| x << 8);
+            let lf = log_footprint.into_mi32().map(|x| x << 16);
+            detail[i] = (lo_x | lo_y) | lf;
+
             (r | g) | (b | a)
         });
 
         self.store_mi32_16x4(gbuffer, x, y, &uvs);
+        self.store_mi32_16x4(gbuffer, x, y + self.height, &detail);
     }
 
     /// Renders a block of 16x4 pixels, where (x, y) is the coordinate of the
@@ -291,12 +414,13 @@ impl Renderer {
     /// for every pixel.
     fn render_block_16x4(&self, x: u32, y: u32, rng: &mut Rng) -> [MPixelData; 8] {
         let (xs, ys) = self.get_pixel_coords_16x4(x, y, rng);
+        let integrator = &self.integrators[self.integrator];
 
-        if self.enable_debug_view {
-            generate_slice8(|i| self.render_pixels_debug(xs[i], ys[i]))
-        } else {
-            generate_slice8(|i| self.render_pixels(xs[i], ys[i], rng))
-        }
+        generate_slice8(|i| {
+            let t = rng.sample_unit();
+            let ray = self.scene.camera.get_ray(xs[i], ys[i], t);
+            integrator.integrate(&self.scene, ray, &self.settings, rng)
+        })
     }
 
     /// Renders a square part of a frame.
@@ -326,11 +450,44 @@ impl Renderer {
         }
     }
 
+    /// Limits the luminance of new samples for 8 pixels, relative to the mean
+    /// of the samples accumulated so far.
+    ///
+    /// A single path that finds a bright light through an unlikely bounce can
+    /// leave a white speck that takes thousands of samples to average out.
+    /// Such a sample is scaled down to a bound relative to the mean of the
+    /// pixel. The bound grows with the square root of the number of samples,
+    /// so fewer and fewer samples are affected as the image converges, and the
+    /// estimate is still consistent. Unlike clamping per bounce, this leaves
+    /// the paths themselves alone.
+    fn reject_outliers(&self, sum: MVector3, sample: MVector3, num_samples: u32) -> MVector3 {
+        // With too few samples the mean is meaningless.
+        if num_samples < 8 {
+            return sample;
+        }
+
+        let luminance = |c: MVector3| {
+            c.x.mul_add(Mf32::broadcast(0.2126),
+                        c.y.mul_add(Mf32::broadcast(0.7152), c.z * Mf32::broadcast(0.0722)))
+        };
+
+        let n = num_samples as f32;
+        let mean = luminance(sum) * Mf32::broadcast(1.0 / n);
+        let bound = (mean * Mf32::broadcast(16.0 * n.sqrt())).max(Mf32::one());
+        let lum = luminance(sample);
+
+        // Where the sample is brighter than the bound, the factor is less
+        // than one.
+        let factor = bound.div(lum.max(bound));
+        sample * factor
+    }
+
     /// Renders a square part of a frame, adds the contribution to the buffer.
     ///
     /// The (x, y) coordinate is the coordinate of the bottom-left pixel of the
     /// patch. The patch width must be a multiple of 16. The memory layout of
-    /// the HDR buffer is as a bitmap of 16x4 blocks.
+    /// the HDR buffer is as a bitmap of 16x4 blocks. The buffer must contain
+    /// the sum of `num_samples` samples so far.
     ///
     /// This also fills the gbuffer. This is not done accumulatively, it is
     /// filled for the current frame. (Though the gbuffer should be fairly
@@ -342,7 +499,8 @@ impl Renderer {
                                 patch_width: u32,
                                 x: u32,
                                 y: u32,
-                                frame_number: u32) {
+                                frame_number: u32,
+                                num_samples: u32) {
         assert_eq!(patch_width & 15, 0); // Patch width must be a multiple of 16.
         let w = patch_width / 16;
         let h = patch_width / 4;
@@ -355,7 +513,14 @@ impl Renderer {
                 let data = self.render_block_16x4(xb, yb, &mut rng);
                 let index = ((y / 4 + j) * (self.width / 16) + (x / 16 + i)) as usize;
                 let current = hdr_buffer[index];
-                hdr_buffer[index] = generate_slice8(|k| current[k] + data[k].color);
+                hdr_buffer[index] = generate_slice8(|k| {
+                    let sample = if self.reject_outliers {
+                        self.reject_outliers(current[k], data[k].color, num_samples)
+                    } else {
+                        data[k].color
+                    };
+                    current[k] + sample
+                });
                 self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
             }
         }
@@ -393,97 +558,13 @@ impl Renderer {
                 for i in 0..w {
                     let rgbs = hdr_buffer[(j * w + i) as usize];
                     let rgbs = generate_slice8(|k| rgbs[k] * factor);
-                    let data = generate_slice8(|k| {
-                        MPixelData {
-                            color: rgbs[k],
-                            // These values are unused, only the color is stored
-                            // in this function.
-                            tex_index: Mi32::zero(),
-                            tex_coords: (Mf32::zero(), Mf32::zero()),
-                            fresnel: Mf32::zero(),
-                        }
-                    });
+                    // Only the color is stored in this function.
+                    let data = generate_slice8(|k| MPixelData::with_color(rgbs[k]));
                     self.store_pixels_color_16x4(bitmap, i * 16, j * 4, &data);
                 }
             }
         }
     }
-
-    /// Returns colors for the pixels, as well as the texture indices.
-    fn render_pixels(&self, x: Mf32, y: Mf32, rng: &mut Rng) -> MPixelData {
-        let t = rng.sample_unit();
-        let mut ray = self.scene.camera.get_ray(x, y, t);
-        let mut color = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
-        let mut hit_emissive = Mf32::zero();
-        let mut texture_index = Mi32::zero();
-        let mut texture_coords = (Mf32::zero(), Mf32::zero());
-        let mut fresnel = Mf32::zero();
-
-        let max_bounces = 5;
-        for i in 0..max_bounces {
-            let isect = self.scene.intersect_nearest(&ray);
-            hit_emissive = isect.material;
-
-            // Do not allow NaNs to creep in.
-            debug_assert!(ray.direction.all_finite(), "infinite ray direction at iteration {}", i);
-            debug_assert!(isect.position.all_finite(), "infinite intersection at iteration {}", i);
-            debug_assert!(isect.distance.all_finite(), "infinite distance at iteration {}", i);
-
-            // Stop when every ray hit a light source.
-            if isect.material.all_sign_bits_negative() {
-                break;
-            }
-
-            // Get a new ray and the color modulation. For the first bounce, the
-            // Fresnel term should not contribute to the color modulation
-            // because that is handled on the GPU.
-            let (new_ray, color_mod, fr) =
-                continue_path(isect.material, &self.scene, &ray, &isect, rng, i == 0);
-            ray = new_ray;
-            color = color.mul_coords(color_mod);
-
-            if i == 0 {
-                texture_index = isect.material.get_texture();
-                texture_coords = isect.tex_coords;
-                fresnel = fr;
-            }
-        }
-
-        // Compute light contribution.
-        let emission = sky_intensity(ray.direction);
-        color = color.mul_coords(emission);
-
-        // If the last thing that a ray hit was an emissive material, it has
-        // found a light source and the computed color is correct. If the ray
-        // did not find a light source but the loop was terminated, the computed
-        // color is invalid; it should be black.
-        let color = MVector3::zero().pick(color, hit_emissive);
-
-        MPixelData {
-            color: color,
-            tex_index: texture_index,
-            tex_coords: texture_coords,
-            fresnel: fresnel,
-        }
-    }
-
-    fn render_pixels_debug(&self, x: Mf32, y: Mf32) -> MPixelData {
-        let t = Mf32::zero();
-        let ray = self.scene.camera.get_ray(x, y, t);
-        let (numi_aabb, numi_tri) = self.scene.intersect_debug(&ray);
-
-        let g = Mf32::broadcast((numi_aabb as f32).log2() * 0.1);
-        let b = Mf32::broadcast((numi_tri as f32).log2() * 0.1);
-
-        let color = MVector3::new(Mf32::zero(), g, b);
-
-        MPixelData {
-            color: color,
-            tex_index: Mi32::zero(),
-            tex_coords: (Mf32::zero(), Mf32::zero()),
-            fresnel: Mf32::zero(),
-        }
-    }
 }
 
 #[test]
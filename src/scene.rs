@@ -5,13 +5,21 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
+use aabb::Aabb;
 use bvh::Bvh;
+use emitters::Emitters;
+use daylight::Daylight;
+use environment::EnvironmentMap;
+use layered::Layered;
+use lights::Light;
 use material::{MDirectSample, MMaterial};
+use medium::{self, GridVolume, MMediumInterval, MMediumSample, Medium, Volume};
 use quaternion::{MQuaternion, SQuaternion, rotate};
 use random::Rng;
 use ray::{MIntersection, MRay};
-use simd::Mf32;
+use simd::{Mask, Mf32};
 use std::f32::consts::PI;
+use texture::{ChannelMap, TextureSet};
 use triangle::Triangle;
 use util::generate_slice8;
 use vector3::{MVector3, SVector3};
@@ -62,6 +70,14 @@ impl Camera {
         self.screen_distance = 1.0 / (fov / 2.0).sin();
     }
 
+    /// Returns the angle in radians between rays through adjacent pixels at
+    /// the center of the screen, for an image of the given width. This is the
+    /// spread angle of the ray cone that a camera ray represents.
+    pub fn pixel_spread(&self, width: u32) -> f32 {
+        // Screen coordinates range from -1 to 1 horizontally.
+        (2.0 / width as f32) / self.screen_distance
+    }
+
     /// Sets the rotation of the camera in the xz-plane.
     pub fn set_rotation(&mut self, radians: f32, delta: f32) {
         let x = (radians * 0.5).cos();
@@ -99,32 +115,440 @@ impl Camera {
     }
 }
 
+/// The source of light for rays that escape the scene.
+pub enum Sky {
+    /// Radiance from an image, see the `environment` module.
+    Environment(EnvironmentMap),
+
+    /// A procedural sky with a sun, see the `daylight` module.
+    Daylight(Daylight),
+}
+
 pub struct Scene {
     pub camera: Camera,
 
     /// Bounding volume hierarchy of all triangles in the scene.
     bvh: Bvh,
 
-    /// Indices into the BVH's triangle list, of triangles that have a material
-    /// eligible for direct sampling.
-    direct_sample: Vec<u32>,
+    /// The triangles that have a material eligible for direct sampling.
+    emitters: Emitters,
+
+    /// Light sources without geometry.
+    lights: Vec<Light>,
+
+    /// What rays that escape the scene see.
+    sky: Sky,
+
+    /// Textures referenced by materials.
+    textures: TextureSet,
+
+    /// The bounding box of all triangles in the scene.
+    bounds: Aabb,
+
+    /// A medium that fills the bounding box of the scene, if any.
+    fog: Option<Medium>,
+
+    /// Media bounded by closed meshes.
+    volumes: Vec<Volume>,
+
+    /// Media with a density given by a voxel grid.
+    grid_volumes: Vec<GridVolume>,
+
+    /// The layers of layered materials, indexed by the material.
+    layered: Vec<Layered>,
 }
 
+/// Rays that do not hit any geometry are considered to hit the sky at this
+/// distance.
+pub const FAR_AWAY: f32 = 1.0e5;
+
 impl Scene {
     pub fn from_meshes(meshes: &[Mesh]) -> Scene {
         let bvh = Bvh::from_meshes(meshes);
+        let vertices: Vec<SVector3> = bvh.triangles
+            .iter()
+            .flat_map(|tri| vec![tri.v0, tri.v1, tri.v2])
+            .collect();
+        let bounds = Aabb::enclose_points(&vertices);
+
+        let mut scene = Scene {
+            camera: Camera::new(),
+            bvh: bvh,
+            emitters: Emitters::new(Vec::new(), &[]),
+            lights: Vec::new(),
+            sky: Sky::Daylight(Daylight::new(0.6, -1.2, 3.0)),
+            textures: TextureSet::new(),
+            bounds: bounds,
+            fog: None,
+            volumes: Vec::new(),
+            grid_volumes: Vec::new(),
+            layered: Vec::new(),
+        };
+        scene.build_emitters();
+        scene
+    }
+
+    /// Estimates the power emitted by a triangle, up to a constant factor.
+    ///
+    /// All emissive triangles currently use the sky material, which emits the
+    /// sky radiance in the direction of the ray that hits it. A window does not
+    /// know on which side the room is, so take the average luminance of the sky
+    /// in the two directions of the normal, and weigh that by the area.
+    fn estimate_power(&self, triangle: &Triangle) -> f32 {
+        let normal = MVector3::broadcast(triangle.normal());
+        let front = self.sky_radiance(normal);
+        let back = self.sky_radiance(-normal);
+        let luminance = |c: MVector3| 0.2126 * c.x.0 + 0.7152 * c.y.0 + 0.0722 * c.z.0;
+        let power = triangle.area() * 0.5 * (luminance(front) + luminance(back));
+
+        // A black sky is possible, but the alias table needs positive weights
+        // somewhere, so never go all the way to zero.
+        power.max(1e-6 * triangle.area())
+    }
+
+    /// Collects the triangles eligible for direct sampling and builds the
+    /// structures to sample them. This must be done again when the sky
+    /// changes, because that changes how much light the emitters emit.
+    fn build_emitters(&mut self) {
+        let emissive: Vec<Triangle> = self.bvh.triangles
+            .iter()
+            .filter(|tri| tri.material.is_direct_sample())
+            .cloned()
+            .collect();
+        let weights: Vec<f32> = emissive.iter().map(|tri| self.estimate_power(tri)).collect();
+        self.emitters = Emitters::new(emissive, &weights);
+    }
+
+    /// Marks the triangles that need an alpha test during intersection. The
+    /// texture of a layered material is that of its base, so this depends on
+    /// both the textures and the layers, and it must be done again when either
+    /// changes.
+    fn mark_cutouts(&mut self) {
+        let textures = &self.textures;
+        let layered = &self.layered;
+        for triangle in &mut self.bvh.triangles {
+            let material = match triangle.material.layered_index() {
+                Some(index) => layered.get(index as usize).and_then(|l| l.opaque_base()),
+                None => Some(triangle.material),
+            };
+            triangle.cutout = material.and_then(|m| m.texture()).and_then(|index| {
+                if textures.has_cutout(index) { Some(index) } else { None }
+            });
+        }
+    }
+
+    /// Sets the textures that the materials in the scene refer to.
+    pub fn set_textures(&mut self, textures: TextureSet) {
+        self.textures = textures;
+        self.mark_cutouts();
+    }
+
+    pub fn textures(&self) -> &TextureSet {
+        &self.textures
+    }
+
+    /// Sets the layers that layered materials refer to, a material created
+    /// with `SMaterial::layered(i)` uses element i.
+    pub fn set_layered(&mut self, layered: Vec<Layered>) {
+        self.layered = layered;
+        self.mark_cutouts();
+    }
+
+    pub fn layered(&self, index: u32) -> Layered {
+        *self.layered.get(index as usize).expect("layered index out of range")
+    }
+
+    /// Converts the width of a ray cone on the surface into the footprint for
+    /// sampling the texture with the given index. That is the width in texture
+    /// coordinate units, except for procedural textures in world space, which
+    /// are filtered over the width itself.
+    fn texture_footprint(&self, index: u32, width: f32, tex_density: f32) -> f32 {
+        if self.textures.is_world_space(index) { width } else { width * tex_density }
+    }
 
-        let mut direct_sample = Vec::new();
-        for i in 0..bvh.triangles.len() {
-            if bvh.triangles[i].material.is_direct_sample() {
-                direct_sample.push(i as u32);
+    /// Returns the material at the intersections, with the color of textured
+    /// materials replaced by the texel at the texture coordinates. The
+    /// footprint is the width of the ray cone projected onto the surface, in
+    /// units of length, it determines the mip level. If `procedural_only` is
+    /// set, image textures are left for the GPU, and only procedural textures
+    /// are applied.
+    pub fn apply_textures(&self,
+                          isect: &MIntersection,
+                          footprint: Mf32,
+                          procedural_only: bool)
+                          -> MMaterial {
+        let material = isect.material;
+
+        // Sampling is done serially, skip it if it is not needed.
+        if material.has_texture().all_sign_bits_positive() {
+            return material;
+        }
+        if procedural_only && !self.textures.has_procedural() {
+            return material;
+        }
+
+        let index = material.get_texture();
+        let color = material.get_color();
+        let colors = generate_slice8(|i| {
+            // The index is offset by one, 0 means no texture.
+            match index.get_coord(i) {
+                0 => (color.extract(i), 0.0),
+                k if procedural_only && !self.textures.is_procedural(k as u32 - 1) => {
+                    (color.extract(i), -1.0)
+                }
+                k => {
+                    let f = self.texture_footprint(k as u32 - 1,
+                                                   footprint.get_coord(i),
+                                                   isect.tex_density.get_coord(i));
+                    let c = self.textures.sample(k as u32 - 1,
+                                                 isect.tex_coords.0.get_coord(i),
+                                                 isect.tex_coords.1.get_coord(i),
+                                                 isect.position.extract(i),
+                                                 f);
+                    (c, 0.0)
+                }
             }
+        });
+
+        // Lanes that keep their texture for the GPU keep the original material.
+        let keep = Mf32::generate(|i| colors[i].1);
+        material.with_color(MVector3::generate(|i| colors[i].0)).pick(material, keep)
+    }
+
+    /// Applies the parameter maps of textured materials at the intersections.
+    ///
+    /// Returns the material with the glossiness and metalness from the maps,
+    /// the light that the surfaces emit, and a mask that has the sign bit set
+    /// where the path should pass through the surface. Fractional roughness,
+    /// metalness, and opacity are sampled stochastically: the material is
+    /// quantized to one of the neighboring values with a probability such that
+    /// the expected BRDF is the blend. If `ignore_texture` is set, the color
+    /// of textured materials is applied on the GPU later, so the emission is
+    /// computed for a white surface.
+    pub fn apply_parameter_maps(&self,
+                                material: MMaterial,
+                                isect: &MIntersection,
+                                footprint: Mf32,
+                                rng: &mut Rng,
+                                ignore_texture: bool)
+                                -> (MMaterial, MVector3, Mask) {
+        // The texture index must come from the intersection, `material` might
+        // have had its texture replaced by a color already.
+        let has_texture = isect.material.has_texture();
+        if !self.textures.has_parameter_maps() || has_texture.all_sign_bits_positive() {
+            return (material, MVector3::zero(), Mf32::zero());
         }
 
-        Scene {
-            camera: Camera::new(),
-            bvh: bvh,
-            direct_sample: direct_sample,
+        let index = isect.material.get_texture();
+        let gloss = material.get_glossiness();
+        let dielectric = material.is_dielectric();
+        let u_gloss = rng.sample_unit();
+        let u_metal = rng.sample_unit();
+        let u_opacity = rng.sample_unit();
+
+        // Per lane: the glossiness, the dielectric and transparent masks, and
+        // the emission strength.
+        let params = generate_slice8(|i| {
+            let mut g = gloss.get_coord(i);
+            let mut d = dielectric.get_coord(i);
+            let mut e = 0.0;
+            let mut t = 0.0;
+            let k = index.get_coord(i);
+            if k == 0 {
+                return (g, d, e, t);
+            }
+
+            let maps = self.textures.parameter_maps(k as u32 - 1);
+            let (u, v) = (isect.tex_coords.0.get_coord(i), isect.tex_coords.1.get_coord(i));
+            let p = isect.position.extract(i);
+            let width = footprint.get_coord(i);
+            let density = isect.tex_density.get_coord(i);
+            let f = |map: &ChannelMap| self.texture_footprint(map.texture, width, density);
+            if let Some(ref map) = maps.roughness {
+                let r = self.textures.sample_parameter(map, u, v, p, f(map)).max(0.0).min(1.0);
+                g = ((1.0 - r) * 5.0 + u_gloss.get_coord(i)).floor().min(5.0) as i32;
+            }
+            if let Some(ref map) = maps.metalness {
+                let m = self.textures.sample_parameter(map, u, v, p, f(map));
+                d = if u_metal.get_coord(i) >= m { -1.0 } else { 0.0 };
+            }
+            if let Some(ref map) = maps.emission {
+                e = self.textures.sample_parameter(map, u, v, p, f(map)).max(0.0);
+            }
+            // With a cutout the opacity was handled during intersection.
+            if let (Some(ref map), None) = (maps.opacity, maps.cutout) {
+                let o = self.textures.sample_parameter(map, u, v, p, f(map));
+                t = if u_opacity.get_coord(i) >= o { -1.0 } else { 0.0 };
+            }
+            (g, d, e, t)
+        });
+
+        let gloss = Mf32::generate(|i| params[i].0 as f32).into_mi32();
+        let dielectric = Mf32::generate(|i| params[i].1);
+        let strength = Mf32::generate(|i| params[i].2);
+        let transparent = Mf32::generate(|i| params[i].3);
+
+        let material = material.with_glossiness(gloss).with_dielectric(dielectric);
+        let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+        let color = if ignore_texture {
+            material.get_color().pick(white, material.has_texture())
+        } else {
+            material.get_color()
+        };
+        (material, color * strength, transparent)
+    }
+
+    /// Returns the shading normal at the intersections. For materials with a
+    /// texture that has a normal map attached, this is the normal perturbed
+    /// by the map, for other materials it is the geometric normal. The
+    /// footprint is as for `apply_textures()`.
+    pub fn apply_normal_maps(&self, ray: &MRay, isect: &MIntersection, footprint: Mf32) -> MVector3 {
+        let material = isect.material;
+
+        // Like texture sampling, this is done serially, so skip it if possible.
+        if !self.textures.has_normal_maps() || material.has_texture().all_sign_bits_positive() {
+            return isect.normal;
+        }
+
+        let index = material.get_texture();
+        let normals = generate_slice8(|i| {
+            let normal = isect.normal.extract(i);
+            match index.get_coord(i) {
+                0 => normal,
+                k => match self.textures.normal_map(k as u32 - 1) {
+                    None => normal,
+                    Some(normal_map) => {
+                        normal_map.perturb(normal,
+                                           isect.tangent.extract(i),
+                                           isect.bitangent.extract(i),
+                                           isect.tex_coords.0.get_coord(i),
+                                           isect.tex_coords.1.get_coord(i),
+                                           footprint.get_coord(i) * isect.tex_density.get_coord(i))
+                    }
+                },
+            }
+        });
+        let shading_normal = MVector3::generate(|i| normals[i]);
+
+        // A shading normal on the other side than the geometric normal, as
+        // seen from the viewer, would make the surface sample directions
+        // behind it, so fall back to the geometric normal there. The sign bit
+        // of the product is 1 if the two disagree.
+        let flipped = shading_normal.dot(ray.direction) * isect.normal.dot(ray.direction);
+        shading_normal.pick(isect.normal, flipped)
+    }
+
+    /// Replaces the sky with an environment map.
+    pub fn set_environment(&mut self, environment: EnvironmentMap) {
+        self.sky = Sky::Environment(environment);
+        self.build_emitters();
+    }
+
+    /// Replaces the sky with a daylight sky.
+    pub fn set_daylight(&mut self, daylight: Daylight) {
+        self.sky = Sky::Daylight(daylight);
+        self.build_emitters();
+    }
+
+    /// Moves the sun by the given angles in radians, if the sky is a daylight
+    /// sky. Returns the new elevation and azimuth.
+    pub fn adjust_sun(&mut self, delta_elevation: f32, delta_azimuth: f32) -> Option<(f32, f32)> {
+        let daylight = match self.sky {
+            Sky::Daylight(ref daylight) => {
+                // Keep the sun just above the horizon, the sky model breaks
+                // down below it.
+                let elevation = (daylight.elevation() + delta_elevation).max(0.02).min(0.5 * PI);
+                let azimuth = daylight.azimuth() + delta_azimuth;
+                Daylight::new(elevation, azimuth, daylight.turbidity())
+            }
+            Sky::Environment(..) => return None,
+        };
+        let result = (daylight.elevation(), daylight.azimuth());
+        self.set_daylight(daylight);
+        Some(result)
+    }
+
+    /// Returns the radiance of the sky in the given directions.
+    ///
+    /// For a daylight sky this excludes the sun, see `sun_radiance()`.
+    pub fn sky_radiance(&self, direction: MVector3) -> MVector3 {
+        match self.sky {
+            Sky::Environment(ref env) => env.radiance(direction),
+            Sky::Daylight(ref daylight) => daylight.sky_radiance(direction),
+        }
+    }
+
+    /// Returns whether the sky is an environment map that can be sampled
+    /// directly.
+    pub fn has_environment(&self) -> bool {
+        match self.sky {
+            Sky::Environment(..) => true,
+            Sky::Daylight(..) => false,
+        }
+    }
+
+    /// Samples 8 directions from the environment map, proportional to its
+    /// luminance. Returns the directions and their probability density.
+    ///
+    /// There must be an environment map.
+    pub fn sample_environment(&self, rng: &mut Rng) -> (MVector3, Mf32) {
+        match self.sky {
+            Sky::Environment(ref env) => env.sample(rng),
+            Sky::Daylight(..) => panic!("cannot sample without environment map"),
+        }
+    }
+
+    /// Returns the probability density of sampling the direction of the ray
+    /// from the environment map, for rays that escaped the scene after
+    /// travelling the given distance. For rays that hit geometry, the density
+    /// is zero.
+    pub fn pd_environment(&self, ray: &MRay, distance: Mf32) -> Mf32 {
+        match self.sky {
+            Sky::Environment(ref env) => {
+                let escaped = distance.geq(Mf32::broadcast(FAR_AWAY));
+                Mf32::zero().pick(env.pd(ray.direction), escaped)
+            }
+            Sky::Daylight(..) => Mf32::zero(),
+        }
+    }
+
+    /// Returns whether the sky has a sun that can be sampled directly.
+    pub fn has_sun(&self) -> bool {
+        match self.sky {
+            Sky::Environment(..) => false,
+            Sky::Daylight(..) => true,
+        }
+    }
+
+    /// Returns the radiance of the sun in the given directions, or zero if
+    /// there is no sun.
+    ///
+    /// Windows let the sun through, so this applies to every emissive
+    /// intersection, not only to rays that escaped.
+    pub fn sun_radiance(&self, direction: MVector3) -> MVector3 {
+        match self.sky {
+            Sky::Environment(..) => MVector3::zero(),
+            Sky::Daylight(ref daylight) => daylight.sun_radiance(direction),
+        }
+    }
+
+    /// Samples 8 directions towards the sun. Returns the directions and their
+    /// probability density.
+    ///
+    /// There must be a sun.
+    pub fn sample_sun(&self, rng: &mut Rng) -> (MVector3, Mf32) {
+        match self.sky {
+            Sky::Environment(..) => panic!("cannot sample sun without daylight sky"),
+            Sky::Daylight(ref daylight) => daylight.sample_sun(rng),
+        }
+    }
+
+    /// Returns the probability density of sampling the given directions
+    /// towards the sun, or zero if there is no sun.
+    pub fn pd_sun(&self, direction: MVector3) -> Mf32 {
+        match self.sky {
+            Sky::Environment(..) => Mf32::zero(),
+            Sky::Daylight(ref daylight) => daylight.pd_sun(direction),
         }
     }
 
@@ -133,84 +557,202 @@ impl Scene {
 
         println!("scene statistics:");
         println!("  triangles eligible for direct sampling: {} / {} ({:0.1}%)",
-                 self.direct_sample.len(),
+                 self.emitters.len(),
                  self.bvh.triangles.len(),
-                 100.0 * self.direct_sample.len() as f32 / self.bvh.triangles.len() as f32);
+                 100.0 * self.emitters.len() as f32 / self.bvh.triangles.len() as f32);
+        println!("  emitter sampling strategy: {:?}", self.emitters.sampling());
+        println!("  analytic lights: {}", self.lights.len());
+        println!("  textures: {}", self.textures.len());
+        println!("  volumes: {}", self.volumes.len());
+        println!("  voxel volumes: {}", self.grid_volumes.len());
+        println!("  layered materials: {}", self.layered.len());
+        match self.sky {
+            Sky::Environment(..) => println!("  sky: environment map"),
+            Sky::Daylight(ref daylight) => {
+                println!("  sky: daylight, sun at elevation {:.2}, azimuth {:.2}, turbidity {:.1}",
+                         daylight.elevation(),
+                         daylight.azimuth(),
+                         daylight.turbidity())
+            }
+        }
+    }
+
+    /// Adds a light source without geometry to the scene.
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    /// Returns the light sources without geometry.
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    /// Fills the bounding box of the scene with a medium, or removes the fog if
+    /// `None` is passed.
+    pub fn set_fog(&mut self, fog: Option<Medium>) {
+        self.fog = fog;
+    }
+
+    pub fn fog(&self) -> Option<Medium> {
+        self.fog
+    }
+
+    /// Adds a medium bounded by a closed mesh to the scene. The mesh is not
+    /// part of the geometry, it does not block rays.
+    pub fn add_volume(&mut self, volume: Volume) {
+        self.volumes.push(volume);
+    }
+
+    /// Adds a medium with a density given by a voxel grid to the scene.
+    pub fn add_grid_volume(&mut self, volume: GridVolume) {
+        self.grid_volumes.push(volume);
+    }
+
+    /// Returns whether the scene contains any participating media.
+    pub fn has_media(&self) -> bool {
+        self.fog.is_some() || !self.volumes.is_empty() || !self.grid_volumes.is_empty()
+    }
+
+    /// Returns the parts of the rays that lie inside every medium.
+    fn media_intervals(&self, ray: &MRay) -> Vec<MMediumInterval> {
+        let num_media = self.volumes.len() + self.grid_volumes.len() + 1;
+        let mut intervals = Vec::with_capacity(num_media);
+
+        if let Some(ref fog) = self.fog {
+            let (enter, exit) = self.bounds.intersect(ray).distances();
+            intervals.push(fog.interval(enter.max(Mf32::zero()), exit));
+        }
+
+        for volume in &self.volumes {
+            intervals.push(volume.interval(ray, FAR_AWAY));
+        }
+
+        for volume in &self.grid_volumes {
+            intervals.push(volume.interval(ray));
+        }
+
+        intervals
+    }
+
+    /// Returns the fraction of light that passes through the media along the
+    /// rays up to the given distance. Occlusion by geometry is not taken into
+    /// account. Through voxel volumes this is a random estimate.
+    pub fn transmittance(&self, ray: &MRay, distance: Mf32, rng: &mut Rng) -> Mf32 {
+        if !self.has_media() {
+            return Mf32::one();
+        }
+        medium::transmittance(&self.media_intervals(ray), distance, ray.active, rng)
+    }
+
+    /// Samples where the rays scatter in the media before they reach the given
+    /// distance, see `medium::sample_distance()`.
+    pub fn sample_medium(&self, ray: &MRay, distance: Mf32, rng: &mut Rng) -> MMediumSample {
+        if !self.has_media() {
+            return MMediumSample {
+                scattered: Mask::zero(),
+                distance: distance,
+                albedo: Mf32::one(),
+                anisotropy: Mf32::zero(),
+            };
+        }
+        medium::sample_distance(&self.media_intervals(ray), distance, ray.active, rng)
+    }
+
+    /// Returns whether there are any triangles eligible for direct sampling.
+    pub fn has_emitters(&self) -> bool {
+        self.emitters.len() > 0
     }
 
     /// Returns 8 random points on 8 random triangles eligible for direct
-    /// sampling.
-    pub fn get_direct_sample(&self, rng: &mut Rng) -> MDirectSample {
-        // The number of triangles eligible for direct sampling must be greater
-        // than 0, bute for good random number, I assume below that there are 8.
-        // This is the case for my hard-coded scene.
-        debug_assert!(self.direct_sample.len() == 8);
-
-        let random_bits = rng.sample_u32();
-
-        // Pick a random direct sampling triangle for every coordinate. This has
-        // to be done serially, unfortunately. The low order bits of the random
-        // number are not really random modulo 8, but the high order bits are.
-        // This has to do with how Rng works. In short, the sequence x*p^n is
-        // not random modulo 8, because p^n can take at most 4 values mod 8. And
-        // if you are unlucky, x = 0 mod 8, and then all indices are the same.
-        // Therefore take the high order bits, which are sufficiently random.
-        // TODO: Are the bounds checks a bottleneck here?
-        let indices = generate_slice8(|i| (random_bits[i] >> 29) as u32);
-        let tri_indices = generate_slice8(|i| self.direct_sample[indices[i] as usize]);
-        let tris = generate_slice8(|i| &self.bvh.triangles[tri_indices[i] as usize]);
-
-        // Gather the vertices of the triangles into SIMD vectors, so from now
-        // on we are not serial any more.
-        let v0 = MVector3::generate(|i| tris[i].v0);
-        let v1 = MVector3::generate(|i| tris[i].v1);
-        let v2 = MVector3::generate(|i| tris[i].v2);
-
-        let e1 = v0 - v2;
-        let e2 = v1 - v0;
-        let normal_denorm = e1.cross(e2);
-        let cross_norm_recip = normal_denorm.norm_squared().rsqrt();
-        let normal = normal_denorm * cross_norm_recip;
-        let area = Mf32::broadcast(0.5) * cross_norm_recip.recip_fast();
-
-        let u = rng.sample_unit();
-        let v = rng.sample_unit();
-        // If u + v > 1, the point lies outside of the triangle, and s will have
-        // negative sign. If the point is inside the triangle, s will have
-        // positive sign.
-        let s = (Mf32::one() - u) - v;
-        // If the point lies outside the triangle, it lies in the other half of
-        // the parallellogram, so transform the coordinates to get them into the
-        // correct triangle again.
-        let u = u.pick(Mf32::one() - u, s);
-        let v = v.pick(Mf32::one() - v, s);
-
-        let p = e2.mul_add(v, e1.neg_mul_add(u, v0));
-
-        let ds = MDirectSample {
-            position: p,
-            normal: normal,
-            area: area,
-        };
+    /// sampling, to be used to light the surfaces at the intersections.
+    ///
+    /// Triangles that are likely to contribute more light are picked more
+    /// often, see the `emitters` module for the details.
+    pub fn get_direct_sample(&self, isect: &MIntersection, rng: &mut Rng) -> MDirectSample {
+        self.emitters.sample(isect.position, isect.normal, rng)
+    }
 
-        // Prevent NaNs from creeping in, and ensure that the sample is valid.
-        debug_assert!(normal.all_finite());
-        debug_assert!(area.all_finite());
-        debug_assert!(area.all_sign_bits_positive(), "area must be positive");
+    /// Returns 8 random points on emissive triangles, picked proportional to
+    /// their power, to start paths from the light.
+    pub fn get_emitter_sample(&self, rng: &mut Rng) -> MDirectSample {
+        self.emitters.sample_by_power(rng)
+    }
 
-        ds
+    /// Returns the probability density for the direction of the given ray, for
+    /// the direct sampling distribution at the given intersections.
+    ///
+    /// Only emitters closer than the given distance are taken into account.
+    /// Direct samples further away are occluded by the shadow ray test.
+    pub fn pd_direct_sample(&self, isect: &MIntersection, ray: &MRay, distance: Mf32) -> Mf32 {
+        self.emitters.pd(isect.position, isect.normal, ray, distance)
     }
 
-    /// Returns the number of triangles eligible for direct sampling.
-    pub fn direct_sample_num(&self) -> usize {
-        self.direct_sample.len()
+    /// Returns whether anything lies on the rays within the given distance.
+    ///
+    /// The sign bit of the result is 1 if the ray is occluded, and 0 if it is
+    /// not.
+    pub fn is_occluded(&self, ray: &MRay, distance: Mf32) -> Mask {
+        // Stop just short of the target, otherwise the surface that the target
+        // lies on could occlude itself.
+        let max_distance = distance * Mf32::broadcast(0.999);
+        let unoccluded = MIntersection {
+            position: ray.direction.mul_add(max_distance, ray.origin),
+            normal: ray.direction,
+            distance: max_distance,
+            material: MMaterial::sky(),
+            tex_coords: (Mf32::zero(), Mf32::zero()),
+            tex_density: Mf32::zero(),
+            tangent: MVector3::zero(),
+            bitangent: MVector3::zero(),
+        };
+        let isect = self.bvh.intersect_nearest(ray, unoccluded, &self.textures);
+
+        // The intersection is only updated if something was hit closer by, in
+        // which case the difference is negative.
+        isect.distance - max_distance
     }
 
-    pub fn foreach_direct_sample<F: FnMut(&Triangle)>(&self, mut f: F) {
-        for i in &self.direct_sample {
-            // TODO: Remove the bounds check?
-            let triangle = &self.bvh.triangles[*i as usize];
-            f(triangle);
+    /// Returns whether anything opaque lies on the rays within the given
+    /// distance.
+    ///
+    /// Unlike `is_occluded()`, emissive triangles do not block the rays. Those
+    /// are windows that let the sky in, and the sun shines through them. The
+    /// sign bit of the result is 1 if the ray is occluded.
+    pub fn is_occluded_by_opaque(&self, ray: &MRay, distance: Mf32) -> Mask {
+        let mut ray = ray.clone();
+        let mut remaining = distance;
+        let mut occluded = Mask::zero();
+
+        loop {
+            let max_distance = remaining * Mf32::broadcast(0.999);
+            let unoccluded = MIntersection {
+                position: ray.direction.mul_add(max_distance, ray.origin),
+                normal: ray.direction,
+                distance: max_distance,
+                material: MMaterial::sky(),
+                tex_coords: (Mf32::zero(), Mf32::zero()),
+                tex_density: Mf32::zero(),
+                tangent: MVector3::zero(),
+                bitangent: MVector3::zero(),
+            };
+            let isect = self.bvh.intersect_nearest(&ray, unoccluded, &self.textures);
+
+            // The sign bit is 1 for rays that hit something before the target.
+            // That something is opaque unless the material is emissive.
+            let hit = isect.distance - max_distance;
+            let hit_opaque = hit.pick(Mask::zero(), isect.material);
+            occluded = occluded | hit_opaque.pick(Mask::zero(), ray.active);
+
+            // Rays that hit an emitter continue behind it, the others are done.
+            let continuing = (hit & isect.material).pick(Mask::zero(), ray.active);
+            if continuing.all_sign_bits_positive() {
+                return occluded;
+            }
+
+            let behind = ray.direction.mul_add(Mf32::epsilon(), isect.position);
+            ray.origin = ray.origin.pick(behind, continuing);
+            ray.active = Mask::ones().pick(Mask::zero(), continuing);
+            remaining = remaining.pick(remaining - isect.distance, continuing);
         }
     }
 
@@ -218,28 +760,57 @@ impl Scene {
     ///
     /// Intersects the sky if no other geometry was intersected.
     pub fn intersect_nearest(&self, ray: &MRay) -> MIntersection {
-        let huge_distance = Mf32::broadcast(1.0e5);
+        let huge_distance = Mf32::broadcast(FAR_AWAY);
         let far_away = MIntersection {
             position: ray.direction.mul_add(huge_distance, ray.origin),
             normal: ray.direction,
             distance: huge_distance,
             material: MMaterial::sky(),
             tex_coords: (Mf32::zero(), Mf32::zero()),
+            tex_density: Mf32::zero(),
+            tangent: MVector3::zero(),
+            bitangent: MVector3::zero(),
         };
-        self.bvh.intersect_nearest(ray, far_away)
+        self.bvh.intersect_nearest(ray, far_away, &self.textures)
     }
 
     /// Returns the number of AABBs and triangles intersected to find the
     /// nearest intersection.
     pub fn intersect_debug(&self, ray: &MRay) -> (u32, u32) {
-        let huge_distance = Mf32::broadcast(1.0e5);
+        let huge_distance = Mf32::broadcast(FAR_AWAY);
         let far_away = MIntersection {
             position: ray.direction.mul_add(huge_distance, ray.origin),
             normal: ray.direction,
             distance: huge_distance,
             material: MMaterial::sky(),
             tex_coords: (Mf32::zero(), Mf32::zero()),
+            tex_density: Mf32::zero(),
+            tangent: MVector3::zero(),
+            bitangent: MVector3::zero(),
         };
-        self.bvh.intersect_debug(ray, far_away)
+        self.bvh.intersect_debug(ray, far_away, &self.textures)
     }
 }
+
+#[test]
+fn layered_material_uses_cutout_of_its_base() {
+    use material::SMaterial;
+    use texture::{ParameterMaps, Texture};
+    use wavefront::quad;
+
+    let floor = quad(SVector3::zero(),
+                     SVector3::new(1.0, 0.0, 0.0),
+                     SVector3::new(0.0, 0.0, 1.0),
+                     SMaterial::layered(0));
+    let mut scene = Scene::from_meshes(&[floor]);
+
+    let mut textures = TextureSet::new();
+    let t = textures.insert("mask", Texture::from_linear(1, 1, 1, vec![0.0]));
+    textures.set_parameter_maps(t, ParameterMaps::new().with_cutout(ChannelMap::new(t, 0), 0.5));
+
+    // The layers are set after the textures, as in the demo scene, so the
+    // cutout must still be found then.
+    scene.set_textures(textures);
+    scene.set_layered(vec![Layered::opaque(SMaterial::white().with_texture(t))]);
+    assert!(scene.bvh.triangles.iter().all(|tri| tri.cutout == Some(t)));
+}
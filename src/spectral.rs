@@ -0,0 +1,215 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements spectral rendering.
+//!
+//! Normally the three channels of a color along a path are red, green, and
+//! blue, and light transport is done for those three channels independently.
+//! That is a decent approximation, but it is not how light works: the product
+//! of two RGB colors is not the RGB color of the product of two spectra. In
+//! spectral mode, every path carries three wavelengths instead, and the three
+//! channels hold the radiance at those wavelengths. One wavelength is sampled
+//! uniformly, the other two are offset by a third of the visible range, so the
+//! three cover the spectrum evenly. All of the existing code that multiplies
+//! colors componentwise remains correct, only the places where colors enter a
+//! path need to convert them.
+//!
+//! Materials and emitters are still specified in RGB. They are uplifted to a
+//! spectrum as a weighted sum of three smooth basis functions, one for every
+//! primary, that add up to one at every wavelength. So white stays flat, and
+//! reflectances between 0 and 1 stay between 0 and 1. At accumulation the
+//! radiance is projected back onto RGB with the CIE color matching functions
+//! and the sRGB primaries. The projection is corrected such that uplifting a
+//! color and projecting it back results in the same color, so a scene without
+//! interreflections looks the same in both modes.
+//!
+//! Because every channel is a single wavelength in spectral mode, effects that
+//! depend on the wavelength, like the dispersion of a refractive index, can be
+//! evaluated per channel, see `MChannels::wavelengths()`.
+
+use random::Rng;
+use simd::Mf32;
+use vector3::{MVector3, SVector3};
+
+/// The shortest wavelength that is sampled, in nanometers.
+pub const LAMBDA_MIN: f32 = 380.0;
+
+/// The longest wavelength that is sampled, in nanometers.
+pub const LAMBDA_MAX: f32 = 720.0;
+
+/// Tables of the basis functions and projection, at every nanometer.
+pub struct SpectralTables {
+    basis: Vec<SVector3>,
+    response: Vec<SVector3>,
+}
+
+/// What the three channels of the colors along 8 paths mean.
+#[derive(Copy, Clone)]
+pub enum MChannels<'a> {
+    /// Red, green, and blue.
+    Rgb,
+
+    /// Radiance at the given wavelengths, in nanometers.
+    Spectral(&'a SpectralTables, MVector3),
+}
+
+/// A piecewise Gaussian with a different width on both sides of the mean.
+fn gaussian(lambda: f32, mean: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+    let sigma = if lambda < mean { sigma_below } else { sigma_above };
+    let t = (lambda - mean) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// Returns the CIE 1931 color matching functions at the given wavelength,
+/// with the multi-lobe fit of Wyman, Sloan, and Shirley, "Simple Analytic
+/// Approximations to the CIE XYZ Color Matching Functions", 2013.
+fn cie_xyz(lambda: f32) -> SVector3 {
+    let x = 1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7) -
+            0.065 * gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8);
+    SVector3::new(x, y, z)
+}
+
+fn xyz_to_linear_srgb(xyz: SVector3) -> SVector3 {
+    SVector3::new(3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+                  -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+                  0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z)
+}
+
+/// Returns the basis functions for red, green, and blue at the wavelength.
+/// They are smooth steps around 490 and 590 nanometers, and they add up to 1.
+fn basis(lambda: f32) -> SVector3 {
+    let step = |center: f32| 1.0 / (1.0 + (-(lambda - center) / 12.0).exp());
+    let blue = 1.0 - step(490.0);
+    let red = step(590.0);
+    SVector3::new(red, 1.0 - red - blue, blue)
+}
+
+/// Inverts a 3x3 matrix given as rows.
+fn invert(m: [SVector3; 3]) -> [SVector3; 3] {
+    // The columns of the inverse are the cross products of the rows, divided
+    // by the determinant.
+    let c0 = m[1].cross(m[2]);
+    let c1 = m[2].cross(m[0]);
+    let c2 = m[0].cross(m[1]);
+    let det = m[0].dot(c0);
+    assert!(det.abs() > 1e-6, "matrix is singular");
+    let r = 1.0 / det;
+    [SVector3::new(c0.x, c1.x, c2.x) * r,
+     SVector3::new(c0.y, c1.y, c2.y) * r,
+     SVector3::new(c0.z, c1.z, c2.z) * r]
+}
+
+impl SpectralTables {
+    pub fn new() -> SpectralTables {
+        let n = (LAMBDA_MAX - LAMBDA_MIN) as usize + 1;
+        let lambdas: Vec<f32> = (0..n).map(|i| LAMBDA_MIN + i as f32).collect();
+        let basis: Vec<SVector3> = lambdas.iter().map(|&l| basis(l)).collect();
+        let cmf: Vec<SVector3> = lambdas.iter().map(|&l| xyz_to_linear_srgb(cie_xyz(l))).collect();
+
+        // Element (i, j) of the round trip matrix is the response of channel j
+        // to basis function i. The response is corrected with the inverse of
+        // its transpose, which makes the round trip the identity.
+        let mut round_trip = [SVector3::zero(); 3];
+        for (b, c) in basis.iter().zip(cmf.iter()) {
+            round_trip[0] = round_trip[0] + *c * b.x;
+            round_trip[1] = round_trip[1] + *c * b.y;
+            round_trip[2] = round_trip[2] + *c * b.z;
+        }
+        let inv = invert(round_trip);
+        let response = cmf.iter()
+            .map(|c| inv[0] * c.x + inv[1] * c.y + inv[2] * c.z)
+            .collect();
+
+        SpectralTables {
+            basis: basis,
+            response: response,
+        }
+    }
+
+    fn index(&self, lambda: f32) -> usize {
+        let i = (lambda - LAMBDA_MIN + 0.5).max(0.0) as usize;
+        i.min(self.basis.len() - 1)
+    }
+
+    /// Samples three wavelengths per path, spread evenly over the spectrum.
+    pub fn sample_wavelengths(rng: &mut Rng) -> MVector3 {
+        let u = rng.sample_unit();
+        let range = Mf32::broadcast(LAMBDA_MAX - LAMBDA_MIN);
+        let min = Mf32::broadcast(LAMBDA_MIN);
+        let at = |offset: f32| {
+            let v = (u + Mf32::broadcast(offset)).map(|x| if x >= 1.0 { x - 1.0 } else { x });
+            v.mul_add(range, min)
+        };
+        MVector3::new(at(0.0), at(1.0 / 3.0), at(2.0 / 3.0))
+    }
+
+    /// Returns the value of the spectra of the RGB colors at the wavelengths.
+    pub fn uplift(&self, rgb: MVector3, wavelengths: MVector3) -> MVector3 {
+        let at = |lambda: Mf32| {
+            Mf32::generate(|i| self.basis[self.index(lambda.get_coord(i))].dot(rgb.extract(i)))
+        };
+        MVector3::new(at(wavelengths.x), at(wavelengths.y), at(wavelengths.z))
+    }
+
+    /// Converts radiance at the wavelengths into linear RGB. This is an
+    /// estimate of the integral over the spectrum, so it is noisy.
+    pub fn to_rgb(&self, radiance: MVector3, wavelengths: MVector3) -> MVector3 {
+        let response = |lambda: Mf32| {
+            MVector3::generate(|i| self.response[self.index(lambda.get_coord(i))])
+        };
+        let sum = response(wavelengths.x) * radiance.x + response(wavelengths.y) * radiance.y +
+                  response(wavelengths.z) * radiance.z;
+        sum * Mf32::broadcast((LAMBDA_MAX - LAMBDA_MIN) / 3.0)
+    }
+}
+
+impl<'a> MChannels<'a> {
+    /// Converts RGB colors that enter a path into the channels of the path.
+    pub fn uplift(&self, rgb: MVector3) -> MVector3 {
+        match *self {
+            MChannels::Rgb => rgb,
+            MChannels::Spectral(tables, wavelengths) => tables.uplift(rgb, wavelengths),
+        }
+    }
+
+    /// Returns the wavelengths of the channels in nanometers, or `None` in RGB
+    /// mode.
+    pub fn wavelengths(&self) -> Option<MVector3> {
+        match *self {
+            MChannels::Rgb => None,
+            MChannels::Spectral(_, wavelengths) => Some(wavelengths),
+        }
+    }
+}
+
+#[test]
+fn basis_is_partition_of_unity() {
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        let b = basis(lambda);
+        assert!(b.x >= 0.0 && b.y >= 0.0 && b.z >= 0.0);
+        assert!((b.x + b.y + b.z - 1.0).abs() < 1e-5);
+        lambda += 5.0;
+    }
+}
+
+#[test]
+fn uplift_round_trips() {
+    let tables = SpectralTables::new();
+    let color = SVector3::new(0.8, 0.3, 0.1);
+
+    // Integrate at every nanometer rather than sampling.
+    let mut sum = SVector3::zero();
+    for i in 0..tables.basis.len() {
+        sum = sum + tables.response[i] * tables.basis[i].dot(color);
+    }
+    assert!((sum.x - color.x).abs() < 1e-3, "red is {}", sum.x);
+    assert!((sum.y - color.y).abs() < 1e-3, "green is {}", sum.y);
+    assert!((sum.z - color.z).abs() < 1e-3, "blue is {}", sum.z);
+}
@@ -0,0 +1,241 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements subsurface scattering with random walks.
+//!
+//! Light that hits a translucent surface like jade, wax, or marble does not
+//! bounce off the surface. It enters the material, scatters around inside,
+//! and leaves somewhere else. Rather than approximating this with a diffusion
+//! profile, I follow the light inside: the interior of the mesh is treated as
+//! a dense homogeneous medium, and a path takes random steps through it until
+//! it hits the boundary of the mesh again. There it leaves the surface as if
+//! it were reflected off a diffuse surface at that point. This is exact for
+//! any shape, thin parts glow and thick parts are dense, at the cost of many
+//! intersection tests for materials with a short mean free path.
+//!
+//! Light enters and leaves through a diffuse interface, there is no Fresnel
+//! reflection at the boundary. For that, put a coat on top, see the `layered`
+//! module.
+
+use material::MMaterial;
+use medium::sample_hg;
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::Scene;
+use simd::{Mask, Mf32};
+use spectral::MChannels;
+use vector3::{MVector3, SVector3};
+
+/// The number of scattering events after which a walk is given up. Walks that
+/// get that far inside carry very little light anyway.
+const MAX_STEPS: u32 = 256;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Subsurface {
+    /// The average distance between scattering events, in scene units.
+    pub mean_free_path: f32,
+
+    /// The fraction of light that survives a scattering event, per channel.
+    pub single_scattering_albedo: SVector3,
+}
+
+/// The result of random walks below the surface for 8 rays.
+pub struct MWalk {
+    /// Where the walks left the surface, with the outward normal there.
+    pub exit: MIntersection,
+
+    /// The product of the single-scattering albedos along the walks.
+    pub weight: MVector3,
+
+    /// The sign bit is 1 for walks that did not leave the surface within the
+    /// maximum number of steps, and for inactive lanes. Those are considered
+    /// absorbed.
+    pub absorbed: Mask,
+}
+
+/// Returns the single-scattering albedo that makes a semi-infinite medium
+/// appear to have the given albedo after multiple scattering. This is the
+/// approximation of van de Hulst, as used by Jensen et al. in "A Practical
+/// Model for Subsurface Light Transport".
+fn invert_albedo(albedo: f32) -> f32 {
+    let a = albedo.max(0.0).min(1.0);
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    1.0 - s * s
+}
+
+impl Subsurface {
+    /// Constructs a translucent material with the given mean free path and
+    /// color. The color is the albedo that the material appears to have, the
+    /// albedo of a single scattering event is derived from it.
+    pub fn new(mean_free_path: f32, albedo: SVector3) -> Subsurface {
+        assert!(mean_free_path > 0.0, "mean free path must be positive");
+        Subsurface {
+            mean_free_path: mean_free_path,
+            single_scattering_albedo: SVector3::new(invert_albedo(albedo.x),
+                                                    invert_albedo(albedo.y),
+                                                    invert_albedo(albedo.z)),
+        }
+    }
+
+    /// Translucent green jade, with a mean free path relative to an object of
+    /// about 10 units, like the Stanford bunny and dragon.
+    pub fn jade() -> Subsurface {
+        Subsurface::new(0.3, SVector3::new(0.31, 0.75, 0.45))
+    }
+
+    /// Pale yellow candle wax, for an object of about 10 units.
+    pub fn wax() -> Subsurface {
+        Subsurface::new(0.15, SVector3::new(0.95, 0.85, 0.6))
+    }
+
+    /// White marble, for an object of about 10 units.
+    pub fn marble() -> Subsurface {
+        Subsurface::new(0.08, SVector3::new(0.93, 0.92, 0.9))
+    }
+
+    /// Returns the material with the mean free path multiplied by the factor,
+    /// to use it for an object of a different size.
+    pub fn scaled(self, factor: f32) -> Subsurface {
+        assert!(factor > 0.0, "scale must be positive");
+        Subsurface { mean_free_path: self.mean_free_path * factor, ..self }
+    }
+}
+
+/// Follows random walks below the surface, for lanes where the sign bit of
+/// `active` is 0. The walks start at the intersection in a direction that
+/// points into the surface, and end where they hit any surface again. The
+/// layered material of the intersection determines the subsurface parameters.
+pub fn random_walk(scene: &Scene,
+                   isect: &MIntersection,
+                   material: MMaterial,
+                   active: Mask,
+                   rng: &mut Rng,
+                   channels: MChannels)
+                   -> MWalk {
+    let index = material.get_layered();
+    let is_active = |i: usize| !active.get_coord(i).is_sign_negative();
+    let params = |i: usize| {
+        scene.layered(index.get_coord(i) as u32).subsurface().expect("material is not translucent")
+    };
+    let mean_free_path = Mf32::generate(|i| {
+        if is_active(i) { params(i).mean_free_path } else { 1.0 }
+    });
+    let albedo = channels.uplift(MVector3::generate(|i| {
+        if is_active(i) { params(i).single_scattering_albedo } else { SVector3::zero() }
+    }));
+
+    // Enter the surface with a cosine-weighted direction, like a diffuse
+    // bounce, but on the inside.
+    let mut position = isect.position;
+    let mut direction = rng.sample_hemisphere_vector().rotate_hemisphere(-isect.normal);
+    let mut weight = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+    let mut exit = MIntersection::with_max_distance(0.0);
+    let mut left = Mask::zero();
+    let mut done = active;
+
+    for _ in 0..MAX_STEPS {
+        if done.all_sign_bits_negative() {
+            break;
+        }
+
+        let ray = MRay {
+            origin: direction.mul_add(Mf32::epsilon(), position),
+            direction: direction,
+            active: done,
+        };
+        let hit = scene.intersect_nearest(&ray);
+
+        let u = rng.sample_unit();
+        let distance = (Mf32::one() - u).map(|x| -x.ln()) * mean_free_path;
+
+        // The sign bit of distance - hit distance is 1 where the boundary is
+        // nearer than the next scattering event, so the walk leaves there.
+        let leaves = (distance - hit.distance).pick(Mask::zero(), done);
+        exit = exit.pick(&hit, leaves);
+        left = left | leaves;
+        done = done | leaves;
+
+        // The other walks scatter, isotropically.
+        position = direction.mul_add(distance, position).pick(position, done);
+        weight = weight.mul_coords(albedo).pick(weight, done);
+        direction = sample_hg(direction, Mf32::zero(), rng).pick(direction, done);
+    }
+
+    MWalk {
+        exit: exit,
+        weight: weight,
+        absorbed: left ^ Mask::ones(),
+    }
+}
+
+#[test]
+fn invert_albedo_is_monotonic_and_bounded() {
+    assert!(invert_albedo(0.0).abs() < 2e-3);
+    assert!((invert_albedo(1.0) - 1.0).abs() < 2e-3);
+    let mut prev = invert_albedo(0.0);
+    for i in 1..101 {
+        let a = invert_albedo(i as f32 * 0.01);
+        assert!(a >= prev);
+
+        // A single scattering event must absorb less than the multiple
+        // scattering that makes up the apparent color.
+        assert!(a >= i as f32 * 0.01 - 2e-3);
+        prev = a;
+    }
+}
+
+#[test]
+fn random_walk_exits_on_the_boundary() {
+    use layered::Layered;
+    use material::SMaterial;
+    use wavefront::quad;
+
+    // A unit cube made of translucent material.
+    let material = SMaterial::layered(0);
+    let o = SVector3::zero();
+    let x = SVector3::new(1.0, 0.0, 0.0);
+    let y = SVector3::new(0.0, 1.0, 0.0);
+    let z = SVector3::new(0.0, 0.0, 1.0);
+    let faces = [quad(o, x, z, material),
+                 quad(y, x, z, material),
+                 quad(o, y, z, material),
+                 quad(x, y, z, material),
+                 quad(o, x, y, material),
+                 quad(z, x, y, material)];
+    let mut scene = Scene::from_meshes(&faces);
+    let albedo = SVector3::new(0.9, 0.9, 0.9);
+    scene.set_layered(vec![Layered::translucent(Subsurface::new(0.2, albedo))]);
+
+    // Enter the cube through the middle of the top face.
+    let mut isect = MIntersection::with_max_distance(1.0);
+    isect.position = MVector3::broadcast(SVector3::new(0.5, 1.0, 0.5));
+    isect.normal = MVector3::broadcast(y);
+    isect.material = MMaterial::broadcast_material(material);
+
+    let mut rng = Rng::with_seed(7, 11, 13);
+    let mut num_left = 0;
+    for _ in 0..16 {
+        let walk = random_walk(&scene, &isect, isect.material, Mask::zero(), &mut rng, MChannels::Rgb);
+        for i in 0..8 {
+            if walk.absorbed.get_coord(i).is_sign_negative() {
+                continue;
+            }
+            num_left += 1;
+
+            // The exit lies on one of the faces, so one coordinate is 0 or 1,
+            // and all of them are inside the cube.
+            let p = walk.exit.position.extract(i);
+            let coords = [p.x, p.y, p.z];
+            assert!(coords.iter().all(|&c| c > -1e-3 && c < 1.0 + 1e-3), "exit {} outside cube", p);
+            let on_face = coords.iter().any(|&c| c.abs() < 1e-3 || (c - 1.0).abs() < 1e-3);
+            assert!(on_face, "exit {} not on the boundary", p);
+        }
+    }
+
+    // With this albedo and mean free path, walks should leave the cube.
+    assert!(num_left > 64);
+}
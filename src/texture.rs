@@ -0,0 +1,866 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module implements texture loading and sampling on the CPU.
+//!
+//! For the first bounce, textures are applied on the GPU in the gbuffer pass.
+//! That is cheap and it gives sharp textures even when there is little time
+//! to render, but it only works for the surface that the camera sees directly.
+//! Indirect light that bounces off a textured surface should be tinted by the
+//! texel at the bounce as well, and for that the renderer samples textures
+//! here. Texture coordinates wrap around, like the GPU sampler does.
+//!
+//! To avoid aliasing on surfaces far away or at grazing angles, every texture
+//! has a chain of mipmaps. The renderer tracks the footprint of a ray in
+//! texture space, and the sampler interpolates between the two mip levels
+//! whose texel size is closest to the footprint.
+//!
+//! Textures can have any size, and one to four channels: luminance, luminance
+//! and alpha, RGB, or RGBA. PNG, JPEG, and the other formats that imagefmt
+//! supports are assumed to be sRGB-encoded, Radiance HDR and OpenEXR files are
+//! linear. Materials refer to textures by index, the `TextureSet` maps names to
+//! indices.
+//!
+//! A texture in the set can have a normal map or a bump map attached to it,
+//! which perturbs the shading normal of every surface that uses the texture.
+//! These store data rather than colors, so they are loaded without sRGB
+//! decoding.
+//!
+//! Next to images, the set can hold procedural textures. Those are evaluated
+//! at the texture coordinates or the position of the intersection, and they
+//! are always sampled on the CPU.
+
+use exr::ExrImage;
+use hdr::HdrImage;
+use imagefmt::{self, ColFmt, ColType};
+use procedural::{Domain, Procedural};
+use std::collections::HashMap;
+use std::ops::{Add, Mul};
+use std::path::Path;
+use vector3::SVector3;
+
+/// One level of the mip chain.
+struct MipLevel {
+    width: u32,
+    height: u32,
+
+    /// Linear values, interleaved per pixel, row by row. The first row is at
+    /// texture coordinate v = 0, as on the GPU.
+    data: Vec<f32>,
+}
+
+pub struct Texture {
+    /// The number of channels per pixel, between 1 and 4.
+    channels: u32,
+
+    /// The full resolution image first, followed by levels with half the
+    /// width and height of the previous one, down to a single texel.
+    levels: Vec<MipLevel>,
+}
+
+/// A texture that perturbs the shading normal of a surface.
+pub enum NormalMap {
+    /// A normal in tangent space per texel, with the RGB channels encoding
+    /// (n + 1) / 2, as most tools export them. The blue channel points away
+    /// from the surface.
+    Tangent(Texture),
+
+    /// A height field in the first channel, of which the gradient tilts the
+    /// normal. The scale converts heights into texture coordinate units.
+    Bump(Texture, f32),
+}
+
+/// A single channel of a texture in a `TextureSet` that drives a scalar
+/// material parameter. Texel values in [0, 1] are mapped linearly onto the
+/// range [min, max].
+#[derive(Copy, Clone, Debug)]
+pub struct ChannelMap {
+    pub texture: u32,
+    pub channel: u32,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Textures that drive material parameters other than the color and the
+/// normal. Every parameter without a map keeps the value of the material.
+#[derive(Copy, Clone, Debug)]
+pub struct ParameterMaps {
+    /// Roughness, where 0 is the glossiest surface, and 1 fully diffuse.
+    pub roughness: Option<ChannelMap>,
+
+    /// Metalness, where 0 is a dielectric with a white highlight and 1 is a
+    /// metal that tints the highlight.
+    pub metalness: Option<ChannelMap>,
+
+    /// Emission strength, the surface emits its own color times this value.
+    pub emission: Option<ChannelMap>,
+
+    /// Opacity, where 0 is fully transparent and 1 fully opaque.
+    pub opacity: Option<ChannelMap>,
+
+    /// If set, the opacity map is an alpha mask with this threshold: where the
+    /// opacity is below it, the surface is cut out. Cutouts are handled during
+    /// intersection, so they also apply to shadow rays. Otherwise, opacity is
+    /// handled stochastically when shading.
+    pub cutout: Option<f32>,
+}
+
+/// The texels of a texture in a `TextureSet`.
+enum Source {
+    Image(Texture),
+    Procedural(Procedural),
+}
+
+/// A collection of textures that can be referred to by name.
+pub struct TextureSet {
+    textures: Vec<Source>,
+    indices: HashMap<String, u32>,
+
+    /// For every texture, an optional normal map applied along with it.
+    normal_maps: Vec<Option<NormalMap>>,
+
+    /// For every texture, maps for other material parameters.
+    parameter_maps: Vec<ParameterMaps>,
+}
+
+/// Converts an sRGB-encoded channel value to linear.
+fn srgb_to_linear(x: u8) -> f32 {
+    let x = x as f32 * (1.0 / 255.0);
+    if x <= 0.04045 {
+        x * (1.0 / 12.92)
+    } else {
+        ((x + 0.055) * (1.0 / 1.055)).powf(2.4)
+    }
+}
+
+impl MipLevel {
+    /// Builds the next level in the mip chain by averaging blocks of 2x2
+    /// texels. For odd sizes, the last row or column is dropped.
+    fn downsample(&self, channels: u32) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let n = channels as usize;
+        let mut data = Vec::with_capacity((width * height) as usize * n);
+
+        for y in 0..height {
+            for x in 0..width {
+                let y0 = (y * 2).min(self.height - 1);
+                let y1 = (y * 2 + 1).min(self.height - 1);
+                let x0 = (x * 2).min(self.width - 1);
+                let x1 = (x * 2 + 1).min(self.width - 1);
+                for c in 0..n {
+                    let at = |x: u32, y: u32| self.data[(y * self.width + x) as usize * n + c];
+                    data.push((at(x0, y0) + at(x1, y0) + at(x0, y1) + at(x1, y1)) * 0.25);
+                }
+            }
+        }
+
+        MipLevel {
+            width: width,
+            height: height,
+            data: data,
+        }
+    }
+}
+
+impl Texture {
+    /// Constructs a texture from linear values with the given number of
+    /// channels per pixel.
+    pub fn from_linear(width: u32, height: u32, channels: u32, data: Vec<f32>) -> Texture {
+        assert!(width > 0 && height > 0, "texture must not be empty");
+        assert!(channels >= 1 && channels <= 4, "texture must have 1 to 4 channels");
+        assert_eq!((width * height * channels) as usize, data.len());
+
+        let mut levels = vec![MipLevel {
+            width: width,
+            height: height,
+            data: data,
+        }];
+
+        while levels.last().map_or(false, |l| l.width > 1 || l.height > 1) {
+            let next = levels.last().unwrap().downsample(channels);
+            levels.push(next);
+        }
+
+        Texture {
+            channels: channels,
+            levels: levels,
+        }
+    }
+
+    /// Constructs a texture from 8-bit data with the given number of channels
+    /// per pixel. The color channels are sRGB-encoded, alpha is linear.
+    pub fn from_srgb8(width: u32, height: u32, channels: u32, data: &[u8]) -> Texture {
+        // There are only 256 distinct values, so build a lookup table rather
+        // than computing the power for every channel.
+        let mut table = [0.0f32; 256];
+        for (i, x) in table.iter_mut().enumerate() {
+            *x = srgb_to_linear(i as u8);
+        }
+
+        // With two or four channels, the last one is alpha.
+        let n = channels as usize;
+        let has_alpha = n % 2 == 0;
+        let linear = data.iter()
+            .enumerate()
+            .map(|(i, &x)| {
+                let is_alpha = has_alpha && i % n == n - 1;
+                if is_alpha { x as f32 * (1.0 / 255.0) } else { table[x as usize] }
+            })
+            .collect();
+
+        Texture::from_linear(width, height, channels, linear)
+    }
+
+    /// Constructs a texture from 8-bit data that is not a color, such as a
+    /// normal map or a height map. Values are mapped linearly onto [0, 1].
+    pub fn from_linear8(width: u32, height: u32, channels: u32, data: &[u8]) -> Texture {
+        let linear = data.iter().map(|&x| x as f32 * (1.0 / 255.0)).collect();
+        Texture::from_linear(width, height, channels, linear)
+    }
+
+    /// Loads a texture from a file. The format is determined by the extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Texture {
+        Texture::load_impl(path.as_ref(), true)
+    }
+
+    /// Loads a texture that contains data rather than colors from a file. This
+    /// is like `load()`, except that 8-bit formats are not sRGB-decoded.
+    pub fn load_linear<P: AsRef<Path>>(path: P) -> Texture {
+        Texture::load_impl(path.as_ref(), false)
+    }
+
+    fn load_impl(path: &Path, srgb: bool) -> Texture {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or(String::new());
+
+        match &extension[..] {
+            "exr" => {
+                let image = ExrImage::load(path);
+                Texture::from_linear(image.width, image.height, image.channels, image.data)
+            }
+            "hdr" | "pic" => {
+                let image = HdrImage::load(path);
+                let mut data = Vec::with_capacity(image.pixels.len() * 3);
+                for px in &image.pixels {
+                    data.push(px.x);
+                    data.push(px.y);
+                    data.push(px.z);
+                }
+                Texture::from_linear(image.width, image.height, 3, data)
+            }
+            _ => {
+                // Ask for the channels that the image has, but in a fixed order.
+                let info = imagefmt::read_info(path).expect("failed to read texture");
+                let (format, channels) = match info.ct {
+                    ColType::Gray => (ColFmt::Y, 1),
+                    ColType::GrayAlpha => (ColFmt::YA, 2),
+                    ColType::ColorAlpha => (ColFmt::RGBA, 4),
+                    _ => (ColFmt::RGB, 3),
+                };
+                let image = imagefmt::read(path, format).expect("failed to read texture");
+                let (w, h) = (image.w as u32, image.h as u32);
+                if srgb {
+                    Texture::from_srgb8(w, h, channels, &image.buf)
+                } else {
+                    Texture::from_linear8(w, h, channels, &image.buf)
+                }
+            }
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.levels[0].height
+    }
+
+    /// Returns the index into the data of a texel at the given mip level,
+    /// where the texel coordinates wrap around, also for negative coordinates.
+    fn texel_index(&self, level: usize, x: i32, y: i32) -> usize {
+        let level = &self.levels[level];
+        let w = level.width as i32;
+        let h = level.height as i32;
+        let x = ((x % w) + w) % w;
+        let y = ((y % h) + h) % h;
+        (y * w + x) as usize * self.channels as usize
+    }
+
+    /// Returns the RGB color of a texel at the given mip level.
+    fn texel(&self, level: usize, x: i32, y: i32) -> SVector3 {
+        let i = self.texel_index(level, x, y);
+        let data = &self.levels[level].data;
+
+        // Luminance is used for all three color channels.
+        if self.channels < 3 {
+            let l = data[i];
+            SVector3::new(l, l, l)
+        } else {
+            SVector3::new(data[i], data[i + 1], data[i + 2])
+        }
+    }
+
+    /// Returns one channel of a texel at the given mip level. If the texture
+    /// has fewer channels, the last one is used.
+    fn texel_channel(&self, level: usize, x: i32, y: i32, channel: u32) -> f32 {
+        let i = self.texel_index(level, x, y);
+        self.levels[level].data[i + channel.min(self.channels - 1) as usize]
+    }
+
+    /// Interpolates bilinearly between the four texels around the given
+    /// coordinates at the given mip level, fetched with `fetch`.
+    fn sample_level<T, F>(&self, level: usize, u: f32, v: f32, fetch: &F) -> T
+        where T: Add<Output = T> + Mul<f32, Output = T>,
+              F: Fn(usize, i32, i32) -> T
+    {
+        // Texel centers are at half-integer coordinates.
+        let x = u * self.levels[level].width as f32 - 0.5;
+        let y = v * self.levels[level].height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+        let (x0, y0) = (x0 as i32, y0 as i32);
+
+        let c00 = fetch(level, x0, y0);
+        let c10 = fetch(level, x0 + 1, y0);
+        let c01 = fetch(level, x0, y0 + 1);
+        let c11 = fetch(level, x0 + 1, y0 + 1);
+
+        let top = c00 * (1.0 - tx) + c10 * tx;
+        let bottom = c01 * (1.0 - tx) + c11 * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    /// Interpolates trilinearly between texels fetched with `fetch`, at the
+    /// two mip levels closest to the footprint.
+    fn sample_trilinear<T, F>(&self, u: f32, v: f32, footprint: f32, fetch: F) -> T
+        where T: Add<Output = T> + Mul<f32, Output = T>,
+              F: Fn(usize, i32, i32) -> T
+    {
+        // At level n a texel is 2^n texels of the full resolution image.
+        let size = self.width().max(self.height()) as f32;
+        let max_lod = (self.levels.len() - 1) as f32;
+        let lod = (footprint * size).log2().max(0.0).min(max_lod);
+        let level = lod.floor();
+        let t = lod - level;
+        let level = level as usize;
+
+        if t == 0.0 {
+            self.sample_level(level, u, v, &fetch)
+        } else {
+            self.sample_level(level, u, v, &fetch) * (1.0 - t) +
+            self.sample_level(level + 1, u, v, &fetch) * t
+        }
+    }
+
+    /// Samples the RGB color of the texture at the given coordinates with
+    /// trilinear filtering. The footprint is the size of the area to filter
+    /// over in texture coordinate units; a footprint of 0 samples the full
+    /// resolution image.
+    pub fn sample(&self, u: f32, v: f32, footprint: f32) -> SVector3 {
+        self.sample_trilinear(u, v, footprint, |level, x, y| self.texel(level, x, y))
+    }
+
+    /// Samples a single channel of the texture, like `sample()`.
+    pub fn sample_channel(&self, u: f32, v: f32, footprint: f32, channel: u32) -> f32 {
+        self.sample_trilinear(u, v, footprint, |level, x, y| self.texel_channel(level, x, y, channel))
+    }
+
+    /// Returns the shading normal for a surface with the given normal and
+    /// tangent space, perturbed by a normal map. The footprint is as for
+    /// `sample()`.
+    fn perturb_tangent(&self,
+                       normal: SVector3,
+                       tangent: SVector3,
+                       bitangent: SVector3,
+                       u: f32,
+                       v: f32,
+                       footprint: f32)
+                       -> SVector3 {
+        let n = self.sample(u, v, footprint) * 2.0 - SVector3::new(1.0, 1.0, 1.0);
+        let (t, b) = orthonormal_tangents(normal, tangent, bitangent);
+        (t * n.x + b * n.y + normal * n.z).normalized()
+    }
+
+    /// Returns the shading normal for a surface with the given normal and
+    /// tangent space, perturbed by the gradient of a height map. The gradient
+    /// is estimated with central differences, one mip level texel apart.
+    fn perturb_bump(&self,
+                    normal: SVector3,
+                    tangent: SVector3,
+                    bitangent: SVector3,
+                    u: f32,
+                    v: f32,
+                    footprint: f32,
+                    scale: f32)
+                    -> SVector3 {
+        let size = self.width().max(self.height()) as f32;
+        let d = footprint.max(1.0 / size);
+        let dh_du = (self.sample(u + d, v, footprint).x - self.sample(u - d, v, footprint).x) / (2.0 * d);
+        let dh_dv = (self.sample(u, v + d, footprint).x - self.sample(u, v - d, footprint).x) / (2.0 * d);
+        let (t, b) = orthonormal_tangents(normal, tangent, bitangent);
+        (normal - t * (dh_du * scale) - b * (dh_dv * scale)).normalized()
+    }
+
+    /// Returns the linear RGB values of all texels at full resolution, for
+    /// uploading to the GPU. The GPU generates its own mipmaps.
+    pub fn to_rgb(&self) -> Vec<f32> {
+        let mut rgb = Vec::with_capacity((self.width() * self.height() * 3) as usize);
+        for y in 0..self.height() as i32 {
+            for x in 0..self.width() as i32 {
+                let c = self.texel(0, x, y);
+                rgb.push(c.x);
+                rgb.push(c.y);
+                rgb.push(c.z);
+            }
+        }
+        rgb
+    }
+}
+
+/// Makes the tangent and bitangent perpendicular to the normal and to each
+/// other, but keeps the handedness of the texture mapping.
+fn orthonormal_tangents(normal: SVector3,
+                        tangent: SVector3,
+                        bitangent: SVector3)
+                        -> (SVector3, SVector3) {
+    let t = (tangent - normal * normal.dot(tangent)).normalized();
+    let b = normal.cross(t);
+    if b.dot(bitangent) < 0.0 { (t, -b) } else { (t, b) }
+}
+
+impl ChannelMap {
+    /// Maps the given channel of the texture at the given index onto [0, 1].
+    pub fn new(texture: u32, channel: u32) -> ChannelMap {
+        ChannelMap {
+            texture: texture,
+            channel: channel,
+            min: 0.0,
+            max: 1.0,
+        }
+    }
+
+    /// Sets the parameter values for texel values 0 and 1. The minimum may be
+    /// larger than the maximum, to invert the channel.
+    pub fn with_range(self, min: f32, max: f32) -> ChannelMap {
+        ChannelMap {
+            min: min,
+            max: max,
+            ..self
+        }
+    }
+}
+
+impl ParameterMaps {
+    pub fn new() -> ParameterMaps {
+        ParameterMaps {
+            roughness: None,
+            metalness: None,
+            emission: None,
+            opacity: None,
+            cutout: None,
+        }
+    }
+
+    pub fn with_roughness(self, map: ChannelMap) -> ParameterMaps {
+        ParameterMaps { roughness: Some(map), ..self }
+    }
+
+    pub fn with_metalness(self, map: ChannelMap) -> ParameterMaps {
+        ParameterMaps { metalness: Some(map), ..self }
+    }
+
+    pub fn with_emission(self, map: ChannelMap) -> ParameterMaps {
+        ParameterMaps { emission: Some(map), ..self }
+    }
+
+    pub fn with_opacity(self, map: ChannelMap) -> ParameterMaps {
+        ParameterMaps { opacity: Some(map), cutout: None, ..self }
+    }
+
+    /// Uses the map as an alpha mask, see `cutout`.
+    pub fn with_cutout(self, map: ChannelMap, threshold: f32) -> ParameterMaps {
+        ParameterMaps { opacity: Some(map), cutout: Some(threshold), ..self }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.roughness.is_none() && self.metalness.is_none() &&
+        self.emission.is_none() && self.opacity.is_none()
+    }
+}
+
+impl NormalMap {
+    /// Returns the shading normal at the given texture coordinates for a
+    /// surface with the given normal and tangent space. If there is no tangent
+    /// space, the normal is returned unchanged.
+    pub fn perturb(&self,
+                   normal: SVector3,
+                   tangent: SVector3,
+                   bitangent: SVector3,
+                   u: f32,
+                   v: f32,
+                   footprint: f32)
+                   -> SVector3 {
+        if tangent.norm_squared() == 0.0 {
+            return normal;
+        }
+
+        match *self {
+            NormalMap::Tangent(ref texture) => {
+                texture.perturb_tangent(normal, tangent, bitangent, u, v, footprint)
+            }
+            NormalMap::Bump(ref texture, scale) => {
+                texture.perturb_bump(normal, tangent, bitangent, u, v, footprint, scale)
+            }
+        }
+    }
+}
+
+impl TextureSet {
+    pub fn new() -> TextureSet {
+        TextureSet {
+            textures: Vec::new(),
+            indices: HashMap::new(),
+            normal_maps: Vec::new(),
+            parameter_maps: Vec::new(),
+        }
+    }
+
+    /// Adds a texture under the given name, and returns its index.
+    pub fn insert(&mut self, name: &str, texture: Texture) -> u32 {
+        self.insert_source(name, Source::Image(texture))
+    }
+
+    /// Adds a procedural texture under the given name, and returns its index.
+    pub fn insert_procedural(&mut self, name: &str, procedural: Procedural) -> u32 {
+        self.insert_source(name, Source::Procedural(procedural))
+    }
+
+    fn insert_source(&mut self, name: &str, source: Source) -> u32 {
+        assert!(!self.indices.contains_key(name), "texture '{}' already exists", name);
+        let index = self.textures.len() as u32;
+        self.textures.push(source);
+        self.normal_maps.push(None);
+        self.parameter_maps.push(ParameterMaps::new());
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+
+    /// Attaches a normal map or bump map to the texture with the given index.
+    pub fn set_normal_map(&mut self, index: u32, normal_map: NormalMap) {
+        self.normal_maps[index as usize] = Some(normal_map);
+    }
+
+    /// Returns the normal map attached to the texture with the given index,
+    /// if there is one.
+    pub fn normal_map(&self, index: u32) -> Option<&NormalMap> {
+        self.normal_maps.get(index as usize).and_then(|n| n.as_ref())
+    }
+
+    /// Returns whether any texture has a normal map attached.
+    pub fn has_normal_maps(&self) -> bool {
+        self.normal_maps.iter().any(|n| n.is_some())
+    }
+
+    /// Sets the maps for other material parameters of materials that use the
+    /// texture with the given index. The maps may refer to any texture in the
+    /// set, including the texture itself.
+    pub fn set_parameter_maps(&mut self, index: u32, maps: ParameterMaps) {
+        for map in [maps.roughness, maps.metalness, maps.emission, maps.opacity].iter() {
+            if let Some(map) = *map {
+                assert!((map.texture as usize) < self.textures.len(),
+                        "parameter map refers to missing texture");
+            }
+        }
+        self.parameter_maps[index as usize] = maps;
+    }
+
+    /// Returns the parameter maps for the texture with the given index.
+    pub fn parameter_maps(&self, index: u32) -> &ParameterMaps {
+        &self.parameter_maps[index as usize]
+    }
+
+    /// Returns whether any texture has parameter maps.
+    pub fn has_parameter_maps(&self) -> bool {
+        self.parameter_maps.iter().any(|m| !m.is_empty())
+    }
+
+    /// Returns whether materials with the texture at the given index have an
+    /// alpha mask.
+    pub fn has_cutout(&self, index: u32) -> bool {
+        self.parameter_maps.get(index as usize).map_or(false, |m| m.cutout.is_some())
+    }
+
+    /// Returns whether a surface with the texture at the given index is opaque
+    /// at the given texture coordinates or position, according to its alpha
+    /// mask. The full resolution mask is used, so cutouts are sharp.
+    pub fn is_opaque_at(&self, index: u32, u: f32, v: f32, position: SVector3) -> bool {
+        let maps = &self.parameter_maps[index as usize];
+        match (maps.opacity, maps.cutout) {
+            (Some(ref map), Some(threshold)) => {
+                self.sample_parameter(map, u, v, position, 0.0) >= threshold
+            }
+            _ => true,
+        }
+    }
+
+    /// Samples the RGB color of the texture with the given index. Image
+    /// textures are sampled at the texture coordinates, procedural textures
+    /// at the texture coordinates or at the position, depending on their
+    /// domain. The footprint is as for `Texture::sample()`, except for
+    /// textures in world space, where it is in units of length, see
+    /// `is_world_space()`.
+    pub fn sample(&self, index: u32, u: f32, v: f32, position: SVector3, footprint: f32) -> SVector3 {
+        match self.textures[index as usize] {
+            Source::Image(ref texture) => texture.sample(u, v, footprint),
+            Source::Procedural(ref procedural) => procedural.sample(u, v, position, footprint),
+        }
+    }
+
+    /// Samples a single channel of the texture with the given index, like
+    /// `sample()`.
+    pub fn sample_channel(&self,
+                          index: u32,
+                          u: f32,
+                          v: f32,
+                          position: SVector3,
+                          footprint: f32,
+                          channel: u32)
+                          -> f32 {
+        match self.textures[index as usize] {
+            Source::Image(ref texture) => texture.sample_channel(u, v, footprint, channel),
+            Source::Procedural(ref procedural) => {
+                procedural.sample_channel(u, v, position, footprint, channel)
+            }
+        }
+    }
+
+    /// Samples the parameter that the channel map drives.
+    pub fn sample_parameter(&self,
+                            map: &ChannelMap,
+                            u: f32,
+                            v: f32,
+                            position: SVector3,
+                            footprint: f32)
+                            -> f32 {
+        let x = self.sample_channel(map.texture, u, v, position, footprint, map.channel);
+        map.min + (map.max - map.min) * x
+    }
+
+    /// Loads a texture from a file and adds it under the given name. Returns
+    /// the index of the texture.
+    pub fn load<P: AsRef<Path>>(&mut self, name: &str, path: P) -> u32 {
+        self.insert(name, Texture::load(path))
+    }
+
+    /// Loads a texture that contains data rather than colors, see
+    /// `Texture::load_linear()`. Returns the index of the texture.
+    pub fn load_linear<P: AsRef<Path>>(&mut self, name: &str, path: P) -> u32 {
+        self.insert(name, Texture::load_linear(path))
+    }
+
+    /// Returns the index of the texture with the given name.
+    pub fn index(&self, name: &str) -> u32 {
+        match self.indices.get(name) {
+            Some(&index) => index,
+            None => panic!("no texture named '{}'", name),
+        }
+    }
+
+    /// Returns the image of the texture with the given index, or `None` if the
+    /// texture is procedural.
+    pub fn get(&self, index: u32) -> Option<&Texture> {
+        match self.textures.get(index as usize) {
+            Some(&Source::Image(ref texture)) => Some(texture),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the texture with the given index is procedural.
+    pub fn is_procedural(&self, index: u32) -> bool {
+        match self.textures.get(index as usize) {
+            Some(&Source::Procedural(..)) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the texture at the given index is a procedural texture
+    /// that is evaluated at the position rather than the texture coordinates.
+    pub fn is_world_space(&self, index: u32) -> bool {
+        match self.textures.get(index as usize) {
+            Some(&Source::Procedural(ref procedural)) => procedural.domain() == Domain::World,
+            _ => false,
+        }
+    }
+
+    /// Returns whether any texture in the set is procedural.
+    pub fn has_procedural(&self) -> bool {
+        (0..self.len() as u32).any(|i| self.is_procedural(i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+}
+
+#[test]
+fn texture_sample_interpolates_bilinearly() {
+    // A 2x1 texture, black on the left, white on the right.
+    let texture = Texture::from_srgb8(2, 1, 3, &[0, 0, 0, 255, 255, 255]);
+
+    // At the texel centers the exact texel value is returned.
+    assert_eq!(0.0, texture.sample(0.25, 0.5, 0.0).x);
+    assert!((texture.sample(0.75, 0.5, 0.0).x - 1.0).abs() < 1e-6);
+
+    // Halfway between the centers the values are averaged.
+    assert!((texture.sample(0.5, 0.5, 0.0).x - 0.5).abs() < 1e-6);
+
+    // At the edge it wraps around to the other side.
+    assert!((texture.sample(0.0, 0.5, 0.0).x - 0.5).abs() < 1e-6);
+    assert!((texture.sample(1.25, 0.5, 0.0).x).abs() < 1e-6);
+}
+
+#[test]
+fn texture_decodes_srgb() {
+    let texture = Texture::from_srgb8(1, 1, 3, &[0, 128, 255]);
+    let texel = texture.sample(0.5, 0.5, 0.0);
+    assert_eq!(0.0, texel.x);
+    assert!((texel.y - 0.2158).abs() < 1e-3);
+    assert!((texel.z - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn texture_expands_luminance_and_keeps_alpha_linear() {
+    let texture = Texture::from_srgb8(1, 1, 2, &[255, 128]);
+    assert_eq!(SVector3::new(1.0, 1.0, 1.0), texture.sample(0.5, 0.5, 0.0));
+    assert!((texture.levels[0].data[1] - 128.0 / 255.0).abs() < 1e-6);
+}
+
+#[test]
+fn texture_set_finds_textures_by_name() {
+    let mut set = TextureSet::new();
+    let a = set.insert("a", Texture::from_linear(1, 1, 1, vec![0.5]));
+    let b = set.insert("b", Texture::from_linear(1, 1, 1, vec![0.25]));
+    assert_eq!(a, set.index("a"));
+    assert_eq!(b, set.index("b"));
+    assert_eq!(0.25, set.get(b).unwrap().sample(0.5, 0.5, 0.0).x);
+}
+
+#[test]
+fn texture_builds_mip_chain() {
+    let texture = Texture::from_linear(4, 2, 1, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+    assert_eq!(3, texture.levels.len());
+    assert_eq!(&[2.5, 4.5], &texture.levels[1].data[..]);
+    assert_eq!(&[3.5], &texture.levels[2].data[..]);
+}
+
+#[test]
+fn texture_sample_interpolates_between_mip_levels() {
+    // A 2x2 checkerboard, which averages to 0.5 at the 1x1 level.
+    let texture = Texture::from_linear(2, 2, 1, vec![0.0, 1.0, 1.0, 0.0]);
+    assert_eq!(0.0, texture.sample(0.25, 0.25, 0.0).x);
+
+    // A footprint of one texel is still the full resolution level, a footprint
+    // of two texels is the next level, and in between levels are blended.
+    assert_eq!(0.0, texture.sample(0.25, 0.25, 0.5).x);
+    assert_eq!(0.5, texture.sample(0.25, 0.25, 1.0).x);
+    assert!((texture.sample(0.25, 0.25, 0.7071).x - 0.25).abs() < 1e-3);
+
+    // Beyond the smallest level, the footprint is clamped.
+    assert_eq!(0.5, texture.sample(0.25, 0.25, 100.0).x);
+}
+
+#[test]
+fn normal_map_flat_keeps_normal() {
+    // A flat normal map points straight out of the surface.
+    let texture = Texture::from_linear8(1, 1, 3, &[128, 128, 255]);
+    let normal_map = NormalMap::Tangent(texture);
+    let normal = SVector3::new(0.0, 0.0, 1.0);
+    let tangent = SVector3::new(1.0, 0.0, 0.0);
+    let bitangent = SVector3::new(0.0, 1.0, 0.0);
+    let n = normal_map.perturb(normal, tangent, bitangent, 0.5, 0.5, 0.0);
+    assert!((n - normal).norm_squared() < 1e-4);
+}
+
+#[test]
+fn bump_map_tilts_normal_down_the_slope() {
+    // A height that increases with u tilts the normal towards -u.
+    let texture = Texture::from_linear(4, 1, 1, vec![0.0, 0.25, 0.5, 0.75]);
+    let normal_map = NormalMap::Bump(texture, 0.1);
+    let normal = SVector3::new(0.0, 0.0, 1.0);
+    let tangent = SVector3::new(1.0, 0.0, 0.0);
+    let bitangent = SVector3::new(0.0, 1.0, 0.0);
+    let n = normal_map.perturb(normal, tangent, bitangent, 0.5, 0.5, 0.0);
+    assert!(n.x < 0.0);
+    assert!(n.y.abs() < 1e-6);
+    assert!((n.norm_squared() - 1.0).abs() < 1e-4);
+}
+
+#[test]
+fn texture_sample_channel_selects_channel() {
+    let texture = Texture::from_linear(1, 1, 2, vec![0.25, 0.75]);
+    assert_eq!(0.25, texture.sample_channel(0.5, 0.5, 0.0, 0));
+    assert_eq!(0.75, texture.sample_channel(0.5, 0.5, 0.0, 1));
+    // Channels beyond the last one clamp.
+    assert_eq!(0.75, texture.sample_channel(0.5, 0.5, 0.0, 3));
+}
+
+#[test]
+fn texture_set_maps_parameter_range() {
+    let mut set = TextureSet::new();
+    let t = set.insert("t", Texture::from_linear(1, 1, 1, vec![0.25]));
+    let map = ChannelMap::new(t, 0).with_range(1.0, 0.0);
+    set.set_parameter_maps(t, ParameterMaps::new().with_roughness(map));
+    assert!(set.has_parameter_maps());
+    let roughness = set.parameter_maps(t).roughness.unwrap();
+    assert_eq!(0.75, set.sample_parameter(&roughness, 0.5, 0.5, SVector3::zero(), 0.0));
+}
+
+#[test]
+fn texture_set_cutout_rejects_below_threshold() {
+    let mut set = TextureSet::new();
+    let t = set.insert("t", Texture::from_linear(2, 1, 2, vec![1.0, 0.0, 1.0, 1.0]));
+    assert!(!set.has_cutout(t));
+    set.set_parameter_maps(t, ParameterMaps::new().with_cutout(ChannelMap::new(t, 1), 0.5));
+    assert!(set.has_cutout(t));
+    assert!(!set.is_opaque_at(t, 0.25, 0.5, SVector3::zero()));
+    assert!(set.is_opaque_at(t, 0.75, 0.5, SVector3::zero()));
+}
+
+#[test]
+fn texture_set_samples_procedural_texture() {
+    use procedural::Pattern;
+
+    let mut set = TextureSet::new();
+    let image = set.insert("image", Texture::from_linear(1, 1, 1, vec![0.5]));
+    let checker = Procedural::new(Pattern::Checkerboard).in_world_space();
+    let t = set.insert_procedural("checker", checker);
+    assert!(!set.is_procedural(image));
+    assert!(set.is_procedural(t));
+    assert!(set.is_world_space(t));
+    assert!(!set.is_world_space(image));
+    assert!(set.get(t).is_none());
+
+    // The position selects the cell, the texture coordinates do not matter.
+    let p0 = SVector3::new(0.5, 0.5, 0.5);
+    let p1 = SVector3::new(1.5, 0.5, 0.5);
+    assert_eq!(0.0, set.sample_channel(t, 0.5, 0.5, p0, 0.0, 0));
+    assert_eq!(1.0, set.sample_channel(t, 0.5, 0.5, p1, 0.0, 0));
+
+    // Procedural textures can drive parameters like images can.
+    let map = ChannelMap::new(t, 0).with_range(0.2, 0.8);
+    assert_eq!(0.8, set.sample_parameter(&map, 0.0, 0.0, p1, 0.0));
+}
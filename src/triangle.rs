@@ -14,12 +14,31 @@
 
 use material::{SMaterial, MMaterial};
 use ray::{MIntersection, MRay};
-use simd::Mf32;
+use simd::{Mask, Mf32};
+use texture::TextureSet;
 use vector3::{MVector3, SVector3};
 
 #[cfg(test)]
 use {bench, test};
 
+/// Which sides of a triangle interact with light. The front is the side from
+/// which the vertices appear in counterclockwise order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sidedness {
+    /// Both sides reflect light alike. This is the default, it is what thin
+    /// surfaces modeled as a single plane, like walls, need.
+    TwoSided,
+
+    /// Only the front reflects light, the back is black. Inside a closed mesh
+    /// the back is never visible, unless the mesh has holes.
+    OneSided,
+
+    /// Rays pass through the back as if the triangle was not there. For closed
+    /// meshes this saves work, but rays that start inside, like the random
+    /// walks of translucent materials, do not see the mesh at all.
+    Culled,
+}
+
 #[derive(Clone, Debug)]
 pub struct Triangle {
     pub v0: SVector3,
@@ -29,6 +48,24 @@ pub struct Triangle {
     pub uv1: (f32, f32),
     pub uv2: (f32, f32),
     pub material: SMaterial,
+
+    /// Texture coordinate units per unit of length on the triangle: the
+    /// square root of the ratio of the area in texture space to the area in
+    /// world space. This is used to select a mipmap level.
+    pub tex_density: f32,
+
+    /// The directions in which the texture coordinates u and v increase, in
+    /// the plane of the triangle. These span the tangent space for normal
+    /// mapping. They are zero if the triangle has no texture coordinates.
+    pub tangent: SVector3,
+    pub bitangent: SVector3,
+
+    /// The texture with an alpha mask that cuts out parts of the triangle, if
+    /// there is one. Intersections with those parts are rejected. For a
+    /// layered material this is the texture of the base.
+    pub cutout: Option<u32>,
+
+    pub sidedness: Sidedness,
 }
 
 /// The result of intersecting a triangle to compute a probability density.
@@ -49,6 +86,41 @@ impl Triangle {
             uv1: (0.0, 0.0),
             uv2: (0.0, 0.0),
             material: mat,
+            tex_density: 0.0,
+            tangent: SVector3::zero(),
+            bitangent: SVector3::zero(),
+            cutout: None,
+            sidedness: Sidedness::TwoSided,
+        }
+    }
+
+    /// Sets the texture coordinates at the vertices.
+    pub fn set_tex_coords(&mut self, uv0: (f32, f32), uv1: (f32, f32), uv2: (f32, f32)) {
+        self.uv0 = uv0;
+        self.uv1 = uv1;
+        self.uv2 = uv2;
+
+        let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+        let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+        let tex_area = 0.5 * (du1 * dv2 - du2 * dv1).abs();
+        let area = self.area();
+        self.tex_density = if area > 0.0 { (tex_area / area).sqrt() } else { 0.0 };
+
+        // Solve e1 = du1 * T + dv1 * B and e2 = du2 * T + dv2 * B for the
+        // tangent T and bitangent B. If the texture coordinates are degenerate
+        // there is no tangent space.
+        let det = du1 * dv2 - du2 * dv1;
+        if det != 0.0 {
+            let e1 = self.v1 - self.v0;
+            let e2 = self.v2 - self.v0;
+            let r = 1.0 / det;
+            self.tangent = ((e1 * dv2) - (e2 * dv1)) * r;
+            self.bitangent = ((e2 * du1) - (e1 * du2)) * r;
+            self.tangent = self.tangent.normalized();
+            self.bitangent = self.bitangent.normalized();
+        } else {
+            self.tangent = SVector3::zero();
+            self.bitangent = SVector3::zero();
         }
     }
 
@@ -56,7 +128,61 @@ impl Triangle {
         (self.v0 + self.v1 + self.v2) * 3.0f32.recip()
     }
 
+    /// Returns the normal of the triangle, as determined by the winding order.
+    pub fn normal(&self) -> SVector3 {
+        let e1 = self.v0 - self.v2;
+        let e2 = self.v1 - self.v0;
+        e1.cross(e2).normalized()
+    }
+
+    /// Returns the surface area of the triangle.
+    pub fn area(&self) -> f32 {
+        let e1 = self.v0 - self.v2;
+        let e2 = self.v1 - self.v0;
+        0.5 * e1.cross(e2).norm_squared().sqrt()
+    }
+
     pub fn intersect(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
+        let (new_isect, reject) = self.intersect_candidate(ray, &isect);
+
+        // Per ray, pick the new intersection if it is closer and if it was
+        // indeed an intersection of the triangle, or pick the previous
+        // intersection otherwise.
+        new_isect.pick(&isect, reject)
+    }
+
+    /// Intersects the triangle like `intersect()`, but rejects intersections
+    /// where the alpha mask of the texture cuts out the triangle. This is only
+    /// needed if `cutout` is set.
+    pub fn intersect_cutout(&self,
+                            ray: &MRay,
+                            isect: MIntersection,
+                            textures: &TextureSet)
+                            -> MIntersection {
+        let (new_isect, reject) = self.intersect_candidate(ray, &isect);
+
+        // Looking up the alpha mask is done serially, and it is not cheap, so
+        // only do it for rays that actually hit the triangle.
+        if reject.all_sign_bits_negative() {
+            return isect;
+        }
+
+        let index = self.cutout.expect("triangle must have a cutout");
+        let cut_out = Mf32::generate(|i| {
+            let hit = !reject.get_coord(i).is_sign_negative();
+            let (u, v) = (new_isect.tex_coords.0.get_coord(i), new_isect.tex_coords.1.get_coord(i));
+            let p = new_isect.position.extract(i);
+            if hit && !textures.is_opaque_at(index, u, v, p) { -1.0 } else { 0.0 }
+        });
+
+        new_isect.pick(&isect, reject | cut_out)
+    }
+
+    /// Computes the intersections with the triangle. Returns the intersections
+    /// and a mask with the sign bit set for the rays that did not hit the
+    /// triangle, or that hit it further away than the existing intersection.
+    #[inline(always)]
+    fn intersect_candidate(&self, ray: &MRay, isect: &MIntersection) -> (MIntersection, Mask) {
         // One would expect that if the triangle were represented as
         // (v0, e1, e2) instead of (v0, v1, v2), that would be faster because we
         // could avoid the subtractions here. My measurements show that the
@@ -126,6 +252,19 @@ impl Triangle {
         // means discard intersection.)
         let mask_closer = t.geq(isect.distance);
 
+        // The denominator is negative where the ray hits the front, so its
+        // sign bit is 0 where the ray hits the back.
+        let back = denom ^ Mask::ones();
+        let material = MMaterial::broadcast_material(self.material);
+        let (material, mask_culled) = match self.sidedness {
+            Sidedness::TwoSided => (material, Mask::zero()),
+            Sidedness::OneSided => {
+                let black = MMaterial::broadcast_material(SMaterial::diffuse(0.0, 0.0, 0.0));
+                (material.pick(black, back), Mask::zero())
+            }
+            Sidedness::Culled => (material, back),
+        };
+
         // Interpolate the texture coordinates.
         let (tx0x, tx0y) = (Mf32::broadcast(self.uv0.0), Mf32::broadcast(self.uv0.1));
         let (tx1x, tx1y) = (Mf32::broadcast(self.uv1.0), Mf32::broadcast(self.uv1.1));
@@ -137,14 +276,14 @@ impl Triangle {
             position: ray.direction.mul_add(t, ray.origin),
             normal: normal_denorm.normalized(),
             distance: t,
-            material: MMaterial::broadcast_material(self.material),
+            material: material,
             tex_coords: (tex_x, tex_y),
+            tex_density: Mf32::broadcast(self.tex_density),
+            tangent: MVector3::broadcast(self.tangent),
+            bitangent: MVector3::broadcast(self.bitangent),
         };
 
-        // Per ray, pick the new intersection if it is closer and if it was
-        // indeed an intersection of the triangle, or pick the previous
-        // intersection otherwise.
-        new_isect.pick(&isect, mask_positive | (ray.active | mask_closer))
+        (new_isect, (mask_positive | mask_culled) | (ray.active | mask_closer))
     }
 
     /// Intersects the triangle to determine the probability density for the
@@ -222,6 +361,70 @@ fn intersect_triangle() {
     assert!(should_be_zero.0 < 0.01);
 }
 
+#[test]
+fn intersect_triangle_cutout() {
+    use ray::SRay;
+    use texture::{ChannelMap, ParameterMaps, Texture};
+
+    // A texture that is transparent on the left half.
+    let mut textures = TextureSet::new();
+    let t = textures.insert("mask", Texture::from_linear(2, 1, 1, vec![0.0, 1.0]));
+    textures.set_parameter_maps(t, ParameterMaps::new().with_cutout(ChannelMap::new(t, 0), 0.5));
+
+    let mut triangle = Triangle::new(
+        SVector3::new(0.0, 1.0, 1.0),
+        SVector3::new(-1.0, -1.0, 1.0),
+        SVector3::new(1.0, -1.0, 1.0),
+        SMaterial::white().with_texture(t),
+    );
+    triangle.set_tex_coords((0.5, 1.0), (0.0, 0.0), (1.0, 0.0));
+    triangle.cutout = Some(t);
+
+    let left = SRay::new(SVector3::new(-0.5, -0.5, 0.0), SVector3::new(0.0, 0.0, 1.0));
+    let right = SRay::new(SVector3::new(0.5, -0.5, 0.0), SVector3::new(0.0, 0.0, 1.0));
+    let ray = MRay::generate(|i| if i % 2 == 0 { left.clone() } else { right.clone() });
+
+    let isect_far = MIntersection::with_max_distance(1e5);
+    let isect = triangle.intersect_cutout(&ray, isect_far, &textures);
+    assert_eq!(isect.distance.0, 1e5);
+    assert!((isect.distance.1 - 1.0).abs() < 0.01);
+}
+
+#[test]
+fn intersect_triangle_sidedness() {
+    use ray::SRay;
+    use std::mem::transmute;
+
+    let mut triangle = Triangle::new(
+        SVector3::new(0.0, 1.0, 1.0),
+        SVector3::new(-1.0, -1.0, 1.0),
+        SVector3::new(1.0, -1.0, 1.0),
+        SMaterial::white(),
+    );
+
+    // The front of the triangle faces positive z.
+    let front = SRay::new(SVector3::new(0.0, 0.0, 2.0), SVector3::new(0.0, 0.0, -1.0));
+    let back = SRay::new(SVector3::new(0.0, 0.0, 0.0), SVector3::new(0.0, 0.0, 1.0));
+    let ray = MRay::generate(|i| if i % 2 == 0 { front.clone() } else { back.clone() });
+    let bits = |x: f32| -> u32 { unsafe { transmute(x) } };
+    let white = bits(MMaterial::broadcast_material(SMaterial::white()).0);
+
+    let isect = triangle.intersect(&ray, MIntersection::with_max_distance(1e5));
+    assert!(isect.normal.z.0 > 0.0);
+    assert!((isect.distance.1 - 1.0).abs() < 0.01);
+    assert_eq!(bits(isect.material.1), white);
+
+    triangle.sidedness = Sidedness::OneSided;
+    let isect = triangle.intersect(&ray, MIntersection::with_max_distance(1e5));
+    assert_eq!(bits(isect.material.0), white);
+    assert!(bits(isect.material.1) != white);
+
+    triangle.sidedness = Sidedness::Culled;
+    let isect = triangle.intersect(&ray, MIntersection::with_max_distance(1e5));
+    assert!((isect.distance.0 - 1.0).abs() < 0.01);
+    assert_eq!(isect.distance.1, 1e5);
+}
+
 #[test]
 fn intersect_triangle_direct() {
     use ray::SRay;
@@ -303,3 +506,31 @@ fn bench_intersect_direct_8_tris_per_mray(b: &mut test::Bencher) {
         }
     });
 }
+
+#[test]
+fn set_tex_coords_computes_density() {
+    // A right triangle with legs of length 2, mapped to a right triangle with
+    // legs of length 1 in texture space.
+    let mut triangle = Triangle::new(
+        SVector3::new(0.0, 0.0, 0.0),
+        SVector3::new(2.0, 0.0, 0.0),
+        SVector3::new(0.0, 2.0, 0.0),
+        SMaterial::white(),
+    );
+    triangle.set_tex_coords((0.0, 0.0), (1.0, 0.0), (0.0, 1.0));
+    assert!((triangle.tex_density - 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn set_tex_coords_computes_tangents() {
+    // Texture coordinates that run in the -y and x directions.
+    let mut triangle = Triangle::new(
+        SVector3::new(0.0, 0.0, 0.0),
+        SVector3::new(0.0, -1.0, 0.0),
+        SVector3::new(1.0, 0.0, 0.0),
+        SMaterial::white(),
+    );
+    triangle.set_tex_coords((0.0, 0.0), (1.0, 0.0), (0.0, 1.0));
+    assert_eq!(SVector3::new(0.0, -1.0, 0.0), triangle.tangent);
+    assert_eq!(SVector3::new(1.0, 0.0, 0.0), triangle.bitangent);
+}
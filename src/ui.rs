@@ -14,8 +14,10 @@ use glium::backend::Facade;
 use glium::backend::glutin_backend::GlutinFacade;
 use glium::glutin::{Event, WindowBuilder};
 use glium::index::{NoIndices, PrimitiveType};
-use glium::texture::{MipmapsOption, RawImage2d, SrgbTexture2d, Texture2d};
+use glium::uniforms::{MinifySamplerFilter, SamplerWrapFunction};
+use glium::texture::{MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
 use stats::GlobalStats;
+use texture::Texture;
 use std::str;
 use time::PreciseTime;
 
@@ -166,23 +168,33 @@ impl FullScreenQuad {
     }
 
     /// Applies the gbuffer shader for texture filtering.
+    ///
+    /// There is one pass per texture, each pass only touches the pixels that
+    /// use that texture. The other pixels are copied from the frame first.
     pub fn draw_gbuffer<S: Surface>(&self,
                                     target: &mut S,
                                     frame: &Texture2d,
                                     gbuffer: &Texture2d,
-                                    textures: &[SrgbTexture2d]) {
-        let uniforms = uniform! {
-            frame: frame,
-            gbuffer: gbuffer,
-            texture1: &textures[0],
-            texture2: &textures[1],
-        };
-        target.draw(&self.vertex_buffer,
-                  &self.indices,
-                  &self.program_gbuffer,
-                  &uniforms,
-                  &Default::default())
-            .expect("failed to draw quad");
+                                    textures: &[Texture2d]) {
+        self.draw_id(target, frame);
+
+        for (i, texture) in textures.iter().enumerate() {
+            let uniforms = uniform! {
+                frame: frame,
+                gbuffer: gbuffer,
+                surface_texture: texture.sampled()
+                    .minify_filter(MinifySamplerFilter::LinearMipmapLinear)
+                    .wrap_function(SamplerWrapFunction::Repeat),
+                // Index 0 in the gbuffer means no texture.
+                texture_index: (i + 1) as f32,
+            };
+            target.draw(&self.vertex_buffer,
+                      &self.indices,
+                      &self.program_gbuffer,
+                      &uniforms,
+                      &Default::default())
+                .expect("failed to draw quad");
+        }
     }
 
     /// Applies a median filter to the source and draws that to the target.
@@ -210,7 +222,7 @@ pub struct Window {
     frames: [Texture2d; 8],
     scratch: Texture2d,
     gbuffer_texture: Texture2d,
-    textures: Vec<SrgbTexture2d>,
+    textures: Vec<Texture2d>,
     frame_index: u32,
     enable_blend: bool,
     enable_median: bool,
@@ -219,12 +231,19 @@ pub struct Window {
 }
 
 pub enum Action {
+    AdjustMaxBounces(i32),
     DumpTrace,
+    MoveSun(f32, f32),
+    NextIntegrator,
     None,
     PrintStats,
     Quit,
-    ToggleDebugView,
+    ToggleClampFireflies,
+    ToggleFog,
+    ToggleMisHeuristic,
+    ToggleOutlierRejection,
     ToggleRealtime,
+    ToggleSpectral,
 }
 
 fn black_bitmap(width: u32, height: u32) -> Vec<u8> {
@@ -255,7 +274,7 @@ impl Window {
         let scratch = Texture2d::empty(&display, width, height)
             .expect("failed to create scratch texture");
 
-        let gbuffer_tex = Texture2d::empty(&display, width, height)
+        let gbuffer_tex = Texture2d::empty(&display, width, height * 2)
             .expect("failed to create scratch texture");
 
         let mut window = Window {
@@ -290,6 +309,10 @@ impl Window {
 
     fn upload_frame(&mut self, bitmap: Vec<u8>) -> Texture2d {
         let dimensions = (self.width, self.height);
+        self.upload_bitmap(bitmap, dimensions)
+    }
+
+    fn upload_bitmap(&mut self, bitmap: Vec<u8>, dimensions: (u32, u32)) -> Texture2d {
         let texture_data = RawImage2d::from_raw_rgba(bitmap, dimensions);
         let texture = Texture2d::with_mipmaps(&self.display, texture_data, MipmapsOption::NoMipmap)
             .expect("failed to create texture");
@@ -297,15 +320,20 @@ impl Window {
     }
 
     /// Uploads a texture to the GPU. This is intended for the textures that are
-    /// used for the scene, not the full-screen rendered frames. Texture
-    /// dimensions must be 1024 x 1024.
-    pub fn upload_texture(&mut self, bitmap: Vec<u8>) {
-        assert_eq!(bitmap.len(), 1024 * 1024 * 3);
-
-        let texture_data = RawImage2d::from_raw_rgb(bitmap, (1024, 1024));
-        let texture =
-            SrgbTexture2d::with_mipmaps(&self.display, texture_data, MipmapsOption::NoMipmap)
-                .expect("failed to create texture");
+    /// used for the scene, not the full-screen rendered frames. Textures must
+    /// be uploaded in the order of their index.
+    pub fn upload_texture(&mut self, texture: &Texture) {
+        let dimensions = (texture.width(), texture.height());
+        let texture_data = RawImage2d::from_raw_rgb(texture.to_rgb(), dimensions);
+
+        // The values are linear and possibly high dynamic range, so store them
+        // as floats rather than sRGB bytes. The gbuffer shader selects a mip
+        // level based on the texture footprint that the renderer computed.
+        let texture = Texture2d::with_format(&self.display,
+                                             texture_data,
+                                             UncompressedFloatFormat::F16F16F16,
+                                             MipmapsOption::AutoGeneratedMipmaps)
+            .expect("failed to create texture");
 
         self.textures.push(texture);
     }
@@ -322,7 +350,8 @@ impl Window {
         // Upload the render result to the GPU. It is not yet correct, it needs
         // a gbuffer pass to add the textures.
         self.scratch = self.upload_frame(rgba_buffer);
-        self.gbuffer_texture = self.upload_frame(gbuffer);
+        let gbuffer_dimensions = (self.width, self.height * 2);
+        self.gbuffer_texture = self.upload_bitmap(gbuffer, gbuffer_dimensions);
 
         // TODO: Fix timers and trace here.
 
@@ -369,12 +398,29 @@ impl Window {
             match ev {
                 // Window was closed by the user.
                 Event::Closed => return Action::Quit,
+                // The user pressed ',' or '.' to change the path depth.
+                Event::ReceivedCharacter(',') => return Action::AdjustMaxBounces(-1),
+                Event::ReceivedCharacter('.') => return Action::AdjustMaxBounces(1),
+                // The user pressed '[' or ']' to rotate the sun.
+                Event::ReceivedCharacter('[') => return Action::MoveSun(0.0, -0.1),
+                Event::ReceivedCharacter(']') => return Action::MoveSun(0.0, 0.1),
+                // The user pressed '-' or '=' to lower or raise the sun.
+                Event::ReceivedCharacter('-') => return Action::MoveSun(-0.05, 0.0),
+                Event::ReceivedCharacter('=') => return Action::MoveSun(0.05, 0.0),
                 // The user pressed 'b' to toggle blending.
                 Event::ReceivedCharacter('b') => self.enable_blend = !self.enable_blend,
-                // The user pressed 'd' to toggle debug view.
-                Event::ReceivedCharacter('d') => return Action::ToggleDebugView,
+                // The user pressed 'f' to toggle firefly clamping.
+                Event::ReceivedCharacter('f') => return Action::ToggleClampFireflies,
+                // The user pressed 'g' to toggle fog.
+                Event::ReceivedCharacter('g') => return Action::ToggleFog,
+                // The user pressed 'h' to toggle the MIS heuristic.
+                Event::ReceivedCharacter('h') => return Action::ToggleMisHeuristic,
+                // The user pressed 'i' to switch to the next integrator.
+                Event::ReceivedCharacter('i') => return Action::NextIntegrator,
                 // The user pressed 'm' to toggle the median filter.
                 Event::ReceivedCharacter('m') => self.enable_median = !self.enable_median,
+                // The user pressed 'o' to toggle outlier rejection.
+                Event::ReceivedCharacter('o') => return Action::ToggleOutlierRejection,
                 // The user pressed 'q' for quit.
                 Event::ReceivedCharacter('q') => return Action::Quit,
                 // The user pressed 'r' to toggle the render mode.
@@ -383,6 +429,8 @@ impl Window {
                 Event::ReceivedCharacter('s') => return Action::PrintStats,
                 // The user pressed 't' for trace.
                 Event::ReceivedCharacter('t') => return Action::DumpTrace,
+                // The user pressed 'w' to toggle rendering with wavelengths.
+                Event::ReceivedCharacter('w') => return Action::ToggleSpectral,
                 // Something else.
                 _ => (),
             }
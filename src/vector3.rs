@@ -172,6 +172,17 @@ impl MVector3 {
         }
     }
 
+    /// Returns the i-th vector. Index must be in the range 0-7 (inclusive).
+    ///
+    /// Note: this is essentially a transpose, avoid in hot code.
+    pub fn extract(self, i: usize) -> SVector3 {
+        SVector3 {
+            x: self.x.get_coord(i),
+            y: self.y.get_coord(i),
+            z: self.z.get_coord(i),
+        }
+    }
+
     #[inline(always)]
     pub fn cross_naive(self, other: MVector3) -> MVector3 {
         let (a, b) = (self, other);
@@ -0,0 +1,160 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module reads voxel grids of densities, for smoke and clouds.
+//!
+//! The file format is as simple as it gets: the dimensions of the grid as
+//! three little-endian 32-bit unsigned integers (width, height, and depth),
+//! followed by a little-endian 32-bit float for every voxel. The x coordinate
+//! varies fastest, then y, then z. Converting from other formats is a job for
+//! a script, not for the renderer.
+
+use filebuffer::FileBuffer;
+use std::mem::transmute;
+use std::path::Path;
+use vector3::SVector3;
+
+pub struct VoxelGrid {
+    width: u32,
+    height: u32,
+    depth: u32,
+    densities: Vec<f32>,
+    max_density: f32,
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    (input[pos] as u32) | ((input[pos + 1] as u32) << 8) | ((input[pos + 2] as u32) << 16) |
+    ((input[pos + 3] as u32) << 24)
+}
+
+/// Returns the number of voxels in a grid of the given size. The header of a
+/// file can claim any size, so the product must not silently overflow.
+fn voxel_count(width: u32, height: u32, depth: u32) -> usize {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(depth as usize))
+        .expect("voxel grid is too large")
+}
+
+impl VoxelGrid {
+    /// Constructs a grid from densities in the same order as in the file.
+    pub fn new(width: u32, height: u32, depth: u32, densities: Vec<f32>) -> VoxelGrid {
+        assert!(width > 0 && height > 0 && depth > 0, "voxel grid must not be empty");
+        assert!(voxel_count(width, height, depth) == densities.len(),
+                "voxel grid size does not match the number of densities");
+        assert!(densities.iter().all(|&d| d >= 0.0), "densities must not be negative");
+
+        let max_density = densities.iter().fold(0.0f32, |m, &d| m.max(d));
+
+        VoxelGrid {
+            width: width,
+            height: height,
+            depth: depth,
+            densities: densities,
+            max_density: max_density,
+        }
+    }
+
+    pub fn decode(input: &[u8]) -> VoxelGrid {
+        assert!(input.len() >= 12, "voxel file is too short for its header");
+        let width = read_u32(input, 0);
+        let height = read_u32(input, 4);
+        let depth = read_u32(input, 8);
+
+        let count = voxel_count(width, height, depth);
+        let len = count.checked_mul(4)
+            .and_then(|n| n.checked_add(12))
+            .expect("voxel grid is too large");
+        assert!(input.len() >= len, "voxel data is truncated");
+
+        let densities = (0..count)
+            .map(|i| unsafe { transmute::<u32, f32>(read_u32(input, 12 + i * 4)) })
+            .collect();
+
+        VoxelGrid::new(width, height, depth, densities)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> VoxelGrid {
+        let fbuffer = FileBuffer::open(path).expect("failed to open file");
+        VoxelGrid::decode(&fbuffer[..])
+    }
+
+    /// Returns the largest density in the grid. No interpolated density
+    /// exceeds it, so it bounds the extinction of the medium.
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    fn voxel(&self, x: i32, y: i32, z: i32) -> f32 {
+        // Clamp to the edge, the interpolation ends at the center of the
+        // outermost voxels.
+        let x = x.max(0).min(self.width as i32 - 1) as usize;
+        let y = y.max(0).min(self.height as i32 - 1) as usize;
+        let z = z.max(0).min(self.depth as i32 - 1) as usize;
+        let w = self.width as usize;
+        let h = self.height as usize;
+        self.densities[(z * h + y) * w + x]
+    }
+
+    /// Returns the trilinearly interpolated density at a point in the unit
+    /// cube that the grid fills. Outside of the cube the density is zero.
+    pub fn density_at(&self, p: SVector3) -> f32 {
+        if p.x < 0.0 || p.y < 0.0 || p.z < 0.0 || p.x > 1.0 || p.y > 1.0 || p.z > 1.0 {
+            return 0.0;
+        }
+
+        // Voxel centers are at half-integer coordinates.
+        let x = p.x * self.width as f32 - 0.5;
+        let y = p.y * self.height as f32 - 0.5;
+        let z = p.z * self.depth as f32 - 0.5;
+        let (xf, yf, zf) = (x.floor(), y.floor(), z.floor());
+        let (xi, yi, zi) = (xf as i32, yf as i32, zf as i32);
+        let (u, v, w) = (x - xf, y - yf, z - zf);
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let x00 = lerp(self.voxel(xi, yi, zi), self.voxel(xi + 1, yi, zi), u);
+        let x10 = lerp(self.voxel(xi, yi + 1, zi), self.voxel(xi + 1, yi + 1, zi), u);
+        let x01 = lerp(self.voxel(xi, yi, zi + 1), self.voxel(xi + 1, yi, zi + 1), u);
+        let x11 = lerp(self.voxel(xi, yi + 1, zi + 1), self.voxel(xi + 1, yi + 1, zi + 1), u);
+        lerp(lerp(x00, x10, v), lerp(x01, x11, v), w)
+    }
+}
+
+#[cfg(test)]
+fn push_u32(out: &mut Vec<u8>, x: u32) {
+    out.push(x as u8);
+    out.push((x >> 8) as u8);
+    out.push((x >> 16) as u8);
+    out.push((x >> 24) as u8);
+}
+
+#[test]
+fn decode_reads_header_and_densities() {
+    let mut data = Vec::new();
+    push_u32(&mut data, 2);
+    push_u32(&mut data, 1);
+    push_u32(&mut data, 1);
+    push_u32(&mut data, unsafe { transmute(0.25f32) });
+    push_u32(&mut data, unsafe { transmute(0.75f32) });
+
+    let grid = VoxelGrid::decode(&data);
+    assert_eq!(2, grid.width);
+    assert_eq!(1, grid.height);
+    assert_eq!(1, grid.depth);
+    assert_eq!(0.75, grid.max_density());
+    assert_eq!(0.25, grid.density_at(SVector3::new(0.25, 0.5, 0.5)));
+    assert_eq!(0.75, grid.density_at(SVector3::new(0.75, 0.5, 0.5)));
+    assert_eq!(0.5, grid.density_at(SVector3::new(0.5, 0.5, 0.5)));
+}
+
+#[test]
+fn density_is_zero_outside_unit_cube() {
+    let grid = VoxelGrid::new(1, 1, 1, vec![1.0]);
+    assert_eq!(1.0, grid.density_at(SVector3::new(0.0, 1.0, 0.5)));
+    assert_eq!(0.0, grid.density_at(SVector3::new(-0.1, 0.5, 0.5)));
+    assert_eq!(0.0, grid.density_at(SVector3::new(0.5, 1.1, 0.5)));
+}
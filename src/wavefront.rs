@@ -13,12 +13,14 @@ use material::SMaterial;
 use std::collections::HashMap;
 use std::path::Path;
 use std::str::{FromStr, from_utf8};
+use triangle::Sidedness;
 use vector3::SVector3;
 
 pub struct Triangle {
     pub vertices: (u32, u32, u32),
     pub tex_coords: Option<(u32, u32, u32)>,
     pub material: SMaterial,
+    pub sidedness: Sidedness,
 }
 
 pub struct Mesh {
@@ -71,6 +73,7 @@ pub fn push_triangle(vertices: &[SVector3],
         vertices: vidxs,
         tex_coords: tidxs,
         material: material,
+        sidedness: Sidedness::TwoSided,
     };
     triangles.push(triangle);
 }
@@ -150,6 +153,51 @@ impl Mesh {
             tex_coords: tex_coords,
         }
     }
+
+    /// Scales the mesh about the origin and then moves it by the offset, to
+    /// place a model that was not made for the scene.
+    pub fn with_transform(mut self, offset: SVector3, scale: f32) -> Mesh {
+        for vertex in &mut self.vertices {
+            *vertex = *vertex * scale + offset;
+        }
+        self
+    }
+
+    /// Replaces the material of every triangle. Scanned models like the
+    /// Stanford bunny and dragon have no materials of their own, this makes
+    /// them jade or wax with `SMaterial::layered()`.
+    pub fn with_material(mut self, material: SMaterial) -> Mesh {
+        for triangle in &mut self.triangles {
+            triangle.material = material;
+        }
+        self
+    }
+
+    /// Sets which sides of the triangles with the given material interact with
+    /// light. All triangles are two-sided by default.
+    pub fn with_sidedness(mut self, material: SMaterial, sidedness: Sidedness) -> Mesh {
+        for triangle in &mut self.triangles {
+            if triangle.material == material {
+                triangle.sidedness = sidedness;
+            }
+        }
+        self
+    }
+}
+
+/// Returns a mesh with a single parallelogram spanned by `u` and `v` from
+/// `origin`, for tests that need a scene without loading a model.
+#[cfg(test)]
+pub fn quad(origin: SVector3, u: SVector3, v: SVector3, material: SMaterial) -> Mesh {
+    let vertices = vec![origin, origin + u, origin + u + v, origin + v];
+    let mut triangles = Vec::new();
+    push_triangle(&vertices, &mut triangles, (0, None), (1, None), (2, None), material, 0);
+    push_triangle(&vertices, &mut triangles, (0, None), (2, None), (3, None), material, 0);
+    Mesh {
+        vertices: vertices,
+        tex_coords: Vec::new(),
+        triangles: triangles,
+    }
 }
 
 // The loader should be able to load all of these files without crashing. The